@@ -18,8 +18,8 @@ function onGraphMouseMove(e) {
     const value = g.getAttribute('data-v');
     const date = g.getAttribute('data-d');
     if (value && date) {
-      const dateObj = new Date(date);
-      const formattedDate = dateObj.toLocaleDateString('en-US', { month: 'short', day: 'numeric' });
+      const formattedDate = g.getAttribute('data-l') ||
+        new Date(date).toLocaleDateString('en-US', { month: 'short', day: 'numeric' });
       graphHover.style.left = (g.querySelector('rect').getAttribute('x') - graphScroll.scrollLeft + 10) + 'px';
       graphHover.style.display = 'block';
       graphHover.textContent = formattedDate + ': ' + value;
@@ -38,10 +38,9 @@ function onGraphMouseLeave(e) {
 function onGraphClick(e) {
   const g = findGroup(e);
   if (g) {
-    const date = g.getAttribute('data-d');
     const url = new URL(window.location.href);
-    url.searchParams.set('from', date);
-    url.searchParams.set('to', date);
+    url.searchParams.set('from', g.getAttribute('data-d'));
+    url.searchParams.set('to', g.getAttribute('data-e') || g.getAttribute('data-d'));
     window.location.href = url.toString();
   }
 }
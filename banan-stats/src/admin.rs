@@ -0,0 +1,178 @@
+use crate::dashboard;
+use crate::state::AppState;
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::post,
+    Router,
+};
+use serde::Deserialize;
+
+/// Maintenance endpoints, e.g. for cron jobs next to the sidecar. They need
+/// the dashboard token, and are turned away when none is configured since
+/// they write files on the host.
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/admin/backup", post(backup_handler))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            dashboard::require_token,
+        ))
+        .with_state(state)
+}
+
+#[derive(Deserialize)]
+struct BackupParams {
+    path: String,
+}
+
+/// Copies the database to `path`, which must not exist yet, and answers
+/// `{"path": ..., "bytes": N}`. An existing `path` answers `409`; other
+/// failures `500`, with the details going to stderr.
+async fn backup_handler(
+    State(state): State<AppState>,
+    Query(params): Query<BackupParams>,
+) -> Response {
+    if state.dashboard.token.is_none() {
+        return (
+            StatusCode::FORBIDDEN,
+            "the admin endpoints require --dashboard-token",
+        )
+            .into_response();
+    }
+    if std::path::Path::new(&params.path).exists() {
+        return (StatusCode::CONFLICT, "the backup path already exists").into_response();
+    }
+    match state.store.backup(params.path.clone()).await {
+        Ok(bytes) => {
+            let body = serde_json::json!({"path": params.path, "bytes": bytes});
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", "application/json".parse().expect("header"));
+            (headers, body.to_string()).into_response()
+        }
+        Err(err) => {
+            eprintln!("backup to {} failed: {:#}", params.path, err);
+            (StatusCode::INTERNAL_SERVER_ERROR, "backup failed").into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    /// Serves `app` on a loopback port.
+    pub(crate) async fn serve(app: Router) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        addr
+    }
+
+    /// Sends `head`, the request line and headers, and returns the whole
+    /// response.
+    pub(crate) async fn send(addr: SocketAddr, head: String) -> String {
+        let request = format!("{}Host: localhost\r\nConnection: close\r\n\r\n", head);
+        tokio::task::spawn_blocking(move || {
+            let mut stream = std::net::TcpStream::connect(addr)?;
+            stream.write_all(request.as_bytes())?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok::<_, std::io::Error>(response)
+        })
+        .await
+        .unwrap()
+        .expect("request")
+    }
+
+    async fn serve_admin(token: Option<&str>) -> SocketAddr {
+        let state = AppState {
+            store: Arc::new(crate::store::tests::memory_store()),
+            dashboard: Arc::new(dashboard::Config {
+                token: token.map(str::to_string),
+                ..Default::default()
+            }),
+            pages: Default::default(),
+            ingest: Default::default(),
+        };
+        serve(router(state)).await
+    }
+
+    /// Posts to `target`, returning the status line and the body.
+    async fn post(addr: SocketAddr, target: &str, token: Option<&str>) -> (String, String) {
+        let mut head = format!("POST {} HTTP/1.1\r\nContent-Length: 0\r\n", target);
+        if let Some(token) = token {
+            head += &format!("Authorization: Bearer {}\r\n", token);
+        }
+        let response = send(addr, head).await;
+        let status = response.lines().next().unwrap_or_default().to_string();
+        let body = response
+            .split_once("\r\n\r\n")
+            .map(|(_, body)| body.to_string())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    fn backup_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!(
+            "banan-stats-backup-{}-{}.duckdb",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[tokio::test]
+    async fn backups_need_a_configured_token() {
+        let path = backup_path("no-token");
+        let addr = serve_admin(None).await;
+        let target = format!("/admin/backup?path={}", path.display());
+        let (status, _) = post(addr, &target, None).await;
+        assert!(status.starts_with("HTTP/1.1 403"), "{}", status);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn backups_need_the_right_token() {
+        let path = backup_path("wrong-token");
+        let addr = serve_admin(Some("secret")).await;
+        let target = format!("/admin/backup?path={}", path.display());
+        for token in [None, Some("guess")] {
+            let (status, _) = post(addr, &target, token).await;
+            assert!(status.starts_with("HTTP/1.1 401"), "{}", status);
+        }
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn backups_keep_existing_files() {
+        let path = backup_path("existing");
+        std::fs::write(&path, "keep me").unwrap();
+        let addr = serve_admin(Some("secret")).await;
+        let target = format!("/admin/backup?path={}", path.display());
+        let (status, body) = post(addr, &target, Some("secret")).await;
+        let kept = std::fs::read_to_string(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(status.starts_with("HTTP/1.1 409"), "{}", status);
+        assert_eq!(body, "the backup path already exists");
+        assert_eq!(kept.unwrap(), "keep me");
+    }
+
+    #[tokio::test]
+    async fn backups_copy_the_database() {
+        let path = backup_path("copy");
+        let addr = serve_admin(Some("secret")).await;
+        let target = format!("/admin/backup?path={}", path.display());
+        let (status, body) = post(addr, &target, Some("secret")).await;
+        let size = std::fs::metadata(&path).map(|meta| meta.len());
+        let _ = std::fs::remove_file(&path);
+        assert!(status.starts_with("HTTP/1.1 200"), "{}", status);
+        let body: serde_json::Value = serde_json::from_str(&body).expect("json");
+        assert_eq!(body["path"], path.display().to_string());
+        assert_eq!(body["bytes"], size.expect("backup written"));
+    }
+}
@@ -1,10 +1,20 @@
+use crate::rules::RuleSet;
+use anyhow::Context;
+use chrono::{Datelike, NaiveDate, Utc};
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::borrow::Cow;
-use url::Url;
+use std::collections::BTreeMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use url::{Host, Url};
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default, Serialize)]
 pub struct Line {
     pub event_id: String,
     pub date: String,
@@ -15,35 +25,299 @@ pub struct Line {
     pub ip: String,
     pub user_agent: String,
     pub referrer: String,
+    pub content_type: String,
+    /// HTTP status of the response, when the shipper reported it.
+    pub status: Option<u16>,
+    pub accept_language: String,
+    pub screen_size: String,
+    pub protocol: String,
+    pub tls_version: String,
+    pub metric: String,
+    pub metric_value: Option<f64>,
     pub r#type: String,
     pub agent: String,
+    pub agent_version: String,
     pub os: String,
+    pub device: String,
     pub ref_domain: String,
+    pub ref_channel: String,
+    pub utm_source: String,
+    pub utm_campaign: String,
+    pub country: String,
     pub mult: i64,
     pub set_cookie: String,
     pub uniq: String,
     pub second_visit: bool,
 }
 
-pub fn analyze(line: &mut Line) {
+/// Runtime options for the analyzer, fixed at startup.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    pub feed_uniq: FeedUniq,
+    pub local_referrers: LocalReferrers,
+    /// Domain suffixes treated as internal referrers, e.g. `corp.example.com`.
+    pub internal_domains: Vec<String>,
+    pub store_ua: StoreUserAgent,
+    /// Classify otherwise unrecognized clients as browsers when the response
+    /// was a successful HTML one. Explicit feed and bot signals in the user
+    /// agent still win.
+    pub html_as_browser: bool,
+    /// Extra request fields mixed into the `ip + user_agent` visitor hash.
+    pub uniq_fields: Vec<UniqField>,
+    /// Browser, feed and bot rules used to pick the line type, and the
+    /// aliases agents are recorded under.
+    pub rules: RuleSet,
+    /// Record agent tokens as found in the user agent, without aliases.
+    pub raw_agents: bool,
+    /// GeoLite2/GeoIP2 country database resolving `country` from the IP.
+    pub geoip: Option<Arc<maxminddb::Reader<Vec<u8>>>>,
+    /// Salt mixed into the `ip + user_agent` visitor hash.
+    pub uniq_salt: Option<Arc<UniqSalt>>,
+    /// Clear `ip` once the line is analyzed.
+    pub drop_ip: bool,
+    /// Largest subscriber count trusted from a feed reader's user agent;
+    /// higher counts are clamped to it. 0 disables the cap.
+    pub max_subscribers: i64,
+}
+
+/// Random salt mixed into visitor hashes, one for every `period_days` days
+/// of event dates, so that hashes can't be linked across periods, nor
+/// reversed by hashing every IP once the period is over.
+///
+/// The salts of the newest two periods are kept in `path`, so a restart
+/// doesn't split the visitors of a period in two; older ones only live in
+/// memory until the process exits.
+#[derive(Debug)]
+pub struct UniqSalt {
+    period_days: u32,
+    path: Option<PathBuf>,
+    salts: Mutex<BTreeMap<i32, String>>,
+}
+
+/// Contents of the `UniqSalt` file.
+#[derive(Default, Serialize, serde::Deserialize)]
+struct StoredSalts {
+    period_days: u32,
+    salts: BTreeMap<i32, String>,
+}
+
+impl UniqSalt {
+    /// Salts rotating every `period_days` days, loaded from and saved to
+    /// `path` when given. Salts saved with another period are discarded.
+    pub fn new(period_days: u32, path: Option<PathBuf>) -> Result<Self, anyhow::Error> {
+        let period_days = period_days.max(1);
+        let mut salts = BTreeMap::new();
+        if let Some(path) = &path {
+            match std::fs::read_to_string(path) {
+                Ok(text) => {
+                    let stored: StoredSalts = serde_json::from_str(&text)
+                        .with_context(|| format!("invalid salt file {}", path.display()))?;
+                    if stored.period_days == period_days {
+                        salts = stored.salts;
+                    }
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err)
+                        .with_context(|| format!("failed to read salt file {}", path.display()));
+                }
+            }
+        }
+        Ok(Self {
+            period_days,
+            path,
+            salts: Mutex::new(salts),
+        })
+    }
+
+    /// Salt of the period `date` falls in; today's (UTC) when `date` isn't
+    /// a `YYYY-MM-DD` date.
+    fn for_date(&self, date: &str) -> String {
+        let date =
+            NaiveDate::parse_from_str(date, "%Y-%m-%d").unwrap_or_else(|_| Utc::now().date_naive());
+        let period = date.num_days_from_ce().div_euclid(self.period_days as i32);
+        let mut salts = self.salts.lock().expect("salt lock");
+        if let Some(salt) = salts.get(&period) {
+            return salt.clone();
+        }
+        // `RandomState` keys are seeded from the OS.
+        let salt = format!(
+            "{:016x}{:016x}",
+            RandomState::new().build_hasher().finish(),
+            RandomState::new().build_hasher().finish()
+        );
+        salts.insert(period, salt.clone());
+        if let Err(err) = self.save(&salts) {
+            eprintln!("failed to save uniq salts: {:#}", err);
+        }
+        salt
+    }
+
+    /// Writes the newest two periods' salts to `path`, replacing the file
+    /// in one rename.
+    fn save(&self, salts: &BTreeMap<i32, String>) -> Result<(), anyhow::Error> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let stored = StoredSalts {
+            period_days: self.period_days,
+            salts: salts
+                .iter()
+                .rev()
+                .take(2)
+                .map(|(period, salt)| (*period, salt.clone()))
+                .collect(),
+        };
+        let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options
+            .open(&tmp)
+            .with_context(|| format!("create {}", tmp.display()))?;
+        std::io::Write::write_all(&mut file, serde_json::to_string(&stored)?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, path).with_context(|| format!("replace {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Request field that can separate visitors sharing an IP and user agent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum UniqField {
+    AcceptLanguage,
+    ScreenSize,
+}
+
+/// How `uniq` is derived for feed readers that don't send a cookie.
+///
+/// Readers that include a `feed-id` in their user agent are always keyed by
+/// `agent/feed-id`, and readers that report neither a feed-id nor a subscriber
+/// count are keyed by `ip + user_agent` like any other client. The strategy
+/// only decides what happens to readers reporting a subscriber count without
+/// a feed-id.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum FeedUniq {
+    /// Key by agent alone, so every instance of a hosted reader is one visitor.
+    #[default]
+    Agent,
+    /// Key by agent and IP, so self-hosted instances count separately.
+    AgentIp,
+}
+
+/// What to record as `ref_domain` for referrers on localhost, loopback or
+/// private IP literals, or one of the configured internal domains.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LocalReferrers {
+    /// Record the host like any other referrer.
+    #[default]
+    Keep,
+    /// Treat the visit as direct traffic.
+    Direct,
+    /// Record the `local` label instead of the host.
+    Label,
+}
+
+/// How much of the raw user agent is kept once the line has been analyzed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum StoreUserAgent {
+    /// Keep the raw user agent.
+    #[default]
+    Full,
+    /// Drop the raw user agent once an agent was derived from it, keeping
+    /// unrecognized ones so the rules can be improved.
+    Parsed,
+    /// Always drop the raw user agent.
+    None,
+}
+
+/// Fills in the derived columns of `line` left empty. Returns whether its
+/// subscriber count was clamped to `max_subscribers`, which the store logs
+/// once per write rather than once per line.
+pub fn analyze(line: &mut Line, config: &Config) -> bool {
+    let mut clamped = false;
     if line.agent.is_empty() {
         line.agent = line_agent(&line.user_agent);
     }
+    if line.agent_version.is_empty() {
+        line.agent_version = line_agent_version(&line.user_agent, &line.agent);
+    }
     if line.r#type.is_empty() {
-        line.r#type = line_type(&line.path, &line.agent, &line.user_agent);
+        let html = config.html_as_browser
+            && is_html(&line.content_type)
+            && line
+                .status
+                .is_some_and(|status| (200..300).contains(&status));
+        line.r#type = line_type(
+            &line.path,
+            &line.agent,
+            &line.user_agent,
+            html,
+            &config.rules,
+        );
     }
     if line.os.is_empty() {
         line.os = line_os(&line.user_agent);
     }
+    if line.device.is_empty() {
+        line.device = line_device(&line.user_agent);
+    }
     if line.mult == 0 {
         line.mult = line_multiplier(&line.user_agent);
+        if config.max_subscribers > 0 && line.mult > config.max_subscribers {
+            line.mult = config.max_subscribers;
+            clamped = true;
+        }
     }
     if line.uniq.is_empty() {
-        line.uniq = line_uniq(&line.ip, &line.user_agent, &line.agent);
+        let entropy = uniq_entropy(line, &config.uniq_fields);
+        let salt = config
+            .uniq_salt
+            .as_ref()
+            .map(|salt| salt.for_date(&line.date))
+            .unwrap_or_default();
+        line.uniq = line_uniq(
+            &line.ip,
+            &line.user_agent,
+            &line.agent,
+            &entropy,
+            &salt,
+            config.feed_uniq,
+        );
+    }
+    // After the version, type and `uniq`, which are derived from the raw token.
+    if !config.raw_agents
+        && let Some(alias) = config.rules.agent_alias(&line.agent)
+    {
+        line.agent = alias.to_string();
     }
     if line.ref_domain.is_empty() {
-        line.ref_domain = line_ref_domain(&line.referrer);
+        line.ref_domain = line_ref_domain(&line.referrer, config);
+    }
+    if line.ref_channel.is_empty() {
+        line.ref_channel = line_ref_channel(&line.ref_domain);
+    }
+    if line.utm_source.is_empty() {
+        line.utm_source = line_utm(&line.query, "utm_source");
     }
+    if line.utm_campaign.is_empty() {
+        line.utm_campaign = line_utm(&line.query, "utm_campaign");
+    }
+    if line.country.is_empty()
+        && let Some(geoip) = &config.geoip
+    {
+        line.country = line_country(&line.ip, geoip);
+    }
+    match config.store_ua {
+        StoreUserAgent::Full => {}
+        StoreUserAgent::Parsed if line.agent.is_empty() => {}
+        StoreUserAgent::Parsed | StoreUserAgent::None => line.user_agent.clear(),
+    }
+    if config.drop_ip {
+        line.ip.clear();
+    }
+    clamped
 }
 
 fn dequote(s: &str) -> Cow<'_, str> {
@@ -66,7 +340,11 @@ static RE_COMPATIBLE: Lazy<Regex> =
 static RE_BOT_BEFORE: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(?i)^[\w\.\-_@ ]*[\w\.\-_@] (?:ro)?bot").expect("re"));
 static RE_BOT_CONTAINS: Lazy<Regex> =
-    Lazy::new(|| Regex::new(r"(?i)\b[\w-_]+bot\b").expect("re"));
+    Lazy::new(|| Regex::new(r"(?i)\b[\w-]+bot\b").expect("re"));
+/// Automation tools that drive a real browser engine, so their user agents
+/// otherwise pass for a browser.
+static RE_HEADLESS: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)Headless|Puppeteer|Playwright|PhantomJS").expect("re"));
 static RE_TRIDENT: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)Trident/[0-9.]+").expect("re"));
 static RE_MOZILLA_FIRST: Lazy<Regex> = Lazy::new(|| {
     Regex::new(r"(?i)^Mozilla/.* ([A-Za-z0-9_]+)/[A-Z0-9.]+(?: (?:Chrome|Version|Mobile|Safari|Mobile Safari)/[A-Z0-9.]+)+$")
@@ -91,17 +369,20 @@ static RE_BEFORE_SLASH: Lazy<Regex> =
 static RE_SINGLE_WORD: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(?i)^[\w\.\-_@ ]*[\w\.\-_@]$").expect("re"));
 
-static RE_RSS: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)rss").expect("re"));
-static RE_BOT_UA: Lazy<Regex> = Lazy::new(|| {
-    Regex::new(r"(?i)bot|crawl|fetch|node|ruby|.rb|python|curl|okhttp|spider|scan|nutch|mastodon|\+http")
-        .expect("re")
-});
 static RE_OS_ANDROID: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)Android").expect("re"));
 static RE_OS_WINDOWS: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)Windows").expect("re"));
 static RE_OS_IOS: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(?i)iOS|iPhone|iPad|Mobile.*Safari").expect("re"));
 static RE_OS_MAC: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)macOS|Mac OS|Macintosh|Darwin").expect("re"));
 static RE_OS_LINUX: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)Linux|X11").expect("re"));
+static RE_DEVICE_TABLET: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)iPad|Tablet").expect("re"));
+static RE_DEVICE_MOBILE: Lazy<Regex> = Lazy::new(|| Regex::new(r"(?i)Mobile|iPhone").expect("re"));
+static RE_CHANNEL_SEARCH: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)(^|\.)(google|bing|duckduckgo|yandex|baidu|ecosia|qwant|startpage|kagi|search\.yahoo|search\.brave)\.[a-z]{2,3}(\.[a-z]{2})?$").expect("re")
+});
+static RE_CHANNEL_SOCIAL: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"(?i)(^|\.)(twitter\.com|x\.com|t\.co|facebook\.com|fb\.com|instagram\.com|linkedin\.com|lnkd\.in|reddit\.com|news\.ycombinator\.com|bsky\.app|threads\.net|youtube\.com)$|^(mastodon|mstdn)\.").expect("re")
+});
 
 static RE_MULTIPLIER: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(?i)(\d+) subscriber").expect("re"));
@@ -178,6 +459,38 @@ fn line_agent(user_agent: &str) -> String {
     String::new()
 }
 
+/// Returns the version following the agent token, e.g. `120.0.6099.109` for
+/// `Chrome/120.0.6099.109`. Safari reports its marketing version separately
+/// in `Version/`, so that one wins for Safari.
+fn line_agent_version(user_agent: &str, agent: &str) -> String {
+    if user_agent.is_empty() || agent.is_empty() {
+        return String::new();
+    }
+    let ua = dequote(user_agent);
+    if agent == "Safari" {
+        let version = version_after(&ua, "Version/");
+        if !version.is_empty() {
+            return version;
+        }
+    }
+    version_after(&ua, &format!("{}/", agent))
+}
+
+fn version_after(s: &str, token: &str) -> String {
+    let Some(pos) = s.find(token) else {
+        return String::new();
+    };
+    let version: String = s[pos + token.len()..]
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric() || *c == '.')
+        .collect();
+    if version.starts_with(|c: char| c.is_ascii_digit()) {
+        version
+    } else {
+        String::new()
+    }
+}
+
 fn is_excluded_agent(name: &str) -> bool {
     matches!(
         name,
@@ -185,27 +498,43 @@ fn is_excluded_agent(name: &str) -> bool {
     )
 }
 
-fn line_type(path: &str, agent: &str, user_agent: &str) -> String {
-    if !user_agent.is_empty() && RE_RSS.is_match(user_agent) {
+/// Classifies a line as `feed`, `browser` or `bot`, in order of precedence:
+/// a feed user agent, a headless browser user agent (a bot), a known browser
+/// agent, a bot-like user agent, a `Mozilla/` user agent, then the `html`
+/// hint. Feeds detected from the response content type are typed before
+/// analysis and never reach this.
+fn line_type(path: &str, agent: &str, user_agent: &str, html: bool, rules: &RuleSet) -> String {
+    if !user_agent.is_empty() && rules.is_feed(user_agent) {
         return "feed".to_string();
     }
-    match agent {
-        "Chrome" | "Firefox" | "Edg" | "EdgA" | "EdgiOS" | "Safari" | "OPR" | "YaBrowser"
-        | "Vivaldi" | "SamsungBrowser" | "UCBrowser" => return "browser".to_string(),
-        _ => {}
+    if RE_HEADLESS.is_match(user_agent) {
+        return "bot".to_string();
+    }
+    if rules.is_browser(agent) {
+        return "browser".to_string();
     }
-    if !user_agent.is_empty() && RE_BOT_UA.is_match(user_agent) {
+    if !user_agent.is_empty() && rules.is_bot(user_agent) {
         return "bot".to_string();
     }
     if user_agent.starts_with("Mozilla/") {
         return "browser".to_string();
     }
+    if html && !path.is_empty() {
+        return "browser".to_string();
+    }
     if path.is_empty() {
         return "bot".to_string();
     }
     "bot".to_string()
 }
 
+fn is_html(content_type: &str) -> bool {
+    content_type
+        .trim_start()
+        .to_lowercase()
+        .starts_with("text/html")
+}
+
 fn line_os(user_agent: &str) -> String {
     if RE_OS_ANDROID.is_match(user_agent) {
         return "Android".to_string();
@@ -225,6 +554,25 @@ fn line_os(user_agent: &str) -> String {
     String::new()
 }
 
+/// Form factor of the client: `tablet`, `mobile` or `desktop`. Tablets are
+/// checked first since iPads also send `Mobile`, and Android devices without
+/// `Mobile` are tablets by Android's own convention.
+fn line_device(user_agent: &str) -> String {
+    if user_agent.is_empty() {
+        return String::new();
+    }
+    if RE_DEVICE_TABLET.is_match(user_agent) {
+        return "tablet".to_string();
+    }
+    if RE_DEVICE_MOBILE.is_match(user_agent) {
+        return "mobile".to_string();
+    }
+    if RE_OS_ANDROID.is_match(user_agent) {
+        return "tablet".to_string();
+    }
+    "desktop".to_string()
+}
+
 fn line_multiplier(user_agent: &str) -> i64 {
     if let Some(caps) = RE_MULTIPLIER.captures(user_agent) {
         if let Some(m) = caps.get(1) {
@@ -236,16 +584,41 @@ fn line_multiplier(user_agent: &str) -> i64 {
     1
 }
 
-fn line_uniq(ip: &str, user_agent: &str, agent: &str) -> String {
+fn line_uniq(
+    ip: &str,
+    user_agent: &str,
+    agent: &str,
+    entropy: &str,
+    salt: &str,
+    feed_uniq: FeedUniq,
+) -> String {
     if !user_agent.is_empty() && !agent.is_empty() {
         if let Some(feed_id) = extract_feed_id(user_agent) {
             return hash_uuid(&format!("{}/{}", agent, feed_id));
         }
         if user_agent.to_lowercase().contains("subscriber") {
-            return hash_uuid(agent);
+            return match feed_uniq {
+                FeedUniq::Agent => hash_uuid(agent),
+                FeedUniq::AgentIp => hash_uuid(&format!("{}{}/{}", salt, agent, ip)),
+            };
         }
     }
-    hash_uuid(&format!("{}{}", ip, user_agent))
+    hash_uuid(&format!("{}{}{}{}", salt, ip, user_agent, entropy))
+}
+
+/// Joins the configured extra fields, each behind a newline so that values
+/// can't run into each other. Empty when no fields are configured, which
+/// keeps the hash identical to the plain `ip + user_agent` one.
+fn uniq_entropy(line: &Line, fields: &[UniqField]) -> String {
+    let mut entropy = String::new();
+    for field in fields {
+        entropy.push('\n');
+        entropy.push_str(match field {
+            UniqField::AcceptLanguage => &line.accept_language,
+            UniqField::ScreenSize => &line.screen_size,
+        });
+    }
+    entropy
 }
 
 fn extract_feed_id(user_agent: &str) -> Option<String> {
@@ -255,19 +628,91 @@ fn extract_feed_id(user_agent: &str) -> Option<String> {
     None
 }
 
-fn line_ref_domain(referrer: &str) -> String {
+/// ISO 3166-1 code of the country `ip` is located in, or empty when unknown.
+/// Only the first address of a forwarded list is looked up.
+fn line_country(ip: &str, geoip: &maxminddb::Reader<Vec<u8>>) -> String {
+    let Ok(addr) = ip.split(',').next().unwrap_or("").trim().parse::<IpAddr>() else {
+        return String::new();
+    };
+    geoip
+        .lookup::<maxminddb::geoip2::Country>(addr)
+        .ok()
+        .and_then(|found| found.country)
+        .and_then(|country| country.iso_code)
+        .unwrap_or_default()
+        .to_string()
+}
+
+fn line_ref_domain(referrer: &str, config: &Config) -> String {
     if referrer.is_empty() {
         return String::new();
     }
     if let Ok(u) = Url::parse(referrer) {
         if let Some(host) = u.host_str() {
+            if config.local_referrers != LocalReferrers::Keep
+                && is_local_host(&u, &config.internal_domains)
+            {
+                return match config.local_referrers {
+                    LocalReferrers::Label => "local".to_string(),
+                    _ => String::new(),
+                };
+            }
             return host.trim_start_matches("www.").to_string();
         }
     }
     String::new()
 }
 
-fn hash_uuid(input: &str) -> String {
+/// Channel the visit came through, from its referrer domain: `search`,
+/// `social`, `direct` without a referrer, and `other` for everything else.
+fn line_ref_channel(ref_domain: &str) -> String {
+    if ref_domain.is_empty() {
+        return "direct".to_string();
+    }
+    if RE_CHANNEL_SEARCH.is_match(ref_domain) {
+        return "search".to_string();
+    }
+    if RE_CHANNEL_SOCIAL.is_match(ref_domain) {
+        return "social".to_string();
+    }
+    "other".to_string()
+}
+
+/// Value of the UTM parameter `key` in the query string, empty when the
+/// visit wasn't tagged with it.
+fn line_utm(query: &str, key: &str) -> String {
+    url::form_urlencoded::parse(query.trim_start_matches('?').as_bytes())
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value.trim().to_string())
+        .unwrap_or_default()
+}
+
+fn is_local_host(u: &Url, internal_domains: &[String]) -> bool {
+    match u.host() {
+        Some(Host::Ipv4(ip)) => {
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        }
+        Some(Host::Ipv6(ip)) => {
+            ip.is_loopback()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || ip.is_unspecified()
+        }
+        Some(Host::Domain(domain)) => {
+            let domain = domain.to_lowercase();
+            domain == "localhost"
+                || domain.ends_with(".localhost")
+                || internal_domains.iter().any(|suffix| {
+                    let suffix = suffix.trim_start_matches('.').to_lowercase();
+                    !suffix.is_empty()
+                        && (domain == suffix || domain.ends_with(&format!(".{}", suffix)))
+                })
+        }
+        None => false,
+    }
+}
+
+pub fn hash_uuid(input: &str) -> String {
     let mut hasher = Sha256::new();
     hasher.update(input.as_bytes());
     let sum = hasher.finalize();
@@ -297,3 +742,511 @@ fn regex_group(re: &Regex, s: &str, idx: usize) -> String {
         .and_then(|caps| caps.get(idx).map(|m| m.as_str().to_string()))
         .unwrap_or_default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn analyzed(user_agent: &str, config: &Config) -> Line {
+        analyzed_from("203.0.113.7", user_agent, config)
+    }
+
+    fn analyzed_from(ip: &str, user_agent: &str, config: &Config) -> Line {
+        let mut line = Line {
+            ip: ip.to_string(),
+            user_agent: user_agent.to_string(),
+            ..Line::default()
+        };
+        analyze(&mut line, config);
+        line
+    }
+
+    /// Whether two instances of `user_agent` on different IPs count as one
+    /// visitor under `feed_uniq`.
+    fn same_feed_visitor(user_agent: &str, feed_uniq: FeedUniq) -> bool {
+        let config = Config {
+            feed_uniq,
+            ..Config::default()
+        };
+        analyzed_from("203.0.113.7", user_agent, &config).uniq
+            == analyzed_from("198.51.100.9", user_agent, &config).uniq
+    }
+
+    const FEED_ID_READER: &str = "Feedbin feed-id:1373711 - 6 subscribers";
+    const SUBSCRIBER_READER: &str =
+        "Feedly/1.0 (+http://www.feedly.com/fetcher.html; 6 subscribers)";
+    const PLAIN_READER: &str = "NetNewsWire (RSS Reader; https://netnewswire.com/)";
+
+    #[test]
+    fn feed_id_readers_are_keyed_by_feed_id() {
+        for feed_uniq in [FeedUniq::Agent, FeedUniq::AgentIp] {
+            assert!(
+                same_feed_visitor(FEED_ID_READER, feed_uniq),
+                "{:?}",
+                feed_uniq
+            );
+        }
+        let config = Config::default();
+        assert_eq!(
+            analyzed(FEED_ID_READER, &config).uniq,
+            hash_uuid("Feedbin/1373711")
+        );
+    }
+
+    #[test]
+    fn subscriber_readers_follow_feed_uniq() {
+        assert!(same_feed_visitor(SUBSCRIBER_READER, FeedUniq::Agent));
+        assert!(!same_feed_visitor(SUBSCRIBER_READER, FeedUniq::AgentIp));
+    }
+
+    #[test]
+    fn plain_readers_are_keyed_by_ip_and_user_agent() {
+        for feed_uniq in [FeedUniq::Agent, FeedUniq::AgentIp] {
+            assert!(
+                !same_feed_visitor(PLAIN_READER, feed_uniq),
+                "{:?}",
+                feed_uniq
+            );
+        }
+    }
+
+    fn feedly(subscribers: u64) -> String {
+        format!(
+            "Feedly/1.0 (+http://www.feedly.com/fetcher.html; {} subscribers; like FeedFetcher-Google)",
+            subscribers
+        )
+    }
+
+    #[test]
+    fn clamps_absurd_subscriber_count() {
+        let config = Config {
+            max_subscribers: 100_000,
+            ..Config::default()
+        };
+        assert_eq!(analyzed(&feedly(9_999_999), &config).mult, 100_000);
+
+        let mut line = Line {
+            user_agent: feedly(9_999_999),
+            ..Line::default()
+        };
+        assert!(analyze(&mut line, &config));
+        // An analyzed line keeps its multiplier.
+        assert!(!analyze(&mut line, &config));
+        let mut line = Line {
+            user_agent: feedly(42),
+            ..Line::default()
+        };
+        assert!(!analyze(&mut line, &config));
+    }
+
+    #[test]
+    fn keeps_subscriber_count_under_cap() {
+        let config = Config {
+            max_subscribers: 100_000,
+            ..Config::default()
+        };
+        assert_eq!(analyzed(&feedly(42), &config).mult, 42);
+        assert_eq!(analyzed(&feedly(100_000), &config).mult, 100_000);
+    }
+
+    #[test]
+    fn zero_max_subscribers_disables_cap() {
+        assert_eq!(
+            analyzed(&feedly(9_999_999), &Config::default()).mult,
+            9_999_999
+        );
+    }
+
+    fn local_referrers(local_referrers: LocalReferrers) -> Config {
+        Config {
+            local_referrers,
+            internal_domains: vec!["corp.example.com".to_string()],
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn maps_loopback_referrers() {
+        for referrer in [
+            "http://localhost:8080/",
+            "http://127.0.0.1/admin",
+            "http://[::1]:3000/",
+        ] {
+            assert_eq!(
+                line_ref_domain(referrer, &local_referrers(LocalReferrers::Direct)),
+                "",
+                "{}",
+                referrer
+            );
+            assert_eq!(
+                line_ref_domain(referrer, &local_referrers(LocalReferrers::Label)),
+                "local",
+                "{}",
+                referrer
+            );
+        }
+    }
+
+    #[test]
+    fn maps_private_ip_referrers() {
+        let config = local_referrers(LocalReferrers::Label);
+        assert_eq!(line_ref_domain("http://192.168.1.20/", &config), "local");
+        assert_eq!(line_ref_domain("http://10.0.0.5:8000/", &config), "local");
+        assert_eq!(
+            line_ref_domain("http://203.0.113.7/", &config),
+            "203.0.113.7"
+        );
+    }
+
+    #[test]
+    fn maps_internal_domain_referrers() {
+        let config = local_referrers(LocalReferrers::Direct);
+        assert_eq!(line_ref_domain("https://corp.example.com/", &config), "");
+        assert_eq!(
+            line_ref_domain("https://wiki.Corp.Example.com/page", &config),
+            ""
+        );
+        assert_eq!(
+            line_ref_domain("https://notcorp.example.com/", &config),
+            "notcorp.example.com"
+        );
+    }
+
+    #[test]
+    fn keeps_local_referrers_by_default() {
+        let config = local_referrers(LocalReferrers::Keep);
+        assert_eq!(
+            line_ref_domain("http://localhost:8080/", &config),
+            "localhost"
+        );
+        assert_eq!(
+            line_ref_domain("https://www.corp.example.com/", &config),
+            "corp.example.com"
+        );
+    }
+
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+
+    fn stored_user_agent(user_agent: &str, store_ua: StoreUserAgent) -> Line {
+        analyzed(
+            user_agent,
+            &Config {
+                store_ua,
+                ..Config::default()
+            },
+        )
+    }
+
+    #[test]
+    fn parsed_drops_recognized_user_agents_after_analysis() {
+        let line = stored_user_agent(FIREFOX, StoreUserAgent::Parsed);
+        assert_eq!(line.user_agent, "");
+        assert_eq!(line.agent, "Firefox");
+        assert_eq!(line.os, "Linux");
+        assert_eq!(line.r#type, "browser");
+    }
+
+    #[test]
+    fn none_drops_every_user_agent_and_full_keeps_it() {
+        let line = stored_user_agent(FIREFOX, StoreUserAgent::None);
+        assert_eq!(line.user_agent, "");
+        assert_eq!(line.agent, "Firefox");
+        assert_eq!(
+            stored_user_agent(FIREFOX, StoreUserAgent::Full).user_agent,
+            FIREFOX
+        );
+    }
+
+    /// Type of a reader-mode proxy's request answered with `content_type`
+    /// and `status`, with `--html-as-browser` set to `html_as_browser`.
+    fn proxied_type(
+        user_agent: &str,
+        content_type: &str,
+        status: Option<u16>,
+        html_as_browser: bool,
+    ) -> String {
+        let mut line = Line {
+            path: "/article".to_string(),
+            user_agent: user_agent.to_string(),
+            content_type: content_type.to_string(),
+            status,
+            ..Line::default()
+        };
+        analyze(
+            &mut line,
+            &Config {
+                html_as_browser,
+                ..Config::default()
+            },
+        );
+        line.r#type
+    }
+
+    #[test]
+    fn html_as_browser_nudges_ambiguous_user_agents() {
+        let html = "text/html; charset=utf-8";
+        assert_eq!(
+            proxied_type("ReaderView/2.0", html, Some(200), true),
+            "browser"
+        );
+        assert_eq!(
+            proxied_type("ReaderView/2.0", html, Some(204), true),
+            "browser"
+        );
+        assert_eq!(
+            proxied_type("ReaderView/2.0", html, Some(200), false),
+            "bot"
+        );
+    }
+
+    #[test]
+    fn html_as_browser_requires_successful_html_responses() {
+        let html = "text/html";
+        assert_eq!(proxied_type("ReaderView/2.0", html, Some(404), true), "bot");
+        assert_eq!(proxied_type("ReaderView/2.0", html, Some(301), true), "bot");
+        assert_eq!(proxied_type("ReaderView/2.0", html, None, true), "bot");
+        assert_eq!(
+            proxied_type("ReaderView/2.0", "application/json", Some(200), true),
+            "bot"
+        );
+    }
+
+    #[test]
+    fn html_as_browser_keeps_explicit_bots() {
+        assert_eq!(
+            proxied_type("curl/8.0", "text/html", Some(200), true),
+            "bot"
+        );
+        assert_eq!(
+            proxied_type(
+                "Googlebot/2.1 (+http://www.google.com/bot.html)",
+                "text/html",
+                Some(200),
+                true
+            ),
+            "bot"
+        );
+    }
+
+    fn visitor_with_language(accept_language: &str, uniq_fields: &[UniqField]) -> String {
+        let mut line = Line {
+            ip: "203.0.113.7".to_string(),
+            user_agent: FIREFOX.to_string(),
+            accept_language: accept_language.to_string(),
+            ..Line::default()
+        };
+        analyze(
+            &mut line,
+            &Config {
+                uniq_fields: uniq_fields.to_vec(),
+                ..Config::default()
+            },
+        );
+        line.uniq
+    }
+
+    #[test]
+    fn accept_language_separates_visitors_sharing_an_ip() {
+        let fields = [UniqField::AcceptLanguage];
+        assert_ne!(
+            visitor_with_language("de-DE,de;q=0.9", &fields),
+            visitor_with_language("en-US,en;q=0.8", &fields)
+        );
+        assert_eq!(
+            visitor_with_language("de-DE,de;q=0.9", &fields),
+            visitor_with_language("de-DE,de;q=0.9", &fields)
+        );
+    }
+
+    #[test]
+    fn accept_language_is_ignored_unless_configured() {
+        assert_eq!(
+            visitor_with_language("de-DE,de;q=0.9", &[]),
+            visitor_with_language("en-US,en;q=0.8", &[])
+        );
+        assert_eq!(
+            visitor_with_language("de-DE", &[]),
+            hash_uuid(&format!("203.0.113.7{}", FIREFOX))
+        );
+    }
+
+    #[test]
+    fn custom_bot_rules_override_the_browser_fallback() {
+        let user_agent = "Mozilla/5.0 (compatible; AcmeMonitor/2.1)";
+        assert_eq!(analyzed(user_agent, &Config::default()).r#type, "browser");
+        let mut rules = crate::rules::Rules::builtin();
+        rules.merge(crate::rules::Rules {
+            bots: vec!["AcmeMonitor".to_string()],
+            ..Default::default()
+        });
+        let config = Config {
+            rules: rules.compile().expect("compile"),
+            ..Config::default()
+        };
+        assert_eq!(analyzed(user_agent, &config).r#type, "bot");
+    }
+
+    #[test]
+    fn real_bot_user_agents_are_bots() {
+        let config = Config::default();
+        for user_agent in [
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Mozilla/5.0 (Linux; Android 6.0.1; Nexus 5X Build/MMB29P) AppleWebKit/537.36 \
+             (KHTML, like Gecko) Chrome/120.0.6099.216 Mobile Safari/537.36 \
+             (compatible; Googlebot/2.1; +http://www.google.com/bot.html)",
+            "Mozilla/5.0 (compatible; bingbot/2.0; +http://www.bing.com/bingbot.htm)",
+            "Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; compatible; GPTBot/1.0; \
+             +https://openai.com/gptbot)",
+            "Mozilla/5.0 (compatible; AhrefsBot/7.0; +http://ahrefs.com/robot/)",
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+             (KHTML, like Gecko) Version/13.1.1 Safari/605.1.15 \
+             (Applebot/0.1; +http://www.apple.com/go/applebot)",
+            "Mozilla/5.0 (compatible; YandexBot/3.0; +http://yandex.com/bots)",
+            "Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; Some-Crawler-bot) Chrome/120.0",
+            "Twitterbot/1.0",
+            "Slackbot-LinkExpanding 1.0 (+https://api.slack.com/robots)",
+            "facebookexternalhit/1.1 (+http://www.facebook.com/externalhit_uatext.php)",
+            "curl/8.4.0",
+            "python-requests/2.31.0",
+            "Go-http-client/1.1",
+        ] {
+            assert_eq!(
+                analyzed(user_agent, &config).r#type,
+                "bot",
+                "{}",
+                user_agent
+            );
+        }
+    }
+
+    #[test]
+    fn hyphenated_bot_names_are_kept_whole() {
+        let config = Config::default();
+        for (user_agent, agent) in [
+            (
+                "Mozilla/5.0 AppleWebKit/537.36 (KHTML, like Gecko; Some-Crawler-bot) Chrome/120.0",
+                "Some-Crawler-bot",
+            ),
+            (
+                "Mozilla/5.0 (X11; Linux x86_64) my-uptime-bot Firefox/120.0",
+                "my-uptime-bot",
+            ),
+        ] {
+            assert_eq!(analyzed(user_agent, &config).agent, agent);
+        }
+    }
+
+    #[test]
+    fn headless_browsers_are_bots() {
+        let config = Config::default();
+        for user_agent in [
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) \
+             HeadlessChrome/120.0.6099.109 Safari/537.36",
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+             HeadlessChrome/119.0.0.0 Safari/537.36",
+            "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0 Headless",
+            "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 (KHTML, like Gecko) \
+             Chrome/120.0.0.0 Safari/537.36 Puppeteer",
+            "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_15_7) AppleWebKit/605.1.15 \
+             (KHTML, like Gecko) Version/17.0 Safari/605.1.15 Playwright/1.40.0",
+            "Mozilla/5.0 (Unknown; Linux x86_64) AppleWebKit/538.1 (KHTML, like Gecko) \
+             PhantomJS/2.1.1 Safari/538.1",
+        ] {
+            assert_eq!(
+                analyzed(user_agent, &config).r#type,
+                "bot",
+                "{}",
+                user_agent
+            );
+        }
+        // The desktop browsers they imitate stay browsers.
+        assert_eq!(analyzed(FIREFOX, &config).r#type, "browser");
+        let chrome = "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 \
+             (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36";
+        assert_eq!(analyzed(chrome, &config).r#type, "browser");
+    }
+
+    #[test]
+    fn bot_tokens_loaded_from_a_file_classify_as_bots() {
+        let user_agent = "Mozilla/5.0 (compatible; AcmeCrawler/1.0; +https://acme.example/bot)";
+        let path = std::env::temp_dir().join(format!(
+            "banan-stats-acme-rules-{}.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, r#"{"bots": ["AcmeCrawler"]}"#).expect("write");
+        let loaded = crate::rules::Rules::load(path.to_str().expect("utf-8 path"));
+        let _ = std::fs::remove_file(&path);
+        let loaded = loaded.expect("load");
+
+        // The file's token matches on its own, whatever the built-ins say.
+        let only_loaded = loaded.clone().compile().expect("compile");
+        assert!(only_loaded.is_bot(user_agent));
+        assert!(!only_loaded.is_bot(FIREFOX));
+
+        let mut rules = crate::rules::Rules::builtin();
+        rules.merge(loaded);
+        let config = Config {
+            rules: rules.compile().expect("compile"),
+            ..Config::default()
+        };
+        assert_eq!(analyzed(user_agent, &config).r#type, "bot");
+        assert_eq!(analyzed(FIREFOX, &config).r#type, "browser");
+    }
+
+    fn salted_on(date: &str, salt: &Arc<UniqSalt>) -> String {
+        let config = Config {
+            uniq_salt: Some(salt.clone()),
+            ..Config::default()
+        };
+        let mut line = Line {
+            ip: "203.0.113.7".to_string(),
+            user_agent: FIREFOX.to_string(),
+            date: date.to_string(),
+            ..Line::default()
+        };
+        analyze(&mut line, &config);
+        line.uniq
+    }
+
+    #[test]
+    fn uniq_salt_follows_the_event_date() {
+        let salt = Arc::new(UniqSalt::new(7, None).unwrap());
+        // 2024-01-07 is day 738892 since CE, the first of its 7-day period.
+        assert_eq!(
+            salted_on("2024-01-07", &salt),
+            salted_on("2024-01-13", &salt)
+        );
+        assert_ne!(
+            salted_on("2024-01-06", &salt),
+            salted_on("2024-01-07", &salt)
+        );
+        assert_ne!(
+            salted_on("2024-01-07", &salt),
+            analyzed(FIREFOX, &Config::default()).uniq
+        );
+    }
+
+    #[test]
+    fn uniq_salts_survive_a_restart() {
+        let path =
+            std::env::temp_dir().join(format!("banan-stats-salts-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let first = Arc::new(UniqSalt::new(1, Some(path.clone())).unwrap());
+        let uniqs: Vec<String> = ["2024-01-01", "2024-01-02", "2024-01-03"]
+            .iter()
+            .map(|date| salted_on(date, &first))
+            .collect();
+
+        let restarted = Arc::new(UniqSalt::new(1, Some(path.clone())).unwrap());
+        assert_eq!(restarted.salts.lock().unwrap().len(), 2);
+        assert_ne!(salted_on("2024-01-01", &restarted), uniqs[0]);
+        assert_eq!(salted_on("2024-01-02", &restarted), uniqs[1]);
+        assert_eq!(salted_on("2024-01-03", &restarted), uniqs[2]);
+
+        let other_period = Arc::new(UniqSalt::new(2, Some(path.clone())).unwrap());
+        assert!(other_period.salts.lock().unwrap().is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,72 +1,1121 @@
+use crate::query;
 use crate::state::AppState;
 use crate::store::Store;
 use axum::{
-    extract::{RawQuery, State},
-    http::HeaderMap,
-    response::{IntoResponse, Redirect, Response},
-    routing::get,
+    body::Body,
+    extract::{Path, RawQuery, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Redirect, Response,
+    },
+    routing::{get, post},
     Router,
 };
-use chrono::{Datelike, Duration, NaiveDate, Utc};
+use chrono::format::{Item, StrftimeItems};
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime, NaiveTime, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
 use duckdb::params_from_iter;
+use sha2::{Digest, Sha256};
+use flate2::{Compression, write::GzEncoder};
+use futures_util::future::join_all;
+use futures_util::StreamExt;
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::fmt::Write;
+use std::io::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration as StdDuration, Instant};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use tokio::time::MissedTickBehavior;
 
 const STYLE_CSS: &str = include_str!("../assets/style.css");
 const SCRIPT_JS: &str = include_str!("../assets/script.js");
 
-const YEAR_MONTH_FORMAT: &str = "%Y-%m";
+/// Columns that can be filtered on. Only these are ever interpolated into
+/// queries as column names.
+pub const ALLOWED_FILTERS: &[&str] = &[
+    "host",
+    "path",
+    "query",
+    "ref_domain",
+    "ref_channel",
+    "utm_source",
+    "utm_campaign",
+    "agent",
+    "type",
+    "os",
+    "device",
+    "protocol",
+    "tls_version",
+    "country",
+];
 
-const ALLOWED_FILTERS: &[&str] = &["host", "path", "query", "ref_domain", "agent", "type", "os"];
+/// Rows shown per table unless `limit` asks for more, up to `MAX_LIMIT`.
+const DEFAULT_LIMIT: usize = 10;
+const MAX_LIMIT: usize = 500;
+
+/// Longest filter value accepted, above the default ingest limits on stored
+/// values.
+const MAX_FILTER_LEN: usize = 4096;
+
+pub const DEFAULT_MONTH_FORMAT: &str = "%Y-%m";
+pub const DEFAULT_DAY_FORMAT: &str = "%b %-d";
+
+/// Dashboard options, fixed at startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Token required by every dashboard endpoint, as a bearer token or a
+    /// `token` cookie.
+    pub token: Option<String>,
+    /// `strftime` format of the month labels under each timeline.
+    pub month_format: String,
+    /// `strftime` format of the day shown when hovering a bar.
+    pub day_format: String,
+    /// Days covered by `/stats/prom` when no `from`/`to` is given.
+    pub prom_range_days: u32,
+    /// Render timeline sections without data as a placeholder instead of
+    /// omitting them.
+    pub show_empty_sections: bool,
+    /// Average the RSS readers headline over the buckets with readers only,
+    /// rather than over every bucket of the selection so far.
+    pub feed_average_active_only: bool,
+    /// First day of the buckets used by `granularity=week`.
+    pub week_start: Weekday,
+    /// Most bars drawn per timeline before adjacent buckets are merged; 0
+    /// disables thinning.
+    pub max_bars: usize,
+    /// Days of context drawn on each side of selections at most this long;
+    /// 0 disables it.
+    pub context_days: u32,
+    /// Zone of the stored dates, used to tell which day is today.
+    pub timezone: Tz,
+    /// Range shown when a request has no `from`/`to`.
+    pub default_range: DefaultRange,
+    /// Seconds a rendered `/stats` page is served again for the same query;
+    /// 0 disables the cache.
+    pub cache_ttl_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            token: None,
+            month_format: DEFAULT_MONTH_FORMAT.to_string(),
+            day_format: DEFAULT_DAY_FORMAT.to_string(),
+            prom_range_days: 30,
+            show_empty_sections: false,
+            feed_average_active_only: false,
+            week_start: Weekday::Mon,
+            max_bars: 800,
+            context_days: 0,
+            timezone: Tz::UTC,
+            default_range: DefaultRange::Year,
+            cache_ttl_secs: 60,
+        }
+    }
+}
+
+/// Range the dashboard and exports pick when `from`/`to` are missing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DefaultRange {
+    /// The current calendar year.
+    #[default]
+    Year,
+    /// The last 30 days, today included.
+    #[value(name = "last-30-days")]
+    Last30Days,
+    /// The last 90 days, today included.
+    #[value(name = "last-90-days")]
+    Last90Days,
+    /// From the first to the last stored day.
+    All,
+}
+
+impl Config {
+    /// Current time in `timezone`.
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().with_timezone(&self.timezone).naive_local()
+    }
+
+    /// Current date in `timezone`.
+    fn today(&self) -> NaiveDate {
+        self.now().date()
+    }
+}
+
+/// Most pages kept by `PageCache`.
+const PAGE_CACHE_ENTRIES: usize = 256;
+
+/// Recently rendered `/stats` pages by request, so that reloads of a popular
+/// page don't rerun every query. Pages expire after the configured TTL, and
+/// the least recently served page makes room when full.
+#[derive(Default)]
+pub struct PageCache {
+    pages: Mutex<HashMap<String, CachedPage>>,
+    /// Stored hosts as of a `Store::version`, needed to scope a request
+    /// before its page can be looked up.
+    hosts: Mutex<Option<(String, Vec<String>)>>,
+}
+
+struct CachedPage {
+    body: String,
+    rendered: Instant,
+    served: Instant,
+}
+
+impl PageCache {
+    fn get(&self, key: &str, ttl: StdDuration) -> Option<String> {
+        let mut pages = self.pages.lock().expect("page cache");
+        let page = pages.get_mut(key)?;
+        if page.rendered.elapsed() >= ttl {
+            pages.remove(key);
+            return None;
+        }
+        page.served = Instant::now();
+        Some(page.body.clone())
+    }
+
+    /// Stored hosts, queried again only once the store's version moved on.
+    async fn hosts(&self, store: &Store) -> Vec<String> {
+        let version = store.version();
+        if let Some((cached, hosts)) = &*self.hosts.lock().expect("host cache")
+            && *cached == version
+        {
+            return hosts.clone();
+        }
+        let Ok(hosts) = distinct_values(store, "host", None).await else {
+            return Vec::new();
+        };
+        *self.hosts.lock().expect("host cache") = Some((version, hosts.clone()));
+        hosts
+    }
+
+    fn insert(&self, key: String, body: String, ttl: StdDuration) {
+        let mut pages = self.pages.lock().expect("page cache");
+        pages.retain(|_, page| page.rendered.elapsed() < ttl);
+        if pages.len() >= PAGE_CACHE_ENTRIES
+            && let Some(oldest) = pages
+                .iter()
+                .min_by_key(|(_, page)| page.served)
+                .map(|(key, _)| key.clone())
+        {
+            pages.remove(&oldest);
+        }
+        let now = Instant::now();
+        pages.insert(
+            key,
+            CachedPage {
+                body,
+                rendered: now,
+                served: now,
+            },
+        );
+    }
+}
+
+/// Longest selection, in days, drawn with hourly buckets.
+const MAX_HOURLY_DAYS: i64 = 7;
+
+/// Size of the timeline buckets, picked with the `granularity` parameter,
+/// or its older name `period`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Period {
+    Hour,
+    Day,
+    Week,
+}
+
+impl Period {
+    /// Hourly buckets are only used for selections of at most
+    /// `MAX_HOURLY_DAYS`; longer ones fall back to days.
+    fn from_params(
+        params: &HashMap<String, Vec<String>>,
+        from_date: NaiveDate,
+        to_date: NaiveDate,
+    ) -> Self {
+        let granularity =
+            first_value(params, "granularity").or_else(|| first_value(params, "period"));
+        match granularity.as_deref() {
+            Some("hour") if (to_date - from_date).num_days() < MAX_HOURLY_DAYS => Period::Hour,
+            Some("week") => Period::Week,
+            _ => Period::Day,
+        }
+    }
+
+    /// SQL expression mapping the `date` and `time` columns to the start of
+    /// their bucket, as a timestamp.
+    fn bucket_sql(self, week_start: Weekday) -> String {
+        match self {
+            Period::Hour => "date + make_time(COALESCE(hour(time), 0), 0, 0)".to_string(),
+            Period::Day => "CAST(date AS TIMESTAMP)".to_string(),
+            Period::Week => {
+                // date_trunc('week') starts weeks on Monday; shift the dates so
+                // `week_start` lands on a Monday, then shift the result back.
+                let shift = monday_offset(week_start);
+                if shift == 0 {
+                    "CAST(date_trunc('week', date) AS TIMESTAMP)".to_string()
+                } else {
+                    format!(
+                        "CAST(CAST(date_trunc('week', date + {}) AS DATE) - {} AS TIMESTAMP)",
+                        shift, shift
+                    )
+                }
+            }
+        }
+    }
+
+    fn bucket_start(self, time: NaiveDateTime, week_start: Weekday) -> NaiveDateTime {
+        let midnight = time.date().and_time(NaiveTime::MIN);
+        match self {
+            Period::Hour => midnight + Duration::hours(i64::from(time.hour())),
+            Period::Day => midnight,
+            Period::Week => {
+                let days = (7 + time.weekday().num_days_from_monday()
+                    - week_start.num_days_from_monday())
+                    % 7;
+                midnight - Duration::days(days as i64)
+            }
+        }
+    }
+
+    fn step(self) -> Duration {
+        match self {
+            Period::Hour => Duration::hours(1),
+            Period::Day => Duration::days(1),
+            Period::Week => Duration::days(7),
+        }
+    }
+
+    /// Bucket start as shown in the JSON export.
+    fn format_bucket(self, start: NaiveDateTime) -> String {
+        match self {
+            Period::Hour => start.format("%Y-%m-%dT%H:00").to_string(),
+            Period::Day | Period::Week => start.format("%Y-%m-%d").to_string(),
+        }
+    }
+}
+
+/// Days from `week_start` to the next Monday (0 when it is Monday).
+fn monday_offset(week_start: Weekday) -> i64 {
+    ((7 - week_start.num_days_from_monday()) % 7) as i64
+}
+
+/// ISO week label, e.g. `2024-W12`, of the week starting at `start`. Weeks
+/// starting on another day than Monday take the number of the Monday they
+/// contain.
+fn week_label(start: NaiveDate, week_start: Weekday) -> String {
+    let monday = start + Duration::days(monday_offset(week_start));
+    monday.format("%G-W%V").to_string()
+}
+
+/// Parses a `strftime` format for the dashboard labels, rejecting formats
+/// chrono would fail to render.
+pub fn parse_date_format(value: &str) -> Result<String, String> {
+    if StrftimeItems::new(value).any(|item| matches!(item, Item::Error)) {
+        return Err(format!("invalid date format {:?}", value));
+    }
+    Ok(value.to_string())
+}
 
 pub fn router(state: AppState) -> Router {
     Router::new()
         .route("/stats", get(stats_handler))
         .route("/stats/favicon.ico", get(favicon_handler))
+        .route("/stats/stream", get(stream_handler))
+        .route("/stats/live", get(live_handler))
+        .route("/stats/prom", get(prom_handler))
+        .route("/stats.json", get(json_handler))
+        .route("/stats/summary.json", get(summary_json_handler))
+        .route("/stats/filters.json", get(filters_json_handler))
+        .route("/stats/export/:file", get(csv_handler))
+        .route("/query", post(query::query_handler))
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_token))
+        .layer(middleware::from_fn(gzip_response))
         .with_state(state)
 }
 
+/// Responses shorter than this are sent as they are.
+const GZIP_MIN_BYTES: usize = 1024;
+
+/// Gzips text responses for clients that accept it. Event streams are left
+/// alone, since buffering them would hold back every event.
+async fn gzip_response(request: Request, next: Next) -> Response {
+    let accepts_gzip = accepts_gzip(request.headers());
+    let response = next.run(request).await;
+    let compressible = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            (value.starts_with("text/") || value.starts_with("application/json"))
+                && !value.starts_with("text/event-stream")
+        });
+    if !accepts_gzip || !compressible || response.headers().contains_key(header::CONTENT_ENCODING) {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let bytes = match axum::body::to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+    parts
+        .headers
+        .insert(header::VARY, "Accept-Encoding".parse().expect("header"));
+    if bytes.len() < GZIP_MIN_BYTES {
+        return Response::from_parts(parts, Body::from(bytes));
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let compressed = match encoder.write_all(&bytes).and_then(|_| encoder.finish()) {
+        Ok(compressed) => compressed,
+        Err(_) => return Response::from_parts(parts, Body::from(bytes)),
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_ENCODING, "gzip".parse().expect("header"));
+    Response::from_parts(parts, Body::from(compressed))
+}
+
+/// Whether `Accept-Encoding` lists `gzip` (or `*`) without `q=0`.
+fn accepts_gzip(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|coding| {
+            let mut parts = coding.split(';').map(str::trim);
+            let name = parts.next().unwrap_or_default();
+            let refused = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            (name.eq_ignore_ascii_case("gzip") || name == "*") && !refused
+        })
+}
+
+/// Turns away requests without the dashboard token, when one is configured.
+pub async fn require_token(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if !is_authorized(request.headers(), state.dashboard.token.as_deref()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
 async fn favicon_handler() -> impl IntoResponse {
     axum::http::StatusCode::NO_CONTENT
 }
 
-async fn stats_handler(
+/// Streams every newly ingested line as a JSON server-sent event. A client
+/// that falls more than the channel capacity of inserts behind is
+/// disconnected rather than slowing down ingestion.
+async fn stream_handler(State(state): State<AppState>) -> Response {
+    let batches = futures_util::stream::unfold(state.store.subscribe(), |mut rx| async move {
+        match rx.recv().await {
+            Ok(lines) => Some((lines, rx)),
+            Err(broadcast::error::RecvError::Lagged(_) | broadcast::error::RecvError::Closed) => {
+                None
+            }
+        }
+    });
+    let events = batches.flat_map(|lines| {
+        let events: Vec<_> = lines
+            .iter()
+            .map(|line| Event::default().json_data(line))
+            .collect();
+        futures_util::stream::iter(events)
+    });
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Window of `/stats/live` when no `minutes` is given, and the longest one.
+const LIVE_MINUTES: u32 = 5;
+const MAX_LIVE_MINUTES: u32 = 24 * 60;
+
+/// How often `/stats/live` recounts while no events come in.
+const LIVE_INTERVAL: StdDuration = StdDuration::from_secs(5);
+
+/// Streams the unique browser visitors of the last `minutes` as server-sent
+/// events: once right away, then after each commit of new events and at
+/// least every `LIVE_INTERVAL`. Column filters narrow the count as on
+/// `/stats`.
+async fn live_handler(State(state): State<AppState>, RawQuery(raw): RawQuery) -> Response {
+    let params = parse_query(raw.unwrap_or_default());
+    let minutes = first_value(&params, "minutes")
+        .and_then(|value| value.parse::<u32>().ok())
+        .unwrap_or(LIVE_MINUTES)
+        .clamp(1, MAX_LIVE_MINUTES);
+    let filters = extract_filters(&params);
+    let rx = state.store.subscribe();
+    let mut interval = tokio::time::interval(LIVE_INTERVAL);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let events = futures_util::stream::unfold(
+        (state, filters, rx, interval),
+        move |(state, filters, mut rx, mut interval)| async move {
+            tokio::select! {
+                _ = interval.tick() => {}
+                received = rx.recv() => {
+                    if let Err(broadcast::error::RecvError::Closed) = received {
+                        return None;
+                    }
+                    // Count once for every insert committed meanwhile.
+                    while !matches!(
+                        rx.try_recv(),
+                        Err(TryRecvError::Empty | TryRecvError::Closed)
+                    ) {}
+                    interval.reset();
+                }
+            }
+            let visitors = live_visitors(&state, &filters, minutes)
+                .await
+                .unwrap_or_default();
+            let event = Event::default().json_data(serde_json::json!({
+                "visitors": visitors,
+                "minutes": minutes,
+            }));
+            Some((event, (state, filters, rx, interval)))
+        },
+    );
+    Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+}
+
+/// Unique browser visitors whose hits fall within the last `minutes`.
+async fn live_visitors(
+    state: &AppState,
+    filters: &HashMap<String, String>,
+    minutes: u32,
+) -> Result<i64, anyhow::Error> {
+    let now = state.dashboard.now();
+    let since = now - Duration::minutes(minutes as i64);
+    let (where_clause, mut args) = build_where(
+        &since.format("%Y-%m-%d").to_string(),
+        &now.format("%Y-%m-%d").to_string(),
+        filters,
+    );
+    args.push(since.format("%Y-%m-%d %H:%M:%S").to_string());
+    let query = format!(
+        "WITH subq AS (
+            SELECT MAX(mult) AS mult
+            FROM stats
+            WHERE type = 'browser' AND {} AND date + time >= CAST(? AS TIMESTAMP)
+            GROUP BY uniq
+        )
+        SELECT COALESCE(SUM(mult), 0) FROM subq",
+        where_clause
+    );
+    state
+        .store
+        .with_conn(move |conn| {
+            let params = params_from_iter(args.iter().map(|s| s.as_str()));
+            Ok(conn.query_row(&query, params, |row| row.get(0))?)
+        })
+        .await
+}
+
+/// Renders the key aggregates of a date range as Prometheus gauges. Only the
+/// top entries of each table are exported, which bounds label cardinality.
+async fn prom_handler(State(state): State<AppState>, RawQuery(raw): RawQuery) -> Response {
+    let params = parse_query(raw.unwrap_or_default());
+    let parse_date = |key| {
+        first_value(&params, key).and_then(|v| NaiveDate::parse_from_str(&v, "%Y-%m-%d").ok())
+    };
+    let (from_date, to_date) = match (parse_date("from"), parse_date("to")) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            let today = state.dashboard.today();
+            let days = i64::from(state.dashboard.prom_range_days.max(1));
+            (today - Duration::days(days - 1), today)
+        }
+    };
+    let filters = extract_filters(&params);
+    let (where_clause, args) = build_where(
+        &from_date.format("%Y-%m-%d").to_string(),
+        &to_date.format("%Y-%m-%d").to_string(),
+        &filters,
+    );
+
+    match render_prom(&state.store, &where_clause, &args).await {
+        Ok(body) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "Content-Type",
+                "text/plain; version=0.0.4; charset=utf-8"
+                    .parse()
+                    .expect("header"),
+            );
+            (headers, body).into_response()
+        }
+        Err(err) => {
+            eprintln!("prom export failed: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Returns the data behind `/stats` as JSON: the same range, filters and
+/// `granularity` give the same numbers as the page.
+async fn json_handler(State(state): State<AppState>, RawQuery(raw): RawQuery) -> Response {
+    let params = parse_query(raw.unwrap_or_default());
+    let parse_date = |key| {
+        first_value(&params, key).and_then(|v| NaiveDate::parse_from_str(&v, "%Y-%m-%d").ok())
+    };
+    let (from_date, to_date) = match (parse_date("from"), parse_date("to")) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            return redirect_to_default_range("/stats.json", &params, &state)
+                .await
+                .into_response();
+        }
+    };
+
+    match render_json(&state, &params, from_date, to_date).await {
+        Ok(body) => {
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", "application/json".parse().expect("header"));
+            (headers, body.to_string()).into_response()
+        }
+        Err(err) => {
+            eprintln!("json export failed: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Serves just the unique visitors of each type over the range, e.g. for a
+/// badge, without the timelines and tables of `/stats.json`. Column filters
+/// narrow it as on `/stats`.
+async fn summary_json_handler(State(state): State<AppState>, RawQuery(raw): RawQuery) -> Response {
+    let params = parse_query(raw.unwrap_or_default());
+    let (from_str, to_str) = match (first_value(&params, "from"), first_value(&params, "to")) {
+        (Some(from), Some(to))
+            if NaiveDate::parse_from_str(&from, "%Y-%m-%d").is_ok()
+                && NaiveDate::parse_from_str(&to, "%Y-%m-%d").is_ok() =>
+        {
+            (from, to)
+        }
+        _ => {
+            return redirect_to_default_range("/stats/summary.json", &params, &state)
+                .await
+                .into_response();
+        }
+    };
+
+    let filters = extract_filters(&params);
+    let (where_clause, args) = build_where(&from_str, &to_str, &filters);
+    match total_uniq(&state.store, &where_clause, &args).await {
+        Ok(totals) => {
+            let total = |typ: &str| totals.get(typ).copied().unwrap_or(0);
+            let body = serde_json::json!({
+                "from": from_str,
+                "to": to_str,
+                "unique": total("browser"),
+                "feed": total("feed"),
+                "bot": total("bot"),
+            });
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", "application/json".parse().expect("header"));
+            (headers, body.to_string()).into_response()
+        }
+        Err(err) => {
+            eprintln!("summary export failed: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Serves the distinct values of every filterable column within the range,
+/// e.g. to fill dropdowns. Other filters don't narrow the lists.
+async fn filters_json_handler(State(state): State<AppState>, RawQuery(raw): RawQuery) -> Response {
+    let params = parse_query(raw.unwrap_or_default());
+    let parse_date = |key| {
+        first_value(&params, key).and_then(|v| NaiveDate::parse_from_str(&v, "%Y-%m-%d").ok())
+    };
+    let (from_date, to_date) = match (parse_date("from"), parse_date("to")) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            return redirect_to_default_range("/stats/filters.json", &params, &state)
+                .await
+                .into_response();
+        }
+    };
+
+    let results = join_all(
+        ALLOWED_FILTERS
+            .iter()
+            .map(|column| distinct_values(&state.store, column, Some((from_date, to_date)))),
+    )
+    .await;
+    let mut values = serde_json::Map::new();
+    for (column, result) in ALLOWED_FILTERS.iter().zip(results) {
+        match result {
+            Ok(column_values) => {
+                values.insert(column.to_string(), column_values.into());
+            }
+            Err(err) => {
+                eprintln!("filters export failed: {}", err);
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        }
+    }
+    let body = serde_json::json!({
+        "from": from_date.format("%Y-%m-%d").to_string(),
+        "to": to_date.format("%Y-%m-%d").to_string(),
+        "values": values,
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert("Content-Type", "application/json".parse().expect("header"));
+    (headers, body.to_string()).into_response()
+}
+
+async fn render_json(
+    state: &AppState,
+    params: &HashMap<String, Vec<String>>,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+) -> Result<serde_json::Value, anyhow::Error> {
+    let filters = extract_filters(params);
+    let (where_clause, args) = build_where(
+        &from_date.format("%Y-%m-%d").to_string(),
+        &to_date.format("%Y-%m-%d").to_string(),
+        &filters,
+    );
+    let period = Period::from_params(params, from_date, to_date);
+    let bucket = period.bucket_sql(state.dashboard.week_start);
+    let rollup = rollup_rows(period, &filters);
+    let mut visits =
+        visits_by_type_date(&state.store, &bucket, rollup, &where_clause, &args).await?;
+    let totals = total_uniq(&state.store, &where_clause, &args).await?;
+
+    let mut timelines = serde_json::Map::new();
+    let mut type_totals = serde_json::Map::new();
+    let types: Vec<String> = timeline_types(visits.keys().chain(totals.keys()))
+        .into_iter()
+        .map(str::to_string)
+        .collect();
+    for typ in &types {
+        let typ = typ.as_str();
+        let mut counts: Vec<_> = visits.remove(typ).unwrap_or_default().into_iter().collect();
+        counts.sort();
+        let counts: Vec<_> = counts
+            .into_iter()
+            .map(|(date, count)| serde_json::json!({"date": period.format_bucket(date), "count": count}))
+            .collect();
+        timelines.insert(typ.to_string(), counts.into());
+        type_totals.insert(typ.to_string(), (*totals.get(typ).unwrap_or(&0)).into());
+    }
+
+    let mut tables = serde_json::Map::new();
+    for spec in table_specs(&where_clause, params) {
+        let rows: Vec<_> = table_rows(
+            &state.store,
+            &spec,
+            &args,
+            table_limit(params),
+            table_offset(params, spec.name),
+            TableSort::from_params(params),
+        )
+            .await?
+            .into_iter()
+            .map(|row| {
+                // The `Others` row has no value.
+                let value = Some(row.value).filter(|v| !v.is_empty());
+                serde_json::json!({"value": value, "count": row.count})
+            })
+            .collect();
+        tables.insert(spec.name.to_string(), rows.into());
+    }
+
+    Ok(serde_json::json!({
+        "from": from_date.format("%Y-%m-%d").to_string(),
+        "to": to_date.format("%Y-%m-%d").to_string(),
+        "filters": filters,
+        "timelines": timelines,
+        "totals": type_totals,
+        "tables": tables,
+    }))
+}
+
+/// Serves one table as CSV, e.g. `/stats/export/paths.csv`, with the same
+/// rows as the page and an `Others` row when there are more values.
+async fn csv_handler(
     State(state): State<AppState>,
+    Path(file): Path<String>,
     RawQuery(raw): RawQuery,
 ) -> Response {
     let params = parse_query(raw.unwrap_or_default());
+    let parse_date = |key| {
+        first_value(&params, key).and_then(|v| NaiveDate::parse_from_str(&v, "%Y-%m-%d").ok())
+    };
+    let (from_date, to_date) = match (parse_date("from"), parse_date("to")) {
+        (Some(from), Some(to)) => (from, to),
+        _ => {
+            return redirect_to_default_range(&format!("/stats/export/{}", file), &params, &state)
+                .await
+                .into_response();
+        }
+    };
+    let filters = extract_filters(&params);
+    let (where_clause, args) = build_where(
+        &from_date.format("%Y-%m-%d").to_string(),
+        &to_date.format("%Y-%m-%d").to_string(),
+        &filters,
+    );
+    let Some(spec) = file.strip_suffix(".csv").and_then(|name| {
+        table_specs(&where_clause, &params)
+            .into_iter()
+            .find(|spec| spec.name == name)
+    }) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let sort = TableSort::from_params(&params);
+    let (limit, offset) = (table_limit(&params), table_offset(&params, spec.name));
+    match table_rows(&state.store, &spec, &args, limit, offset, sort).await {
+        Ok(rows) => {
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "Content-Type",
+                "text/csv; charset=utf-8".parse().expect("header"),
+            );
+            headers.insert(
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{}\"", file)
+                    .parse()
+                    .expect("header"),
+            );
+            (headers, render_csv(&rows)).into_response()
+        }
+        Err(err) => {
+            eprintln!("csv export failed: {}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn render_csv(rows: &[RowCount]) -> String {
+    let total = rows.iter().map(|row| row.count).sum::<i64>().max(1);
+    let mut out = String::from("value,count,percent\r\n");
+    for row in rows {
+        let value = if row.value.is_empty() {
+            "Others"
+        } else {
+            &row.value
+        };
+        let _ = write!(
+            out,
+            "{},{},{:.1}\r\n",
+            csv_field(value),
+            row.count,
+            (row.count as f64) * 100.0 / (total as f64)
+        );
+    }
+    out
+}
+
+/// Quotes a CSV field when it holds a separator, quote or line break.
+fn csv_field(value: &str) -> Cow<'_, str> {
+    if value.contains([',', '"', '\r', '\n']) {
+        Cow::Owned(format!("\"{}\"", value.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+async fn render_prom(
+    store: &Store,
+    where_clause: &str,
+    args: &[String],
+) -> Result<String, anyhow::Error> {
+    let mut out = String::new();
+
+    append(
+        &mut out,
+        "# HELP banan_stats_uniques Unique visitors in the range by type.",
+    );
+    append(&mut out, "# TYPE banan_stats_uniques gauge");
+    let mut totals: Vec<_> = total_uniq(store, where_clause, args)
+        .await?
+        .into_iter()
+        .collect();
+    totals.sort();
+    for (typ, count) in &totals {
+        append(
+            &mut out,
+            &format!(
+                "banan_stats_uniques{{type=\"{}\"}} {}",
+                prom_label(typ),
+                count
+            ),
+        );
+    }
+
+    let browser_where = format!("{} AND type = 'browser'", where_clause);
+    for (metric, help, column) in [
+        (
+            "banan_stats_top_path_hits",
+            "Hits of the top browser paths.",
+            "path",
+        ),
+        (
+            "banan_stats_top_referrer_hits",
+            "Hits of the top browser referrer domains.",
+            "ref_domain",
+        ),
+    ] {
+        append(&mut out, &format!("# HELP {} {}", metric, help));
+        append(&mut out, &format!("# TYPE {} gauge", metric));
+        for row in top_n(
+            store,
+            column,
+            &browser_where,
+            args,
+            DEFAULT_LIMIT,
+            0,
+            TableSort::Count,
+        )
+        .await?
+        {
+            if row.value.is_empty() {
+                continue;
+            }
+            append(
+                &mut out,
+                &format!(
+                    "{}{{{}=\"{}\"}} {}",
+                    metric,
+                    column,
+                    prom_label(&row.value),
+                    row.count
+                ),
+            );
+        }
+    }
+
+    append(
+        &mut out,
+        "# HELP banan_stats_top_agent_uniques Unique visitors of the top agents by type.",
+    );
+    append(&mut out, "# TYPE banan_stats_top_agent_uniques gauge");
+    let typ_where = format!("{} AND type = ?", where_clause);
+    for typ in timeline_types(totals.iter().map(|(typ, _)| typ)) {
+        let mut typ_args = args.to_vec();
+        typ_args.push(typ.to_string());
+        for row in top_n_uniq(
+            store,
+            "agent",
+            &typ_where,
+            &typ_args,
+            DEFAULT_LIMIT,
+            0,
+            TableSort::Count,
+        )
+        .await?
+        {
+            if row.value.is_empty() {
+                continue;
+            }
+            append(
+                &mut out,
+                &format!(
+                    "banan_stats_top_agent_uniques{{type=\"{}\",agent=\"{}\"}} {}",
+                    prom_label(typ),
+                    prom_label(&row.value),
+                    row.count
+                ),
+            );
+        }
+    }
+
+    Ok(out)
+}
+
+/// Escapes a Prometheus label value.
+fn prom_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Whether the request carries `token` in an `Authorization: Bearer` header
+/// or a `token` cookie; always true when no token is configured.
+fn is_authorized(headers: &HeaderMap, token: Option<&str>) -> bool {
+    let Some(token) = token else { return true };
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|value| value == token);
+    bearer
+        || headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .any(|(name, value)| name == "token" && value == token)
+}
+
+async fn stats_handler(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    RawQuery(raw): RawQuery,
+) -> Response {
+    let mut params = parse_query(raw.unwrap_or_default());
+    let hosts = state.pages.hosts(&state.store).await;
+    let mut header_scoped = false;
+    // Host names are case-insensitive, while the filter compares the stored
+    // spelling.
+    if !params.contains_key("host")
+        && let Some(host) = request_host(&headers)
+        && let Some(stored) = hosts
+            .iter()
+            .find(|stored| stored.eq_ignore_ascii_case(&host))
+    {
+        params.insert("host".to_string(), vec![stored.clone()]);
+        header_scoped = true;
+    }
+    // Without a host, several hosts get a summary instead of their traffic
+    // mixed together; `host=*` asks for the combined view.
+    let host_summary =
+        hosts.len() > 1 && first_value(&params, "host").is_none_or(|host| host.is_empty());
     let from_str = first_value(&params, "from");
     let to_str = first_value(&params, "to");
 
     let (from_str, to_str) = match (from_str, to_str) {
         (Some(from), Some(to)) => (from, to),
-        _ => return redirect_to_year("/stats", &params).into_response(),
+        _ => {
+            return redirect_to_default_range("/stats", &params, &state)
+                .await
+                .into_response();
+        }
     };
 
     let from_date = match NaiveDate::parse_from_str(&from_str, "%Y-%m-%d") {
         Ok(val) => val,
-        Err(_) => return redirect_to_year("/stats", &params).into_response(),
+        Err(_) => {
+            return redirect_to_default_range("/stats", &params, &state)
+                .await
+                .into_response();
+        }
     };
     let to_date = match NaiveDate::parse_from_str(&to_str, "%Y-%m-%d") {
-        Ok(val) => val,
-        Err(_) => return redirect_to_year("/stats", &params).into_response(),
+        Ok(val) if val >= from_date => val,
+        _ => {
+            return redirect_to_default_range("/stats", &params, &state)
+                .await
+                .into_response();
+        }
     };
 
+    // The Host header picks the host scope and the first-run sample URL.
+    // With the store's version in the key, new events render a new page.
+    let cache_ttl = StdDuration::from_secs(state.dashboard.cache_ttl_secs);
+    let cache_key = format!(
+        "{} {} {}",
+        headers
+            .get(header::HOST)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default(),
+        state.store.version(),
+        encode_params(&params)
+    );
+    // Today is in the tag too, since the quick ranges and headline averages
+    // move with it.
+    let etag = format!(
+        "\"{}\"",
+        hex::encode(&Sha256::digest(format!("{} {}", state.dashboard.today(), cache_key))[..8])
+    );
+    if matches_etag(&headers, &etag) {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ETAG, etag.parse().expect("header"));
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+    if !cache_ttl.is_zero()
+        && let Some(body) = state.pages.get(&cache_key, cache_ttl)
+    {
+        return html_response(body, &etag);
+    }
+
     let filters = extract_filters(&params);
     let (where_clause, args) = build_where(&from_str, &to_str, &filters);
 
-    let (min_date, max_date) = match min_max_date(&state.store).await {
+    let today = state.dashboard.today();
+    let (min_date, max_date) = match min_max_date(&state.store, today).await {
         Ok(val) => val,
-        Err(_) => default_year_range(),
+        Err(_) => year_range(today),
     };
-    let hosts = distinct_hosts(&state.store).await.unwrap_or_default();
+    let has_rows = has_rows(&state.store).await.unwrap_or(true);
 
-    let visits = visits_by_type_date(&state.store, &where_clause, &args)
+    let period = Period::from_params(&params, from_date, to_date);
+    let bucket = period.bucket_sql(state.dashboard.week_start);
+    let rollup = rollup_rows(period, &filters);
+    let comparing = compare_range(&params).is_some();
+    let (timeline_from, timeline_to) =
+        timeline_range(&state.dashboard, from_date, to_date, comparing);
+    let (timeline_where, timeline_args) = if (timeline_from, timeline_to) == (from_date, to_date) {
+        (where_clause.clone(), args.clone())
+    } else {
+        build_where(
+            &timeline_from.format("%Y-%m-%d").to_string(),
+            &timeline_to.format("%Y-%m-%d").to_string(),
+            &filters,
+        )
+    };
+    let visits = visits_by_type_date(
+        &state.store,
+        &bucket,
+        rollup,
+        &timeline_where,
+        &timeline_args,
+    )
+    .await
+    .unwrap_or_default();
+    let totals = total_uniq(&state.store, &where_clause, &args)
         .await
         .unwrap_or_default();
-    let totals = total_uniq(&state.store, &where_clause, &args)
+    let bounce_rate = bounce_rate(&state.store, &where_clause, &args)
         .await
         .unwrap_or_default();
+    // Headlines show their change against the preceding range of equal
+    // length, unless another range is already compared.
+    let previous_totals = if comparing {
+        None
+    } else {
+        let (prev_from, prev_to) = previous_range(from_date, to_date);
+        let (prev_where, prev_args) = build_where(
+            &prev_from.format("%Y-%m-%d").to_string(),
+            &prev_to.format("%Y-%m-%d").to_string(),
+            &filters,
+        );
+        total_uniq(&state.store, &prev_where, &prev_args).await.ok()
+    };
+    let comparison = match compare_range(&params) {
+        Some((from2, to2)) => {
+            let from2_str = from2.format("%Y-%m-%d").to_string();
+            let to2_str = to2.format("%Y-%m-%d").to_string();
+            let (where_clause2, args2) = build_where(&from2_str, &to2_str, &filters);
+            Some(Comparison {
+                data: visits_by_type_date(&state.store, &bucket, rollup, &where_clause2, &args2)
+                    .await
+                    .unwrap_or_default(),
+                totals: total_uniq(&state.store, &where_clause2, &args2)
+                    .await
+                    .unwrap_or_default(),
+                from_date: from2,
+                to_date: to2,
+            })
+        }
+        None => None,
+    };
 
     let mut body = String::new();
     append(&mut body, "<!DOCTYPE html>");
@@ -101,31 +1150,65 @@ async fn stats_handler(
         min_date,
         max_date,
     );
+    append_quick_ranges(&mut body, &params, from_date, to_date, today);
+    append_range_form(&mut body, &params, from_date, to_date);
     append_host_filters(&mut body, &params, &hosts);
-    append_active_filters(&mut body, &params);
+    append_active_filters(&mut body, &params, header_scoped);
     append(&mut body, "</div>");
 
-    append_timelines(
-        &mut body,
-        &visits,
-        &totals,
-        &params,
-        from_date,
-        to_date,
-    );
-    append_tables(&mut body, &state.store, &where_clause, &args, &params).await;
+    if !has_rows {
+        append_first_run(&mut body, &headers, state.ingest.api_key.is_some());
+    } else if host_summary {
+        let uniques = host_uniq(&state.store, &where_clause, &args)
+            .await
+            .unwrap_or_default();
+        append_host_summary(&mut body, &params, &hosts, &uniques);
+    } else {
+        append_timelines(
+            &mut body,
+            &state.dashboard,
+            &visits,
+            &totals,
+            bounce_rate,
+            previous_totals.as_ref(),
+            comparison.as_ref(),
+            &params,
+            from_date,
+            to_date,
+        );
+        append_tables(&mut body, &state.store, &where_clause, &args, &params).await;
+    }
 
     append(&mut body, "</body>");
     append(&mut body, "</html>");
 
+    if !cache_ttl.is_zero() {
+        state.pages.insert(cache_key, body.clone(), cache_ttl);
+    }
+    html_response(body, &etag)
+}
+
+fn html_response(body: String, etag: &str) -> Response {
     let mut headers = HeaderMap::new();
     headers.insert(
         "Content-Type",
         "text/html; charset=utf-8".parse().expect("header"),
     );
+    headers.insert(header::ETAG, etag.parse().expect("header"));
     (headers, body).into_response()
 }
 
+/// Whether `If-None-Match` lists `etag`, weakly compared, or is `*`.
+fn matches_etag(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
+}
+
 fn append(out: &mut String, value: &str) {
     let _ = writeln!(out, "{}", value);
 }
@@ -141,14 +1224,36 @@ fn parse_query(raw: String) -> HashMap<String, Vec<String>> {
     params
 }
 
+/// Host the request was addressed to, lowercased and without its port.
+fn request_host(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get("Host")?.to_str().ok()?.trim();
+    let host = match value.strip_prefix('[') {
+        Some(rest) => rest.split(']').next()?,
+        None => value.split(':').next()?,
+    };
+    (!host.is_empty()).then(|| host.to_ascii_lowercase())
+}
+
 fn first_value(params: &HashMap<String, Vec<String>>, key: &str) -> Option<String> {
     params.get(key).and_then(|vals| vals.get(0)).cloned()
 }
 
-fn redirect_to_year(path: &str, params: &HashMap<String, Vec<String>>) -> Redirect {
-    let now = Utc::now().date_naive();
-    let from = NaiveDate::from_ymd_opt(now.year(), 1, 1).unwrap();
-    let to = NaiveDate::from_ymd_opt(now.year(), 12, 31).unwrap();
+/// Redirects to `path` with `params` and the `--default-range`, for requests
+/// without a valid `from`/`to`.
+async fn redirect_to_default_range(
+    path: &str,
+    params: &HashMap<String, Vec<String>>,
+    state: &AppState,
+) -> Redirect {
+    let today = state.dashboard.today();
+    let (from, to) = match state.dashboard.default_range {
+        DefaultRange::Year => year_range(today),
+        DefaultRange::Last30Days => (today - Duration::days(29), today),
+        DefaultRange::Last90Days => (today - Duration::days(89), today),
+        DefaultRange::All => min_max_date(&state.store, today)
+            .await
+            .unwrap_or_else(|_| year_range(today)),
+    };
     let mut new_params = clone_params(params);
     new_params.insert("from".to_string(), vec![from.format("%Y-%m-%d").to_string()]);
     new_params.insert("to".to_string(), vec![to.format("%Y-%m-%d").to_string()]);
@@ -156,13 +1261,36 @@ fn redirect_to_year(path: &str, params: &HashMap<String, Vec<String>>) -> Redire
     Redirect::to(&format!("{}?{}", path, query))
 }
 
+/// Parses the optional second range (`from2`/`to2`) overlaid on the timelines.
+fn compare_range(params: &HashMap<String, Vec<String>>) -> Option<(NaiveDate, NaiveDate)> {
+    let from = NaiveDate::parse_from_str(&first_value(params, "from2")?, "%Y-%m-%d").ok()?;
+    let to = NaiveDate::parse_from_str(&first_value(params, "to2")?, "%Y-%m-%d").ok()?;
+    if from > to {
+        return None;
+    }
+    Some((from, to))
+}
+
+/// Whether a filter or search value is worth querying for: values longer
+/// than `MAX_FILTER_LEN` bytes or with control characters are junk from
+/// crafted URLs and are dropped.
+fn is_valid_filter_value(value: &str) -> bool {
+    value.len() <= MAX_FILTER_LEN && !value.chars().any(char::is_control)
+}
+
 fn extract_filters(params: &HashMap<String, Vec<String>>) -> HashMap<String, String> {
     let mut filters = HashMap::new();
     for (key, values) in params {
         if key == "from" || key == "to" {
             continue;
         }
-        if !ALLOWED_FILTERS.contains(&key.as_str()) || values.is_empty() {
+        let allowed = ALLOWED_FILTERS.contains(&key.as_str()) || key == "exclude_type";
+        if !allowed
+            || values.is_empty()
+            || values[0].is_empty()
+            || (key == "host" && values[0] == "*")
+            || !is_valid_filter_value(&values[0])
+        {
             continue;
         }
         filters.insert(key.clone(), values[0].clone());
@@ -170,24 +1298,29 @@ fn extract_filters(params: &HashMap<String, Vec<String>>) -> HashMap<String, Str
     filters
 }
 
-fn build_where(from_str: &str, to_str: &str, filters: &HashMap<String, String>) -> (String, Vec<String>) {
+pub fn build_where(from_str: &str, to_str: &str, filters: &HashMap<String, String>) -> (String, Vec<String>) {
     let mut where_parts = vec!["date >= ?".to_string(), "date <= ?".to_string()];
     let mut args = vec![from_str.to_string(), to_str.to_string()];
     for (key, val) in filters {
-        where_parts.push(format!("{} = ?", key));
+        if key == "exclude_type" {
+            where_parts.push("type != ?".to_string());
+        } else {
+            where_parts.push(format!("{} = ?", key));
+        }
         args.push(val.clone());
     }
     (where_parts.join(" AND "), args)
 }
 
-async fn min_max_date(store: &Store) -> Result<(NaiveDate, NaiveDate), anyhow::Error> {
+async fn min_max_date(
+    store: &Store,
+    today: NaiveDate,
+) -> Result<(NaiveDate, NaiveDate), anyhow::Error> {
     store
-        .with_conn(|conn| {
+        .with_conn(move |conn| {
             let mut stmt = conn.prepare("SELECT min(date), max(date) FROM stats")?;
             let mut rows = stmt.query([])?;
-            let now = Utc::now().date_naive();
-            let mut min = NaiveDate::from_ymd_opt(now.year(), 1, 1).unwrap();
-            let mut max = NaiveDate::from_ymd_opt(now.year(), 12, 31).unwrap();
+            let (mut min, mut max) = year_range(today);
             if let Some(row) = rows.next()? {
                 let min_date: Option<NaiveDate> = row.get(0)?;
                 let max_date: Option<NaiveDate> = row.get(1)?;
@@ -203,50 +1336,209 @@ async fn min_max_date(store: &Store) -> Result<(NaiveDate, NaiveDate), anyhow::E
         .await
 }
 
-fn default_year_range() -> (NaiveDate, NaiveDate) {
-    let now = Utc::now().date_naive();
+/// Whether anything was ever stored, as opposed to nothing matching.
+async fn has_rows(store: &Store) -> Result<bool, anyhow::Error> {
+    store
+        .with_conn(|conn| {
+            Ok(conn.query_row("SELECT EXISTS (SELECT 1 FROM stats)", [], |row| row.get(0))?)
+        })
+        .await
+}
+
+/// Stands in for the timelines and tables until the first event arrives,
+/// with a request that would store one.
+fn append_first_run(out: &mut String, headers: &HeaderMap, needs_key: bool) {
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("localhost:7070");
+    append(out, "<div class=first_run>");
+    append(out, "<h1>No data yet</h1>");
+    append(
+        out,
+        "<p>Start sending events to <code>/ingest</code>, one JSON object per line:</p>",
+    );
+    append(
+        out,
+        &format!(
+            "<pre>curl -H 'Content-Type: application/x-ndjson'{} \\\n  \
+             --data-binary '{{\"host\": \"example.com\", \"path\": \"/\", \
+             \"userAgent\": \"Mozilla/5.0\"}}' \\\n  http://{}/ingest</pre>",
+            if needs_key {
+                " -H 'X-Api-Key: KEY'"
+            } else {
+                ""
+            },
+            html_escape(host)
+        ),
+    );
+    append(out, "</div>");
+}
+
+/// First and last day of the year of `today`.
+fn year_range(today: NaiveDate) -> (NaiveDate, NaiveDate) {
     (
-        NaiveDate::from_ymd_opt(now.year(), 1, 1).unwrap(),
-        NaiveDate::from_ymd_opt(now.year(), 12, 31).unwrap(),
+        NaiveDate::from_ymd_opt(today.year(), 1, 1).unwrap(),
+        NaiveDate::from_ymd_opt(today.year(), 12, 31).unwrap(),
     )
 }
 
-async fn distinct_hosts(store: &Store) -> Result<Vec<String>, anyhow::Error> {
+/// Sorted distinct non-empty values of `column`, which must be one of
+/// `ALLOWED_FILTERS`, over all rows or only those dated within `range`.
+async fn distinct_values(
+    store: &Store,
+    column: &str,
+    range: Option<(NaiveDate, NaiveDate)>,
+) -> Result<Vec<String>, anyhow::Error> {
+    if !ALLOWED_FILTERS.contains(&column) {
+        anyhow::bail!("unknown column {:?}", column);
+    }
+    let mut query = format!(
+        "SELECT DISTINCT CAST({col} AS VARCHAR) AS value FROM stats WHERE {col} IS NOT NULL",
+        col = column
+    );
+    let mut args = Vec::new();
+    if let Some((from_date, to_date)) = range {
+        query.push_str(" AND date >= ? AND date <= ?");
+        args.push(from_date.format("%Y-%m-%d").to_string());
+        args.push(to_date.format("%Y-%m-%d").to_string());
+    }
+    query.push_str(" ORDER BY value");
     store
-        .with_conn(|conn| {
-            let mut stmt = conn.prepare(
-                "SELECT DISTINCT host FROM stats WHERE host IS NOT NULL ORDER BY host",
-            )?;
-            let mut rows = stmt.query([])?;
-            let mut hosts = Vec::new();
+        .with_conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let params = params_from_iter(args.iter().map(|s| s.as_str()));
+            let mut rows = stmt.query(params)?;
+            let mut values = Vec::new();
             while let Some(row) = rows.next()? {
-                let host: Option<String> = row.get(0)?;
-                if let Some(host) = host {
-                    if !host.is_empty() {
-                        hosts.push(host);
-                    }
+                let value: String = row.get(0)?;
+                if !value.is_empty() {
+                    values.push(value);
                 }
             }
-            Ok(hosts)
+            Ok(values)
         })
         .await
 }
 
+/// The `stats_daily` rows the timelines can be read from, as a condition on
+/// their `host`, or `None` when they need the raw rows: the rollup only holds
+/// daily counts, across hosts and per host.
+fn rollup_rows(period: Period, filters: &HashMap<String, String>) -> Option<&'static str> {
+    if period != Period::Day || filters.keys().any(|key| key != "host") {
+        return None;
+    }
+    // A `host` filter picks its own rows.
+    Some(if filters.contains_key("host") {
+        "TRUE"
+    } else {
+        "host IS NULL"
+    })
+}
+
+/// Unique visitors per type and bucket, `bucket` being the SQL expression
+/// giving each row's bucket start. With `rollup` the daily counts come
+/// precomputed from the `stats_daily` rows it selects; `where_clause` must
+/// then only filter on `date` and `host`.
 async fn visits_by_type_date(
     store: &Store,
+    bucket: &str,
+    rollup: Option<&str>,
     where_clause: &str,
     args: &[String],
-) -> Result<HashMap<String, HashMap<NaiveDate, i64>>, anyhow::Error> {
+) -> Result<HashMap<String, HashMap<NaiveDateTime, i64>>, anyhow::Error> {
+    let query = if let Some(rows) = rollup {
+        format!(
+            "SELECT type, CAST(date AS TIMESTAMP), visitors FROM stats_daily WHERE {} AND {}",
+            where_clause, rows
+        )
+    } else {
+        format!(
+            "WITH subq AS (
+                SELECT type, {} AS bucket, MAX(mult) AS mult
+                FROM stats
+                WHERE {}
+                GROUP BY type, bucket, uniq
+            )
+            SELECT type, bucket, SUM(mult) AS cnt
+            FROM subq
+            GROUP BY type, bucket",
+            bucket, where_clause
+        )
+    };
+    let args = args.to_owned();
+    store
+        .with_conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let params = params_from_iter(args.iter().map(|s| s.as_str()));
+            let mut rows = stmt.query(params)?;
+            let mut result: HashMap<String, HashMap<NaiveDateTime, i64>> = HashMap::new();
+            while let Some(row) = rows.next()? {
+                let typ: Option<String> = row.get(0)?;
+                let date: NaiveDateTime = row.get(1)?;
+                let cnt: i64 = row.get(2)?;
+                if let Some(typ) = typ {
+                    result.entry(typ).or_default().insert(date, cnt);
+                }
+            }
+            Ok(result)
+        })
+        .await
+}
+
+/// Share of unique browser visitors with a single hit in the range, or `None`
+/// without any.
+async fn bounce_rate(
+    store: &Store,
+    where_clause: &str,
+    args: &[String],
+) -> Result<Option<f64>, anyhow::Error> {
     let query = format!(
         "WITH subq AS (
-            SELECT type, date, MAX(mult) AS mult
+            SELECT MAX(mult) AS mult, SUM(COALESCE(hits, 1)) AS hits
             FROM stats
-            WHERE {}
-            GROUP BY type, date, uniq
+            WHERE {} AND type = 'browser'
+            GROUP BY uniq
+        )
+        SELECT SUM(mult) FILTER (WHERE hits = 1), SUM(mult)
+        FROM subq",
+        where_clause
+    );
+    let args = args.to_owned();
+    store
+        .with_conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let params = params_from_iter(args.iter().map(|s| s.as_str()));
+            let mut rows = stmt.query(params)?;
+            let Some(row) = rows.next()? else {
+                return Ok(None);
+            };
+            let bounces: Option<i64> = row.get(0)?;
+            let total: Option<i64> = row.get(1)?;
+            Ok(total
+                .filter(|total| *total > 0)
+                .map(|total| bounces.unwrap_or(0) as f64 / total as f64))
+        })
+        .await
+}
+
+/// Unique browser visitors per host over the whole range, counted like
+/// `total_uniq`: a visitor of two hosts counts once for each.
+async fn host_uniq(
+    store: &Store,
+    where_clause: &str,
+    args: &[String],
+) -> Result<HashMap<String, i64>, anyhow::Error> {
+    let query = format!(
+        "WITH subq AS (
+            SELECT host, MAX(mult) AS mult
+            FROM stats
+            WHERE type = 'browser' AND {}
+            GROUP BY host, uniq
         )
-        SELECT type, date, SUM(mult) AS cnt
+        SELECT host, SUM(mult) AS cnt
         FROM subq
-        GROUP BY type, date",
+        GROUP BY host",
         where_clause
     );
     let args = args.to_owned();
@@ -255,13 +1547,12 @@ async fn visits_by_type_date(
             let mut stmt = conn.prepare(&query)?;
             let params = params_from_iter(args.iter().map(|s| s.as_str()));
             let mut rows = stmt.query(params)?;
-            let mut result: HashMap<String, HashMap<NaiveDate, i64>> = HashMap::new();
+            let mut result: HashMap<String, i64> = HashMap::new();
             while let Some(row) = rows.next()? {
-                let typ: Option<String> = row.get(0)?;
-                let date: NaiveDate = row.get(1)?;
-                let cnt: i64 = row.get(2)?;
-                if let Some(typ) = typ {
-                    result.entry(typ).or_default().insert(date, cnt);
+                let host: Option<String> = row.get(0)?;
+                let cnt: i64 = row.get(1)?;
+                if let Some(host) = host {
+                    result.insert(host, cnt);
                 }
             }
             Ok(result)
@@ -269,6 +1560,8 @@ async fn visits_by_type_date(
         .await
 }
 
+/// Unique visitors per type over the whole range: each `uniq` counts once
+/// however many days it was seen on, unlike the per-day timeline bars.
 async fn total_uniq(
     store: &Store,
     where_clause: &str,
@@ -343,6 +1636,64 @@ fn append_year_filters(
     }
 }
 
+/// Links to common ranges ending today, keeping the other parameters.
+fn append_quick_ranges(
+    out: &mut String,
+    params: &HashMap<String, Vec<String>>,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+    today: NaiveDate,
+) {
+    let ranges = [
+        ("Today", today),
+        ("Last 7 days", today - Duration::days(6)),
+        ("Last 30 days", today - Duration::days(29)),
+        ("This month", today.with_day(1).unwrap()),
+    ];
+    for (label, from) in ranges {
+        let mut qs = clone_params(params);
+        qs.insert(
+            "from".to_string(),
+            vec![from.format("%Y-%m-%d").to_string()],
+        );
+        qs.insert("to".to_string(), vec![today.format("%Y-%m-%d").to_string()]);
+        append(
+            out,
+            &format!(
+                "<a href='?{}' class='filter{}'>{}</a>",
+                encode_params(&qs),
+                if (from, today) == (from_date, to_date) {
+                    " in"
+                } else {
+                    ""
+                },
+                label
+            ),
+        );
+    }
+}
+
+/// A form picking any `from`/`to` range, keeping the other parameters.
+fn append_range_form(
+    out: &mut String,
+    params: &HashMap<String, Vec<String>>,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+) {
+    append(
+        out,
+        &format!(
+            "<form class=range action='/stats'>{}\
+             <input type=date name=from value='{}' required>\
+             <input type=date name=to value='{}' required>\
+             <button>Go</button></form>",
+            hidden_inputs(params, &["from", "to"]),
+            from_date.format("%Y-%m-%d"),
+            to_date.format("%Y-%m-%d")
+        ),
+    );
+}
+
 fn append_host_filters(out: &mut String, params: &HashMap<String, Vec<String>>, hosts: &[String]) {
     for host in hosts {
         let mut qs = clone_params(params);
@@ -352,41 +1703,119 @@ fn append_host_filters(out: &mut String, params: &HashMap<String, Vec<String>>,
             &format!(
                 "<a href='?{}' class='filter'>{}</a>",
                 encode_params(&qs),
-                host
+                html_escape(host)
             ),
         );
     }
 }
 
-fn append_active_filters(out: &mut String, params: &HashMap<String, Vec<String>>) {
+/// `header_scoped`: the host filter came from the Host header, so removing
+/// it sets `host=*` rather than dropping it, which would only bring it back.
+fn append_active_filters(
+    out: &mut String,
+    params: &HashMap<String, Vec<String>>,
+    header_scoped: bool,
+) {
     for (key, values) in params {
-        if key == "from" || key == "to" || values.is_empty() {
+        if key == "from" || key == "to" || values.is_empty() || values[0].is_empty() {
             continue;
         }
         let mut qs = clone_params(params);
-        qs.remove(key);
+        if key == "host" && header_scoped {
+            qs.insert(key.clone(), vec!["*".to_string()]);
+        } else {
+            qs.remove(key);
+        }
         append(
             out,
             &format!(
                 "<div class=filter>{}: {}<a href='?{}'>&times;</a></div>",
-                key,
-                values[0],
+                html_escape(key),
+                html_escape(&values[0]),
                 encode_params(&qs)
             ),
         );
     }
 }
 
+/// Range drawn on the timelines: the selection, widened by `context_days` on
+/// each side when it is that short. Comparisons align ranges by offset, so
+/// they never get context.
+fn timeline_range(
+    config: &Config,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+    comparing: bool,
+) -> (NaiveDate, NaiveDate) {
+    let context = config.context_days as i64;
+    if context == 0 || comparing || (to_date - from_date).num_days() + 1 > context {
+        return (from_date, to_date);
+    }
+    (
+        from_date - Duration::days(context),
+        to_date + Duration::days(context),
+    )
+}
+
+/// The range of the same length ending the day before `from_date`.
+fn previous_range(from_date: NaiveDate, to_date: NaiveDate) -> (NaiveDate, NaiveDate) {
+    let days = (to_date - from_date).num_days() + 1;
+    (
+        from_date - Duration::days(days),
+        from_date - Duration::days(1),
+    )
+}
+
+/// Relative change from `previous` to `current`, e.g. `+12%`, or `None`
+/// when there was nothing to compare to.
+fn format_delta(current: i64, previous: i64) -> Option<String> {
+    if previous <= 0 {
+        return None;
+    }
+    let change = (current - previous) as f64 * 100.0 / previous as f64;
+    Some(format!("{:+.0}%", change))
+}
+
+/// A second date range rendered on top of the main one, aligned by day offset.
+struct Comparison {
+    data: HashMap<String, HashMap<NaiveDateTime, i64>>,
+    totals: HashMap<String, i64>,
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+}
+
+/// The analyzer's types, which always get a timeline, followed by any other
+/// type in `found`, in name order.
+fn timeline_types<'a>(found: impl IntoIterator<Item = &'a String>) -> Vec<&'a str> {
+    const KNOWN: [&str; 3] = ["browser", "feed", "bot"];
+    let mut others: Vec<&str> = found
+        .into_iter()
+        .map(String::as_str)
+        .filter(|typ| !KNOWN.contains(typ))
+        .collect();
+    others.sort_unstable();
+    others.dedup();
+    KNOWN.into_iter().chain(others).collect()
+}
+
 fn append_timelines(
     out: &mut String,
-    data: &HashMap<String, HashMap<NaiveDate, i64>>,
+    config: &Config,
+    data: &HashMap<String, HashMap<NaiveDateTime, i64>>,
     totals: &HashMap<String, i64>,
+    bounce_rate: Option<f64>,
+    previous_totals: Option<&HashMap<String, i64>>,
+    comparison: Option<&Comparison>,
     params: &HashMap<String, Vec<String>>,
     from_date: NaiveDate,
     to_date: NaiveDate,
 ) {
     let mut max_val = 1i64;
-    for date_counts in data.values() {
+    let compare_data = comparison.map(|c| &c.data);
+    for date_counts in data
+        .values()
+        .chain(compare_data.into_iter().flat_map(|d| d.values()))
+    {
         for val in date_counts.values() {
             if *val > max_val {
                 max_val = *val;
@@ -395,41 +1824,128 @@ fn append_timelines(
     }
     max_val = round_max_val(max_val);
 
-    let dates = list_dates(from_date, to_date);
-    let graph_w = dates.len() * 3;
+    let period = Period::from_params(params, from_date, to_date);
+    let (timeline_from, timeline_to) =
+        timeline_range(config, from_date, to_date, comparison.is_some());
+    let has_context = (timeline_from, timeline_to) != (from_date, to_date);
+    let selection_start =
+        period.bucket_start(from_date.and_time(NaiveTime::MIN), config.week_start);
+    let dates = list_dates(timeline_from, timeline_to, period, config.week_start);
+    let compare_dates = comparison
+        .map(|c| list_dates(c.from_date, c.to_date, period, config.week_start))
+        .unwrap_or_default();
+    // Past `max_bars` buckets, adjacent buckets share one bar showing their
+    // highest value, which keeps the node count bounded on long ranges.
+    let step = thin_step(dates.len().max(compare_dates.len()), config.max_bars);
+    let groups: Vec<&[NaiveDateTime]> = dates.chunks(step).collect();
+    let compare_groups: Vec<&[NaiveDateTime]> = compare_dates.chunks(step).collect();
+    let graph_w = groups.len().max(compare_groups.len()) * 3;
 
     let bar_height = |v: i64| -> i64 { (v * 100) / max_val.max(1) };
     let hrz_step = horizontal_step(max_val);
+    let current = period.bucket_start(config.now(), config.week_start);
 
-    let sections = [
-        ("browser", "Unique visitors"),
-        ("feed", "RSS Readers"),
-        ("bot", "Scrapers"),
-    ];
+    let types = timeline_types(
+        data.keys()
+            .chain(compare_data.into_iter().flat_map(|d| d.keys())),
+    );
+    let sections = types.into_iter().map(|typ| {
+        let title = match typ {
+            "browser" => "Unique visitors".to_string(),
+            "feed" => "RSS Readers".to_string(),
+            "bot" => "Scrapers".to_string(),
+            _ => format!("Unique {} visitors", html_escape(typ)),
+        };
+        (typ, title)
+    });
+
+    let bounces = bounce_rate
+        .map(|rate| format!(" <span class=bounce>{:.0}% bounce</span>", rate * 100.0))
+        .unwrap_or_default();
 
+    let no_counts = HashMap::new();
     for (typ, title) in sections {
-        let Some(date_counts) = data.get(typ) else { continue };
-        if date_counts.is_empty() {
+        if !shows_type(params, typ) {
             continue;
         }
-        if typ == "feed" {
-            append(
-                out,
-                &format!(
-                    "<h1>{}: ~{} / day</h1>",
-                    title,
-                    format_number_with_commas(average(date_counts))
-                ),
-            );
-        } else {
-            append(
+        let date_counts = data.get(typ).unwrap_or(&no_counts);
+        let compare_counts = comparison
+            .and_then(|c| c.data.get(typ))
+            .unwrap_or(&no_counts);
+        if date_counts.is_empty() && compare_counts.is_empty() {
+            if config.show_empty_sections {
+                append(out, &format!("<h1>{}: 0</h1>", title));
+                append(out, "<div class=\"graph_outer empty\">No data</div>");
+            }
+            continue;
+        }
+        let headline = |counts: &HashMap<NaiveDateTime, i64>,
+                        totals: &HashMap<String, i64>,
+                        dates: &[NaiveDateTime]| {
+            if typ == "feed" {
+                let unit = match period {
+                    Period::Hour => "hour",
+                    Period::Day => "day",
+                    Period::Week => "week",
+                };
+                let in_selection = |date: &NaiveDateTime| {
+                    !has_context || (*date >= selection_start && date.date() <= to_date)
+                };
+                let selected: HashMap<NaiveDateTime, i64> = counts
+                    .iter()
+                    .filter(|(date, _)| in_selection(date))
+                    .map(|(date, val)| (*date, *val))
+                    .collect();
+                // Buckets without readers count as zero, unless only active
+                // ones are averaged; buckets yet to come never count.
+                let buckets = if config.feed_average_active_only {
+                    selected.len()
+                } else {
+                    dates
+                        .iter()
+                        .filter(|date| in_selection(date) && **date <= current)
+                        .count()
+                };
+                format!(
+                    "~{} / {}",
+                    format_number_with_commas(average(&selected, buckets)),
+                    unit
+                )
+            } else {
+                format_number_with_commas(*totals.get(typ).unwrap_or(&0))
+            }
+        };
+        match comparison {
+            Some(c) => append(
                 out,
                 &format!(
-                    "<h1>{}: {}</h1>",
+                    "<h1>{}: {} <span class=cmp>vs {}</span>{}</h1>",
                     title,
-                    format_number_with_commas(*totals.get(typ).unwrap_or(&0))
+                    headline(date_counts, totals, &dates),
+                    headline(compare_counts, &c.totals, &compare_dates),
+                    if typ == "browser" { &bounces } else { "" }
                 ),
-            );
+            ),
+            None => {
+                // Feed headlines are averages, which totals can't be compared to.
+                let delta = previous_totals
+                    .filter(|_| typ != "feed")
+                    .and_then(|prev| {
+                        format_delta(*totals.get(typ).unwrap_or(&0), *prev.get(typ).unwrap_or(&0))
+                    })
+                    .map(|delta| format!(" <span class=delta>({})</span>", delta))
+                    .unwrap_or_default();
+                append(
+                    out,
+                    &format!(
+                        "<h1>{}: {}{}{}</h1>",
+                        title,
+                        headline(date_counts, totals, &dates),
+                        delta,
+                        if typ == "browser" { &bounces } else { "" }
+                    ),
+                )
+            }
         }
         append(out, "<div class=graph_outer>");
         append(out, "<div class=graph_scroll>");
@@ -453,39 +1969,60 @@ fn append_timelines(
             val += hrz_step;
         }
 
-        for (idx, date) in dates.iter().enumerate() {
-            let val = *date_counts.get(date).unwrap_or(&0);
+        for (idx, group) in compare_groups.iter().enumerate() {
+            let val = group_max(compare_counts, group);
             if val > 0 {
-                let bar_h = bar_height(val);
-                let data_v = format_num(val);
-                let data_d = date.format("%Y-%m-%d");
-                let x = idx * 3;
-                let y = 110 - bar_h as usize;
-                append(
-                    out,
-                    &format!(
-                        "<g data-v='{}' data-d='{}'><rect class=i x={} y=0 width=3 height=110 />\
-                         <rect x={} y={} width=3 height={} /><line x1={} y1={} x2={} y2={} /></g>",
-                        data_v,
-                        data_d,
-                        x,
-                        x,
-                        y.saturating_sub(2),
-                        bar_h + 2,
-                        x,
-                        y.saturating_sub(1),
-                        x + 3,
-                        y.saturating_sub(1)
-                    ),
-                );
+                let label = group_label(config, group, period);
+                let range = group_range(group, period);
+                append_bar(out, &label, idx * 3, range, val, bar_height(val), "cmp");
             }
-            if date.day() == 1 {
-                let month_end = (*date + Duration::days(32))
-                    .with_day(1)
-                    .unwrap()
-                    - Duration::days(1);
+        }
+
+        for (idx, group) in groups.iter().enumerate() {
+            let val = group_max(date_counts, group);
+            if val > 0 {
+                let label = group_label(config, group, period);
+                let range = group_range(group, period);
+                let class = if range.1 < from_date || range.0 > to_date {
+                    "ctx"
+                } else {
+                    ""
+                };
+                append_bar(out, &label, idx * 3, range, val, bar_height(val), class);
+            }
+            let (first, last) = group_range(group, period);
+            if period == Period::Hour {
+                // Label days instead of months, on their first hour.
+                if let Some(day) = group.iter().find(|start| start.hour() == 0) {
+                    let mut qs = clone_params(params);
+                    let date = day.format("%Y-%m-%d").to_string();
+                    qs.insert("from".to_string(), vec![date.clone()]);
+                    qs.insert("to".to_string(), vec![date]);
+                    append(
+                        out,
+                        &format!(
+                            "<line class=date x1={} y1=112 x2={} y2=120 />\
+                             <a href='?{}'><text x={} y=130>{}</text></a>",
+                            idx * 3,
+                            idx * 3,
+                            encode_params(&qs),
+                            idx * 3,
+                            day.format(&config.day_format)
+                        ),
+                    );
+                }
+            } else if let Some(month) = first
+                .iter_days()
+                .take_while(|d| *d <= last)
+                .find(|d| d.day() == 1)
+            {
+                let month_end =
+                    (month + Duration::days(32)).with_day(1).unwrap() - Duration::days(1);
                 let mut qs = clone_params(params);
-                qs.insert("from".to_string(), vec![date.format("%Y-%m-%d").to_string()]);
+                qs.insert(
+                    "from".to_string(),
+                    vec![month.format("%Y-%m-%d").to_string()],
+                );
                 qs.insert(
                     "to".to_string(),
                     vec![month_end.format("%Y-%m-%d").to_string()],
@@ -499,11 +2036,11 @@ fn append_timelines(
                         idx * 3,
                         encode_params(&qs),
                         idx * 3,
-                        date.format(YEAR_MONTH_FORMAT)
+                        month.format(&config.month_format)
                     ),
                 );
             }
-            if same_day(*date, Utc::now().date_naive()) {
+            if group.contains(&current) {
                 append(
                     out,
                     &format!(
@@ -538,6 +2075,516 @@ fn append_timelines(
     }
 }
 
+/// Hover label of the bar starting at `start`.
+fn bar_label(config: &Config, start: NaiveDateTime, period: Period) -> String {
+    match period {
+        Period::Hour => format!(
+            "{} {}",
+            start.format(&config.day_format),
+            start.format("%H:00")
+        ),
+        Period::Day => start.format(&config.day_format).to_string(),
+        Period::Week => week_label(start.date(), config.week_start),
+    }
+}
+
+/// Number of adjacent buckets drawn as one bar so at most `max_bars` bars
+/// are rendered; 0 disables thinning.
+fn thin_step(buckets: usize, max_bars: usize) -> usize {
+    if max_bars == 0 || buckets <= max_bars {
+        1
+    } else {
+        buckets.div_ceil(max_bars)
+    }
+}
+
+fn group_max(counts: &HashMap<NaiveDateTime, i64>, group: &[NaiveDateTime]) -> i64 {
+    group
+        .iter()
+        .map(|date| *counts.get(date).unwrap_or(&0))
+        .max()
+        .unwrap_or(0)
+}
+
+/// First and last day covered by a group of buckets.
+fn group_range(group: &[NaiveDateTime], period: Period) -> (NaiveDate, NaiveDate) {
+    let end = group[group.len() - 1] + period.step() - Duration::seconds(1);
+    (group[0].date(), end.date())
+}
+
+fn group_label(config: &Config, group: &[NaiveDateTime], period: Period) -> String {
+    let first = bar_label(config, group[0], period);
+    if group.len() == 1 {
+        return first;
+    }
+    format!(
+        "{} – {}",
+        first,
+        bar_label(config, group[group.len() - 1], period)
+    )
+}
+
+/// Emits one bucket's bar, `class` being `cmp` for comparison bars and `ctx`
+/// for context outside the selection. Comparison bars sit behind the main
+/// series and have no full-height hover target, so they don't shadow the
+/// main bars.
+fn append_bar(
+    out: &mut String,
+    label: &str,
+    x: usize,
+    (from, to): (NaiveDate, NaiveDate),
+    val: i64,
+    bar_h: i64,
+    class: &str,
+) {
+    let y = 110 - bar_h as usize;
+    let hover = if class == "cmp" {
+        String::new()
+    } else {
+        format!("<rect class=i x={} y=0 width=3 height=110 />", x)
+    };
+    append(
+        out,
+        &format!(
+            "<g{} data-v='{}' data-d='{}' data-e='{}' data-l='{}'>{}\
+             <rect x={} y={} width=3 height={} /><line x1={} y1={} x2={} y2={} /></g>",
+            if class.is_empty() {
+                String::new()
+            } else {
+                format!(" class={}", class)
+            },
+            format_num(val),
+            from.format("%Y-%m-%d"),
+            to.format("%Y-%m-%d"),
+            label,
+            hover,
+            x,
+            y.saturating_sub(2),
+            bar_h + 2,
+            x,
+            y.saturating_sub(1),
+            x + 3,
+            y.saturating_sub(1)
+        ),
+    );
+}
+
+/// One of the top-N tables shown under the timelines.
+struct TableSpec {
+    /// Identifier used by the exports.
+    name: &'static str,
+    title: &'static str,
+    /// Column or SQL expression the rows are grouped by.
+    column: &'static str,
+    where_clause: String,
+    /// Bound after the shared arguments, for placeholders `where_clause` adds.
+    args: Vec<String>,
+    /// Parameter set by the search box in the heading; empty for none.
+    search_param: &'static str,
+    count: TableCount,
+    /// Filter set by the magnifier link of each row; empty for none.
+    filter_param: &'static str,
+    href_fn: Option<fn(String) -> String>,
+}
+
+/// What the rows of a table count.
+#[derive(Clone, Copy, PartialEq)]
+enum TableCount {
+    Hits,
+    /// Unique visitors.
+    Uniques,
+    /// Visitors whose first hit of the day was on the value.
+    Entries,
+}
+
+/// Whether the `type` and `exclude_type` parameters let traffic of `typ`
+/// through.
+fn shows_type(params: &HashMap<String, Vec<String>>, typ: &str) -> bool {
+    first_value(params, "type").is_none_or(|t| t == typ)
+        && first_value(params, "exclude_type").is_none_or(|t| t != typ)
+}
+
+/// Tables for the given range and parameters. A `type` or `exclude_type`
+/// filter picks the traffic they describe: the path, query and referrer
+/// tables follow it instead of showing browsers only, and only the matching
+/// agent tables are listed. `path_like` and `ref_domain_like` narrow the
+/// Paths and Referrers tables to values containing the term.
+fn table_specs(where_clause: &str, params: &HashMap<String, Vec<String>>) -> Vec<TableSpec> {
+    let shows_type = |typ: &str| shows_type(params, typ);
+    let content_where = if params.contains_key("type") || params.contains_key("exclude_type") {
+        where_clause.to_string()
+    } else {
+        format!("{} AND type = 'browser'", where_clause)
+    };
+    let by_major_version = first_value(params, "version_detail").as_deref() == Some("major");
+    let (path_where, path_args) = search_where(&content_where, "path", params);
+    let (ref_where, ref_args) = search_where(&content_where, "ref_domain", params);
+
+    let mut specs = vec![
+        TableSpec {
+            name: "paths",
+            title: "Paths",
+            column: "path",
+            where_clause: path_where,
+            args: path_args,
+            search_param: "path_like",
+            count: TableCount::Hits,
+            filter_param: "path",
+            href_fn: Some(site_path),
+        },
+        TableSpec {
+            name: "entry_pages",
+            title: "Entry pages",
+            column: "path",
+            where_clause: content_where.clone(),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Entries,
+            filter_param: "path",
+            href_fn: Some(site_path),
+        },
+        TableSpec {
+            name: "queries",
+            title: "Queries",
+            column: "query",
+            where_clause: content_where.clone(),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Hits,
+            filter_param: "query",
+            href_fn: None,
+        },
+        TableSpec {
+            name: "referrers",
+            title: "Referrers",
+            column: "ref_domain",
+            where_clause: ref_where,
+            args: ref_args,
+            search_param: "ref_domain_like",
+            count: TableCount::Hits,
+            filter_param: "ref_domain",
+            href_fn: Some(|v| format!("https://{}", v)),
+        },
+        TableSpec {
+            name: "channels",
+            title: "Channels",
+            column: "ref_channel",
+            where_clause: format!("{} AND ref_channel IS NOT NULL", content_where),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Hits,
+            filter_param: "ref_channel",
+            href_fn: None,
+        },
+        TableSpec {
+            name: "campaigns",
+            title: "Campaigns",
+            column: "utm_campaign",
+            where_clause: format!("{} AND utm_campaign IS NOT NULL", content_where),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Hits,
+            filter_param: "utm_campaign",
+            href_fn: None,
+        },
+        TableSpec {
+            name: "campaign_sources",
+            title: "Campaign sources",
+            column: "utm_source",
+            where_clause: format!("{} AND utm_source IS NOT NULL", content_where),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Hits,
+            filter_param: "utm_source",
+            href_fn: None,
+        },
+    ];
+    if shows_type("browser") {
+        specs.push(TableSpec {
+            name: "browsers",
+            title: "Browsers",
+            column: if by_major_version {
+                AGENT_MAJOR_VERSION
+            } else {
+                "agent"
+            },
+            where_clause: format!("{} AND type = 'browser'", where_clause),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Uniques,
+            filter_param: if by_major_version { "" } else { "agent" },
+            href_fn: None,
+        });
+        specs.push(TableSpec {
+            name: "browser_versions",
+            title: "Browser versions",
+            column: AGENT_FULL_VERSION,
+            where_clause: format!(
+                "{} AND type = 'browser' AND agent_version IS NOT NULL",
+                where_clause
+            ),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Uniques,
+            filter_param: "",
+            href_fn: None,
+        });
+        specs.push(TableSpec {
+            name: "operating_systems",
+            title: "Operating systems",
+            column: "os",
+            where_clause: format!("{} AND type = 'browser' AND os IS NOT NULL", where_clause),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Uniques,
+            filter_param: "os",
+            href_fn: None,
+        });
+    }
+    if shows_type("feed") {
+        specs.push(TableSpec {
+            name: "feeds",
+            title: "RSS Readers",
+            column: "agent",
+            where_clause: format!("{} AND type = 'feed'", where_clause),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Uniques,
+            filter_param: "agent",
+            href_fn: None,
+        });
+    }
+    if shows_type("bot") {
+        specs.push(TableSpec {
+            name: "scrapers",
+            title: "Scrapers",
+            column: "agent",
+            where_clause: format!("{} AND type = 'bot'", where_clause),
+            args: Vec::new(),
+            search_param: "",
+            count: TableCount::Uniques,
+            filter_param: "agent",
+            href_fn: None,
+        });
+    }
+    specs.push(TableSpec {
+        name: "devices",
+        title: "Devices",
+        column: "device",
+        where_clause: format!("{} AND device IS NOT NULL", where_clause),
+        args: Vec::new(),
+        search_param: "",
+        count: TableCount::Uniques,
+        filter_param: "device",
+        href_fn: None,
+    });
+    specs.push(TableSpec {
+        name: "countries",
+        title: "Countries",
+        column: "country",
+        where_clause: format!("{} AND country IS NOT NULL", where_clause),
+        args: Vec::new(),
+        search_param: "",
+        count: TableCount::Uniques,
+        filter_param: "country",
+        href_fn: None,
+    });
+    specs.push(TableSpec {
+        name: "protocols",
+        title: "Protocols",
+        column: "protocol",
+        where_clause: format!("{} AND protocol IS NOT NULL", where_clause),
+        args: Vec::new(),
+        search_param: "",
+        count: TableCount::Uniques,
+        filter_param: "protocol",
+        href_fn: None,
+    });
+    specs.push(TableSpec {
+        name: "tls_versions",
+        title: "TLS versions",
+        column: "tls_version",
+        where_clause: format!("{} AND tls_version IS NOT NULL", where_clause),
+        args: Vec::new(),
+        search_param: "",
+        count: TableCount::Uniques,
+        filter_param: "tls_version",
+        href_fn: None,
+    });
+    specs
+}
+
+/// Rows shown per table, from the `limit` parameter.
+fn table_limit(params: &HashMap<String, Vec<String>>) -> usize {
+    first_value(params, "limit")
+        .and_then(|v| v.parse::<usize>().ok())
+        .map_or(DEFAULT_LIMIT, |limit| limit.clamp(1, MAX_LIMIT))
+}
+
+/// Rows skipped in table `name`, from its `offset_<name>` parameter, so each
+/// table pages on its own. Paging stops at `MAX_LIMIT` rows.
+fn table_offset(params: &HashMap<String, Vec<String>>, name: &str) -> usize {
+    first_value(params, &format!("offset_{}", name))
+        .and_then(|v| v.parse::<usize>().ok())
+        .map_or(0, |offset| offset.min(MAX_LIMIT - 1))
+}
+
+/// Prev/Next links paging table `name` by `limit` rows, keeping the other
+/// parameters. Empty when there is a single page.
+fn page_links(
+    params: &HashMap<String, Vec<String>>,
+    name: &str,
+    limit: usize,
+    offset: usize,
+    has_next: bool,
+) -> String {
+    if offset == 0 && !has_next {
+        return String::new();
+    }
+    let param = format!("offset_{}", name);
+    let link = |offset: usize, label: &str| {
+        let mut qs = clone_params(params);
+        if offset == 0 {
+            qs.remove(&param);
+        } else {
+            qs.insert(param.clone(), vec![offset.to_string()]);
+        }
+        format!("<a href='?{}'>{}</a>", encode_params(&qs), label)
+    };
+    let mut out = String::from("<div class=pages>");
+    if offset > 0 {
+        out.push_str(&link(offset.saturating_sub(limit), "&larr; Prev"));
+    }
+    if has_next {
+        out.push_str(&link(offset + limit, "Next &rarr;"));
+    }
+    out.push_str("</div>");
+    out
+}
+
+/// Rows of a table, top entries first and `Others` last.
+async fn table_rows(
+    store: &Store,
+    spec: &TableSpec,
+    args: &[String],
+    limit: usize,
+    offset: usize,
+    sort: TableSort,
+) -> Result<Vec<RowCount>, anyhow::Error> {
+    let args = [args, &spec.args].concat();
+    let (column, where_clause) = (spec.column, &spec.where_clause);
+    match spec.count {
+        TableCount::Hits => top_n(store, column, where_clause, &args, limit, offset, sort).await,
+        TableCount::Uniques => {
+            top_n_uniq(store, column, where_clause, &args, limit, offset, sort).await
+        }
+        TableCount::Entries => {
+            top_n_entries(store, column, where_clause, &args, limit, offset, sort).await
+        }
+    }
+}
+
+/// Order of the rows shown in a table, picked with the `sort` parameter.
+/// The rows are always the top ones by count; only their order changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TableSort {
+    Count,
+    Name,
+}
+
+impl TableSort {
+    fn from_params(params: &HashMap<String, Vec<String>>) -> Self {
+        match first_value(params, "sort").as_deref() {
+            Some("name") => TableSort::Name,
+            _ => TableSort::Count,
+        }
+    }
+
+    fn order_by(self) -> &'static str {
+        match self {
+            TableSort::Count => "count DESC, value",
+            TableSort::Name => "value, count DESC",
+        }
+    }
+
+    /// Link switching every table to the other order, keeping the other
+    /// parameters.
+    fn toggle_link(self, params: &HashMap<String, Vec<String>>) -> String {
+        let mut qs = clone_params(params);
+        let label = match self {
+            TableSort::Count => {
+                qs.insert("sort".to_string(), vec!["name".to_string()]);
+                "by name"
+            }
+            TableSort::Name => {
+                qs.remove("sort");
+                "by count"
+            }
+        };
+        format!("<a class=sort href='?{}'>{}</a>", encode_params(&qs), label)
+    }
+}
+
+/// `where_clause` narrowed to rows whose `column` contains the term of the
+/// `<column>_like` parameter, with the argument to bind for it.
+fn search_where(
+    where_clause: &str,
+    column: &str,
+    params: &HashMap<String, Vec<String>>,
+) -> (String, Vec<String>) {
+    match first_value(params, &format!("{}_like", column))
+        .filter(|term| !term.is_empty() && is_valid_filter_value(term))
+    {
+        Some(term) => (
+            format!("{} AND {} LIKE ?", where_clause, column),
+            vec![format!("%{}%", term)],
+        ),
+        None => (where_clause.to_string(), Vec::new()),
+    }
+}
+
+/// Hidden inputs carrying `params` other than `except` through a GET form.
+fn hidden_inputs(params: &HashMap<String, Vec<String>>, except: &[&str]) -> String {
+    let mut inputs = String::new();
+    let mut keys: Vec<_> = params
+        .keys()
+        .filter(|key| !except.contains(&key.as_str()))
+        .collect();
+    keys.sort();
+    for key in keys {
+        for value in &params[key] {
+            inputs.push_str(&format!(
+                "<input type=hidden name='{}' value='{}'>",
+                html_escape(key),
+                html_escape(value)
+            ));
+        }
+    }
+    inputs
+}
+
+/// A GET form searching with `param`, keeping the other parameters.
+fn search_form(param: &str, params: &HashMap<String, Vec<String>>) -> String {
+    let mut form = String::from("<form class=search>");
+    form.push_str(&hidden_inputs(params, &[param]));
+    form.push_str(&format!(
+        "<input type=search name='{}' value='{}' placeholder='Search'></form>",
+        param,
+        html_escape(&first_value(params, param).unwrap_or_default())
+    ));
+    form
+}
+
+/// Escapes a stored or requested value for HTML text and quoted attributes.
+fn html_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('\'', "&#39;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 async fn append_tables(
     out: &mut String,
     store: &Store,
@@ -545,77 +2592,211 @@ async fn append_tables(
     args: &[String],
     params: &HashMap<String, Vec<String>>,
 ) {
+    let specs = table_specs(where_clause, params);
+    let limit = table_limit(params);
+    let sort = TableSort::from_params(params);
+    // The queries are independent, so they run together; the tables are
+    // still appended in spec order.
+    let results = join_all(specs.iter().map(|spec| {
+        let offset = table_offset(params, spec.name);
+        table_rows(store, spec, args, limit, offset, sort)
+    }))
+    .await;
+
     append(out, "<div class=tables>");
-    append_table(
-        out,
+    for (spec, rows) in specs.iter().zip(results) {
+        let mut title = format!(
+            "{} {} <a class=csv href='/stats/export/{}.csv?{}'>CSV</a>",
+            spec.title,
+            sort.toggle_link(params),
+            spec.name,
+            encode_params(params)
+        );
+        if !spec.search_param.is_empty() {
+            title.push_str(&search_form(spec.search_param, params));
+        }
+        let mut rows = rows.unwrap_or_default();
+        // The `Others` row stands for the following pages until paging stops
+        // at `MAX_LIMIT`, so it only shows on the last page.
+        let offset = table_offset(params, spec.name);
+        let has_next =
+            rows.last().is_some_and(|row| row.value.is_empty()) && offset + limit < MAX_LIMIT;
+        if has_next {
+            rows.pop();
+        }
+        let pages = page_links(params, spec.name, limit, offset, has_next);
+        if spec.count == TableCount::Uniques {
+            append_table_uniq(out, &title, rows, params, spec.filter_param, &pages);
+        } else {
+            append_table(
+                out,
+                &title,
+                rows,
+                params,
+                spec.filter_param,
+                spec.href_fn,
+                &pages,
+            );
+        }
+    }
+    append_metric_tables(out, store, where_clause, args, params).await;
+    append(out, "</div>");
+}
+
+/// Lists the metrics sent in the range and, once one is picked with
+/// `metric`, its sum and average per `metric_by` column (`path` by default).
+async fn append_metric_tables(
+    out: &mut String,
+    store: &Store,
+    where_clause: &str,
+    args: &[String],
+    params: &HashMap<String, Vec<String>>,
+) {
+    let rows = top_metrics(store, "metric", where_clause, args)
+        .await
+        .unwrap_or_default();
+    append_metric_table(out, "Metrics", rows, params, "metric");
+
+    let Some(metric) = first_value(params, "metric") else {
+        return;
+    };
+    let column = first_value(params, "metric_by")
+        .filter(|col| ALLOWED_FILTERS.contains(&col.as_str()))
+        .unwrap_or_else(|| "path".to_string());
+    let mut metric_args = args.to_vec();
+    metric_args.push(metric.clone());
+    let rows = top_metrics(
         store,
-        "Paths",
-        "path",
-        &format!("{} AND type = 'browser'", where_clause),
-        args,
-        params,
-        "path",
-        Some(|v: String| v),
+        &column,
+        &format!("{} AND metric = ?", where_clause),
+        &metric_args,
     )
-    .await;
-    append_table(
+    .await
+    .unwrap_or_default();
+    append_metric_table(
         out,
-        store,
-        "Queries",
-        "query",
-        &format!("{} AND type = 'browser'", where_clause),
-        args,
+        &format!("{} by {}", html_escape(&metric), column),
+        rows,
         params,
-        "query",
-        None,
-    )
-    .await;
+        &column,
+    );
+}
+
+fn append_metric_table(
+    out: &mut String,
+    title: &str,
+    rows: Vec<MetricRow>,
+    params: &HashMap<String, Vec<String>>,
+    link_param: &str,
+) {
+    if rows.is_empty() {
+        return;
+    }
+    let max = rows
+        .iter()
+        .map(|row| row.sum)
+        .fold(0.0, f64::max)
+        .max(f64::MIN_POSITIVE);
+    append(out, "<div class=table_outer>");
+    append(out, &format!("<h1>{}</h1>", title));
+    append(out, "<table>");
+    for row in rows {
+        let mut qs = clone_params(params);
+        qs.insert(link_param.to_string(), vec![row.value.clone()]);
+        append(out, "<tr>");
+        append(
+            out,
+            &format!(
+                "<td class=f><a href='?{}' title='Filter by {} = {}'>&#x1F50D;</a></td>",
+                encode_params(&qs),
+                link_param,
+                html_escape(&row.value)
+            ),
+        );
+        append(out, "<th>");
+        append(
+            out,
+            &format!(
+                "<div style='width: {:.0}%'></div>",
+                (row.sum / max * 100.0).max(0.0)
+            ),
+        );
+        append(
+            out,
+            &format!("<span title='{0}'>{0}</span>", html_escape(&row.value)),
+        );
+        append(out, "</th>");
+        append(
+            out,
+            &format!("<td title='Sum'>{}</td>", format_metric(row.sum)),
+        );
+        append(
+            out,
+            &format!(
+                "<td class='pct' title='Average'>{}</td>",
+                format_metric(row.avg)
+            ),
+        );
+        append(out, "</tr>");
+    }
+    append(out, "</table>");
+    append(out, "</div>");
+}
+
+/// Link to a stored path on the site. A path not starting with `/` is made
+/// relative to the root, so it can't turn into a `javascript:` URL.
+fn site_path(path: String) -> String {
+    if path.starts_with('/') {
+        path
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// Labels browsers with their full version, e.g. `Chrome 120.0.6099.109`.
+const AGENT_FULL_VERSION: &str = "agent || ' ' || agent_version";
+
+/// Groups browsers as `Chrome 120`, collapsing minor versions into the major.
+const AGENT_MAJOR_VERSION: &str = "CASE WHEN agent_version IS NULL THEN agent \
+     ELSE agent || ' ' || split_part(agent_version, '.', 1) END";
+
+struct MetricRow {
+    value: String,
+    sum: f64,
+    avg: f64,
+}
+
+/// Per-host unique visitors, most visited first, each linking to that
+/// host's dashboard, with a link to all hosts combined.
+fn append_host_summary(
+    out: &mut String,
+    params: &HashMap<String, Vec<String>>,
+    hosts: &[String],
+    uniques: &HashMap<String, i64>,
+) {
+    let mut rows: Vec<RowCount> = hosts
+        .iter()
+        .map(|host| RowCount {
+            value: host.clone(),
+            count: uniques.get(host).copied().unwrap_or(0),
+        })
+        .collect();
+    rows.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    let mut qs = clone_params(params);
+    qs.insert("host".to_string(), vec!["*".to_string()]);
+    let all = format!(
+        "<div class=pages><a href='?{}'>All hosts combined</a></div>",
+        encode_params(&qs)
+    );
     append_table(
         out,
-        store,
-        "Referrers",
-        "ref_domain",
-        &format!("{} AND type = 'browser'", where_clause),
-        args,
-        params,
-        "ref_domain",
-        Some(|v| format!("https://{}", v)),
-    )
-    .await;
-    append_table_uniq(
-        out,
-        store,
-        "Browsers",
-        "agent",
-        &format!("{} AND type = 'browser'", where_clause),
-        args,
-        params,
-        "agent",
-    )
-    .await;
-    append_table_uniq(
-        out,
-        store,
-        "RSS Readers",
-        "agent",
-        &format!("{} AND type = 'feed'", where_clause),
-        args,
-        params,
-        "agent",
-    )
-    .await;
-    append_table_uniq(
-        out,
-        store,
-        "Scrapers",
-        "agent",
-        &format!("{} AND type = 'bot'", where_clause),
-        args,
+        "Unique visitors by host",
+        rows,
         params,
-        "agent",
-    )
-    .await;
-    append(out, "</div>");
+        "host",
+        None,
+        &all,
+    );
 }
 
 #[derive(Clone)]
@@ -624,18 +2805,15 @@ struct RowCount {
     count: i64,
 }
 
-async fn append_table(
+fn append_table(
     out: &mut String,
-    store: &Store,
     title: &str,
-    column: &str,
-    where_clause: &str,
-    args: &[String],
+    rows: Vec<RowCount>,
     params: &HashMap<String, Vec<String>>,
     filter_param: &str,
     href_fn: Option<fn(String) -> String>,
+    pages: &str,
 ) {
-    let rows = top10(store, column, where_clause, args).await.unwrap_or_default();
     if rows.is_empty() {
         return;
     }
@@ -670,7 +2848,7 @@ async fn append_table(
                     "<a href='?{}' title='Filter by {} = {}'>&#x1F50D;</a>",
                     encode_params(&qs),
                     filter_param,
-                    row.value
+                    html_escape(&row.value)
                 ),
             );
         }
@@ -689,10 +2867,9 @@ async fn append_table(
                 append(
                     out,
                     &format!(
-                        "<a href='{}' title='{}' target=_blank>{}</a>",
-                        href_fn(row.value.clone()),
-                        row.value,
-                        row.value
+                        "<a href='{}' title='{1}' target=_blank>{1}</a>",
+                        html_escape(&href_fn(row.value.clone())),
+                        html_escape(&row.value)
                     ),
                 );
             }
@@ -703,29 +2880,28 @@ async fn append_table(
             } else {
                 row.value.clone()
             };
-            append(out, &format!("<span title='{}'>{}</span>", label, label));
+            append(
+                out,
+                &format!("<span title='{0}'>{0}</span>", html_escape(&label)),
+            );
         }
         append(out, &format!("<td>{}</td>", format_num(row.count)));
         append(out, &format!("<td class='pct'>{}</td>", percent_str));
         append(out, "</tr>");
     }
     append(out, "</table>");
+    append(out, pages);
     append(out, "</div>");
 }
 
-async fn append_table_uniq(
+fn append_table_uniq(
     out: &mut String,
-    store: &Store,
     title: &str,
-    column: &str,
-    where_clause: &str,
-    args: &[String],
+    rows: Vec<RowCount>,
     params: &HashMap<String, Vec<String>>,
     filter_param: &str,
+    pages: &str,
 ) {
-    let rows = top10_uniq(store, column, where_clause, args)
-        .await
-        .unwrap_or_default();
     if rows.is_empty() {
         return;
     }
@@ -760,7 +2936,7 @@ async fn append_table_uniq(
                     "<a href='?{}' title='Filter by {} = {}'>&#x1F50D;</a>",
                     encode_params(&qs),
                     filter_param,
-                    row.value
+                    html_escape(&row.value)
                 ),
             );
         }
@@ -779,49 +2955,130 @@ async fn append_table_uniq(
         } else {
             row.value.clone()
         };
-        append(out, &format!("<span title='{}'>{}</span>", label, label));
+        append(
+            out,
+            &format!("<span title='{0}'>{0}</span>", html_escape(&label)),
+        );
         append(out, "</th>");
         append(out, &format!("<td>{}</td>", format_num(row.count)));
         append(out, &format!("<td class='pct'>{}</td>", percent_str));
         append(out, "</tr>");
     }
     append(out, "</table>");
+    append(out, pages);
     append(out, "</div>");
 }
 
-async fn top10(
+/// Top values of `column` by hits ranked after the first `offset`, `limit` at
+/// most and ordered by `sort`, followed by a row without value summing the
+/// ones ranked after them. Ties are broken by value so `others` excludes
+/// exactly the rows up to this page.
+async fn top_n(
+    store: &Store,
+    column: &str,
+    where_clause: &str,
+    args: &[String],
+    limit: usize,
+    offset: usize,
+    sort: TableSort,
+) -> Result<Vec<RowCount>, anyhow::Error> {
+    let query = format!(
+        "WITH base_query AS (
+            SELECT {col}, COALESCE(hits, 1) AS hits
+            FROM stats
+            WHERE {where_clause}
+        ),
+        top_values AS (
+            SELECT {col} AS value, SUM(hits) AS count
+            FROM base_query
+            WHERE {col} IS NOT NULL
+            GROUP BY value
+        ),
+        top_n AS (
+            SELECT * FROM top_values ORDER BY count DESC, value
+            LIMIT {limit} OFFSET {offset}
+        ),
+        shown AS (
+            SELECT value FROM top_values ORDER BY count DESC, value LIMIT {end}
+        ),
+        others AS (
+            SELECT NULL AS value, SUM(hits) AS count
+            FROM base_query
+            WHERE {col} IS NOT NULL AND {col} NOT IN (SELECT value FROM shown)
+        )
+        SELECT value, count FROM (
+            SELECT *, 0 AS is_others FROM top_n
+            UNION ALL
+            SELECT *, 1 AS is_others FROM others
+            WHERE count > 0
+        )
+        ORDER BY is_others, {order}",
+        col = column,
+        where_clause = where_clause,
+        limit = limit,
+        offset = offset,
+        end = offset + limit,
+        order = sort.order_by()
+    );
+    let args = args.to_owned();
+    store
+        .with_conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let params = params_from_iter(args.iter().map(|s| s.as_str()));
+            let mut rows = stmt.query(params)?;
+            read_rows(&mut rows)
+        })
+        .await
+}
+
+/// Like `top_n`, counting unique visitors.
+async fn top_n_uniq(
     store: &Store,
     column: &str,
     where_clause: &str,
     args: &[String],
+    limit: usize,
+    offset: usize,
+    sort: TableSort,
 ) -> Result<Vec<RowCount>, anyhow::Error> {
     let query = format!(
         "WITH base_query AS (
-            SELECT {col}
+            SELECT ANY_VALUE({col}) AS value, MAX(mult) AS mult
             FROM stats
             WHERE {where_clause}
+            GROUP BY uniq
         ),
         top_values AS (
-            SELECT {col} AS value, COUNT(*) AS count
+            SELECT value, SUM(mult) AS count
             FROM base_query
-            WHERE {col} IS NOT NULL
+            WHERE value IS NOT NULL
             GROUP BY value
-            ORDER BY count DESC
         ),
         top_n AS (
-            SELECT * FROM top_values ORDER BY count DESC LIMIT 10
+            SELECT * FROM top_values ORDER BY count DESC, value
+            LIMIT {limit} OFFSET {offset}
+        ),
+        shown AS (
+            SELECT value FROM top_values ORDER BY count DESC, value LIMIT {end}
         ),
         others AS (
-            SELECT NULL AS value, COUNT(*) AS count
+            SELECT NULL AS value, SUM(mult) AS count
             FROM base_query
-            WHERE {col} IS NOT NULL AND {col} NOT IN (SELECT value FROM top_n)
+            WHERE value IS NOT NULL AND value NOT IN (SELECT value FROM shown)
+        )
+        SELECT value, count FROM (
+            SELECT *, 0 AS is_others FROM top_n
+            UNION ALL
+            SELECT *, 1 AS is_others FROM others
+            WHERE count > 0
         )
-        SELECT * FROM top_n
-        UNION ALL
-        SELECT * FROM others
-        WHERE count > 0",
+        ORDER BY is_others, {order}",
         col = column,
-        where_clause = where_clause
+        where_clause = where_clause,
+        limit = limit,
+        offset = offset,
+        end = offset + limit,
+        order = sort.order_by()
     );
     let args = args.to_owned();
     store
@@ -834,40 +3091,58 @@ async fn top10(
         .await
 }
 
-async fn top10_uniq(
+/// Like `top_n_uniq`, counting each visitor once per day on the value of
+/// their first hit, e.g. the page they entered the site on.
+async fn top_n_entries(
     store: &Store,
     column: &str,
     where_clause: &str,
     args: &[String],
+    limit: usize,
+    offset: usize,
+    sort: TableSort,
 ) -> Result<Vec<RowCount>, anyhow::Error> {
     let query = format!(
-        "WITH base_query AS (
-            SELECT ANY_VALUE({col}) AS {col}, MAX(mult) AS mult
+        "WITH ranked AS (
+            SELECT {col} AS value, mult,
+                ROW_NUMBER() OVER (PARTITION BY uniq, date ORDER BY time, {col}) AS nth
             FROM stats
             WHERE {where_clause}
-            GROUP BY uniq
+        ),
+        base_query AS (
+            SELECT value, mult FROM ranked WHERE nth = 1
         ),
         top_values AS (
-            SELECT {col} AS value, SUM(mult) AS count
+            SELECT value, SUM(mult) AS count
             FROM base_query
-            WHERE {col} IS NOT NULL
+            WHERE value IS NOT NULL
             GROUP BY value
-            ORDER BY count DESC
         ),
         top_n AS (
-            SELECT * FROM top_values ORDER BY count DESC LIMIT 10
+            SELECT * FROM top_values ORDER BY count DESC, value
+            LIMIT {limit} OFFSET {offset}
+        ),
+        shown AS (
+            SELECT value FROM top_values ORDER BY count DESC, value LIMIT {end}
         ),
         others AS (
             SELECT NULL AS value, SUM(mult) AS count
             FROM base_query
-            WHERE {col} IS NOT NULL AND {col} NOT IN (SELECT value FROM top_n)
+            WHERE value IS NOT NULL AND value NOT IN (SELECT value FROM shown)
         )
-        SELECT * FROM top_n
-        UNION ALL
-        SELECT * FROM others
-        WHERE count > 0",
+        SELECT value, count FROM (
+            SELECT *, 0 AS is_others FROM top_n
+            UNION ALL
+            SELECT *, 1 AS is_others FROM others
+            WHERE count > 0
+        )
+        ORDER BY is_others, {order}",
         col = column,
-        where_clause = where_clause
+        where_clause = where_clause,
+        limit = limit,
+        offset = offset,
+        end = offset + limit,
+        order = sort.order_by()
     );
     let args = args.to_owned();
     store
@@ -880,6 +3155,43 @@ async fn top10_uniq(
         .await
 }
 
+/// Top values of `column` by summed metric value, with their average.
+async fn top_metrics(
+    store: &Store,
+    column: &str,
+    where_clause: &str,
+    args: &[String],
+) -> Result<Vec<MetricRow>, anyhow::Error> {
+    let query = format!(
+        "SELECT CAST({col} AS VARCHAR) AS label, SUM(metric_value) AS total,
+                AVG(metric_value) AS average
+         FROM stats
+         WHERE {where_clause} AND metric_value IS NOT NULL AND {col} IS NOT NULL
+         GROUP BY label
+         ORDER BY total DESC
+         LIMIT 10",
+        col = column,
+        where_clause = where_clause
+    );
+    let args = args.to_owned();
+    store
+        .with_conn(move |conn| {
+            let mut stmt = conn.prepare(&query)?;
+            let params = params_from_iter(args.iter().map(|s| s.as_str()));
+            let mut rows = stmt.query(params)?;
+            let mut out = Vec::new();
+            while let Some(row) = rows.next()? {
+                out.push(MetricRow {
+                    value: row.get(0)?,
+                    sum: row.get(1)?,
+                    avg: row.get(2)?,
+                });
+            }
+            Ok(out)
+        })
+        .await
+}
+
 fn read_rows(rows: &mut duckdb::Rows<'_>) -> Result<Vec<RowCount>, anyhow::Error> {
     let mut out = Vec::new();
     while let Some(row) = rows.next()? {
@@ -893,12 +3205,18 @@ fn read_rows(rows: &mut duckdb::Rows<'_>) -> Result<Vec<RowCount>, anyhow::Error
     Ok(out)
 }
 
-fn list_dates(from_date: NaiveDate, to_date: NaiveDate) -> Vec<NaiveDate> {
+/// Starts of the buckets covering `from_date` to `to_date`.
+fn list_dates(
+    from_date: NaiveDate,
+    to_date: NaiveDate,
+    period: Period,
+    week_start: Weekday,
+) -> Vec<NaiveDateTime> {
     let mut dates = Vec::new();
-    let mut d = from_date;
-    while d <= to_date {
+    let mut d = period.bucket_start(from_date.and_time(NaiveTime::MIN), week_start);
+    while d.date() <= to_date {
         dates.push(d);
-        d += Duration::days(1);
+        d += period.step();
     }
     dates
 }
@@ -952,6 +3270,21 @@ fn format_num(n: i64) -> String {
     n.to_string()
 }
 
+/// Metric values: abbreviated like counts from 1,000 up, with at most two
+/// decimals below.
+fn format_metric(value: f64) -> String {
+    if value.abs() >= 1_000.0 {
+        let abbreviated = format_num(value.abs().round() as i64);
+        return if value < 0.0 {
+            format!("-{}", abbreviated)
+        } else {
+            abbreviated
+        };
+    }
+    let s = format!("{:.2}", value);
+    s.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
 fn trim_trailing_zero(mut s: String) -> String {
     if s.ends_with(".0M") {
         s = s.replace(".0M", "M");
@@ -977,17 +3310,15 @@ fn format_number_with_commas(n: i64) -> String {
     result
 }
 
-fn average(values: &HashMap<NaiveDate, i64>) -> i64 {
-    if values.is_empty() {
+/// Rounded sum of `values` per bucket, over `buckets` buckets.
+fn average(values: &HashMap<NaiveDateTime, i64>, buckets: usize) -> i64 {
+    if buckets == 0 {
         return 0;
     }
     let sum: i64 = values.values().sum();
-    ((sum as f64) / (values.len() as f64) + 0.5) as i64
+    ((sum as f64) / (buckets as f64) + 0.5) as i64
 }
 
-fn same_day(a: NaiveDate, b: NaiveDate) -> bool {
-    a == b
-}
 
 fn clone_params(params: &HashMap<String, Vec<String>>) -> HashMap<String, Vec<String>> {
     params
@@ -1009,3 +3340,1156 @@ fn encode_params(params: &HashMap<String, Vec<String>>) -> String {
     }
     serializer.finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analyzer::Line;
+    use crate::store::tests::{hit, memory_store, row_count};
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    fn state(store: Store) -> AppState {
+        AppState {
+            store: Arc::new(store),
+            dashboard: Arc::new(Config::default()),
+            pages: Arc::new(PageCache::default()),
+            ingest: Arc::new(crate::ingest::Config::default()),
+        }
+    }
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, Vec<String>> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), vec![value.to_string()]))
+            .collect()
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d").expect("date")
+    }
+
+    type Visits = (
+        HashMap<String, HashMap<NaiveDateTime, i64>>,
+        HashMap<String, i64>,
+    );
+
+    /// Browser visitors per day and in total, `counts` giving the visitors of
+    /// some days.
+    fn browser_visits(counts: &[(&str, i64)]) -> Visits {
+        let browser: HashMap<NaiveDateTime, i64> = counts
+            .iter()
+            .map(|(day, count)| (date(day).and_time(NaiveTime::MIN), *count))
+            .collect();
+        let total = counts.iter().map(|(_, count)| count).sum();
+        (
+            HashMap::from([("browser".to_string(), browser)]),
+            HashMap::from([("browser".to_string(), total)]),
+        )
+    }
+
+    /// Timelines of browser visitors per day for the range `from..=to`,
+    /// `counts` giving the visitors of some days.
+    fn timelines(
+        config: &Config,
+        params: &HashMap<String, Vec<String>>,
+        from: &str,
+        to: &str,
+        counts: &[(&str, i64)],
+    ) -> String {
+        compared_timelines(config, params, from, to, counts, None)
+    }
+
+    fn compared_timelines(
+        config: &Config,
+        params: &HashMap<String, Vec<String>>,
+        from: &str,
+        to: &str,
+        counts: &[(&str, i64)],
+        comparison: Option<&Comparison>,
+    ) -> String {
+        let (data, totals) = browser_visits(counts);
+        let mut out = String::new();
+        append_timelines(
+            &mut out,
+            config,
+            &data,
+            &totals,
+            None,
+            None,
+            comparison,
+            params,
+            date(from),
+            date(to),
+        );
+        out
+    }
+
+    fn week_starts(from: &str, to: &str, week_start: Weekday) -> Vec<(String, String)> {
+        list_dates(date(from), date(to), Period::Week, week_start)
+            .into_iter()
+            .map(|start| {
+                (
+                    start.format("%Y-%m-%d").to_string(),
+                    week_label(start.date(), week_start),
+                )
+            })
+            .collect()
+    }
+
+    fn week(start: &str, label: &str) -> (String, String) {
+        (start.to_string(), label.to_string())
+    }
+
+    #[test]
+    fn iso_weeks_split_on_monday() {
+        // Saturday to Tuesday, across the Monday of ISO week 12.
+        assert_eq!(
+            week_starts("2024-03-16", "2024-03-19", Weekday::Mon),
+            vec![
+                week("2024-03-11", "2024-W11"),
+                week("2024-03-18", "2024-W12")
+            ]
+        );
+        // ISO week 1 of 2025 starts in December 2024.
+        assert_eq!(
+            week_starts("2024-12-29", "2024-12-30", Weekday::Mon),
+            vec![
+                week("2024-12-23", "2024-W52"),
+                week("2024-12-30", "2025-W01")
+            ]
+        );
+    }
+
+    #[test]
+    fn sunday_weeks_take_the_number_of_their_monday() {
+        assert_eq!(
+            week_starts("2024-03-16", "2024-03-19", Weekday::Sun),
+            vec![
+                week("2024-03-10", "2024-W11"),
+                week("2024-03-17", "2024-W12")
+            ]
+        );
+        let sunday = date("2024-03-17").and_time(NaiveTime::MIN);
+        assert_eq!(Period::Week.bucket_start(sunday, Weekday::Sun), sunday);
+        assert_eq!(
+            Period::Week.bucket_start(sunday, Weekday::Mon),
+            date("2024-03-11").and_time(NaiveTime::MIN)
+        );
+    }
+
+    #[test]
+    fn thins_bars_of_long_ranges() {
+        let first = date("2022-01-01");
+        let days: Vec<(String, i64)> = (0..1096)
+            .map(|idx| {
+                let day = first + Duration::days(idx);
+                (day.format("%Y-%m-%d").to_string(), idx % 7 + 1)
+            })
+            .collect();
+        let counts: Vec<(&str, i64)> = days
+            .iter()
+            .map(|(day, count)| (day.as_str(), *count))
+            .collect();
+        let config = Config::default();
+        let out = timelines(
+            &config,
+            &HashMap::new(),
+            "2022-01-01",
+            "2024-12-31",
+            &counts,
+        );
+
+        let bars = out.matches("<g data-v=").count();
+        assert!(bars <= config.max_bars, "{} bars", bars);
+        // 1096 days, two per bar.
+        assert_eq!(bars, 548);
+        assert!(out.contains("<svg class=graph width=1644 height=130>"));
+        assert!(
+            out.contains("<g data-v='2' data-d='2022-01-01' data-e='2022-01-02'"),
+            "{}",
+            &out[..out.len().min(2000)]
+        );
+    }
+
+    #[test]
+    fn short_selections_show_context_days() {
+        let config = Config {
+            context_days: 3,
+            ..Config::default()
+        };
+        let counts: Vec<(String, i64)> = (10..=20)
+            .map(|day| (format!("2024-03-{}", day), 1))
+            .collect();
+        let counts: Vec<(&str, i64)> = counts
+            .iter()
+            .map(|(day, count)| (day.as_str(), *count))
+            .collect();
+        let out = timelines(
+            &config,
+            &HashMap::new(),
+            "2024-03-15",
+            "2024-03-15",
+            &counts,
+        );
+
+        assert!(
+            out.contains("<svg class=graph width=21 height=130>"),
+            "{}",
+            out
+        );
+        assert_eq!(out.matches("<g class=ctx ").count(), 6);
+        for day in 12..=18 {
+            let class = if day == 15 { "" } else { " class=ctx" };
+            let bar = format!("<g{} data-v='1' data-d='2024-03-{}'", class, day);
+            assert!(out.contains(&bar), "{} in {}", bar, out);
+        }
+        assert!(!out.contains("data-d='2024-03-11'"));
+        assert!(!out.contains("data-d='2024-03-19'"));
+
+        let out = timelines(
+            &Config::default(),
+            &HashMap::new(),
+            "2024-03-15",
+            "2024-03-15",
+            &counts,
+        );
+        assert!(
+            out.contains("<svg class=graph width=3 height=130>"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn granularity_picks_the_bucket_size() {
+        let period = |pairs: &[(&str, &str)], to: &str| {
+            Period::from_params(&params(pairs), date("2024-03-01"), date(to))
+        };
+        assert_eq!(period(&[], "2024-03-07"), Period::Day);
+        assert_eq!(
+            period(&[("granularity", "hour")], "2024-03-07"),
+            Period::Hour
+        );
+        assert_eq!(
+            period(&[("granularity", "week")], "2024-03-31"),
+            Period::Week
+        );
+        // Hours only for selections of at most a week.
+        assert_eq!(
+            period(&[("granularity", "hour")], "2024-03-08"),
+            Period::Day
+        );
+        // `period` still works, and yields to `granularity`.
+        assert_eq!(period(&[("period", "hour")], "2024-03-07"), Period::Hour);
+        assert_eq!(
+            period(&[("period", "week"), ("granularity", "hour")], "2024-03-07"),
+            Period::Hour
+        );
+    }
+
+    #[test]
+    fn empty_sections_render_a_placeholder_when_enabled() {
+        let config = Config {
+            show_empty_sections: true,
+            ..Config::default()
+        };
+        let counts = [("2024-03-01", 4)];
+        let out = timelines(
+            &config,
+            &HashMap::new(),
+            "2024-03-01",
+            "2024-03-07",
+            &counts,
+        );
+        assert!(out.contains("<h1>Unique visitors: 4"), "{}", out);
+        assert!(
+            out.contains("<h1>RSS Readers: 0</h1>\n<div class=\"graph_outer empty\">No data</div>"),
+            "{}",
+            out
+        );
+        assert!(out.contains("<h1>Scrapers: 0</h1>"), "{}", out);
+    }
+
+    #[test]
+    fn empty_sections_are_omitted_by_default() {
+        let counts = [("2024-03-01", 4)];
+        let out = timelines(
+            &Config::default(),
+            &HashMap::new(),
+            "2024-03-01",
+            "2024-03-07",
+            &counts,
+        );
+        assert!(out.contains("<h1>Unique visitors: 4"), "{}", out);
+        assert!(!out.contains("RSS Readers"), "{}", out);
+        assert!(!out.contains("No data"), "{}", out);
+    }
+
+    #[test]
+    fn compares_ranges_of_different_lengths() {
+        let (data, totals) =
+            browser_visits(&[("2023-03-01", 2), ("2023-03-02", 3), ("2023-03-03", 4)]);
+        let comparison = Comparison {
+            data,
+            totals,
+            from_date: date("2023-03-01"),
+            to_date: date("2023-03-03"),
+        };
+        let counts: Vec<(String, i64)> = (1..=7)
+            .map(|day| (format!("2024-03-{:02}", day), 1))
+            .collect();
+        let counts: Vec<(&str, i64)> = counts
+            .iter()
+            .map(|(day, count)| (day.as_str(), *count))
+            .collect();
+        let out = compared_timelines(
+            &Config::default(),
+            &HashMap::new(),
+            "2024-03-01",
+            "2024-03-07",
+            &counts,
+            Some(&comparison),
+        );
+        assert!(
+            out.contains("<h1>Unique visitors: 7 <span class=cmp>vs 9</span>"),
+            "{}",
+            out
+        );
+        assert!(
+            out.contains("<svg class=graph width=21 height=130>"),
+            "{}",
+            out
+        );
+        // Both ranges start at the first bar, whatever their lengths.
+        for (day, count, x) in [("2023-03-01", 2, 0), ("2023-03-03", 4, 6)] {
+            let bar = format!("<g class=cmp data-v='{}' data-d='{}'", count, day);
+            let at = out
+                .find(&bar)
+                .unwrap_or_else(|| panic!("{} in {}", bar, out));
+            let bar = &out[at..at + out[at..].find("</g>").expect("bar end")];
+            assert!(bar.contains(&format!("<rect x={} ", x)), "{}", bar);
+        }
+        assert_eq!(out.matches("<g class=cmp ").count(), 3);
+        assert!(out.contains("data-d='2024-03-01'"));
+        assert!(out.contains("data-d='2024-03-07'"));
+    }
+
+    #[test]
+    fn timelines_use_configured_date_formats() {
+        let config = Config {
+            month_format: "%b %Y".to_string(),
+            day_format: "%d %b".to_string(),
+            ..Config::default()
+        };
+        let out = timelines(
+            &config,
+            &HashMap::new(),
+            "2024-02-20",
+            "2024-03-31",
+            &[("2024-03-01", 4), ("2024-03-15", 2)],
+        );
+        assert!(out.contains(">Mar 2024</text>"), "{}", out);
+        assert!(out.contains("data-l='15 Mar'"), "{}", out);
+        assert!(!out.contains("2024-03</text>"));
+    }
+
+    /// The `data:` payloads of a server-sent events body, in order.
+    struct Events {
+        body: Body,
+        pending: std::collections::VecDeque<serde_json::Value>,
+    }
+
+    impl Events {
+        fn new(response: Response) -> Self {
+            Self {
+                body: response.into_body(),
+                pending: Default::default(),
+            }
+        }
+
+        async fn next(&mut self) -> serde_json::Value {
+            loop {
+                if let Some(event) = self.pending.pop_front() {
+                    return event;
+                }
+                let frame = tokio::time::timeout(StdDuration::from_secs(5), self.body.frame())
+                    .await
+                    .expect("event in time")
+                    .expect("open stream")
+                    .expect("frame");
+                if let Ok(data) = frame.into_data() {
+                    let text = String::from_utf8(data.to_vec()).expect("utf-8");
+                    self.pending.extend(
+                        text.lines()
+                            .filter_map(|line| line.strip_prefix("data: "))
+                            .map(|json| serde_json::from_str(json).expect("json event")),
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_sends_ingested_lines() {
+        let state = state(memory_store());
+        let mut events = Events::new(stream_handler(State(state.clone())).await);
+        state
+            .store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/live")])
+            .await
+            .expect("insert");
+        let event = events.next().await;
+        assert_eq!(event["path"], "/live");
+        assert_eq!(event["type"], "browser");
+    }
+
+    #[tokio::test]
+    async fn stream_keeps_up_with_inserts_larger_than_its_buffer() {
+        let state = state(memory_store());
+        let mut events = Events::new(stream_handler(State(state.clone())).await);
+        let lines: Vec<Line> = (0..3000)
+            .map(|idx| hit("2024-01-10", "10:00:00", "10.0.0.1", &format!("/{}", idx)))
+            .collect();
+        state.store.insert(lines).await.expect("insert");
+        for idx in 0..3000 {
+            assert_eq!(events.next().await["path"], format!("/{}", idx));
+        }
+    }
+
+    #[tokio::test]
+    async fn compact_keeps_dashboard_numbers() {
+        let state = state(memory_store());
+        let with_referrer = |mut line: Line, referrer: &str| {
+            line.referrer = referrer.to_string();
+            line
+        };
+        let with_agent = |mut line: Line, user_agent: &str| {
+            line.user_agent = user_agent.to_string();
+            line
+        };
+        let feedly = "Feedly/1.0 (+http://www.feedly.com/fetcher.html; 3 subscribers)";
+        let lines = vec![
+            with_referrer(
+                hit("2024-01-10", "10:05:00", "10.0.0.1", "/"),
+                "https://news.example.org/a",
+            ),
+            with_referrer(
+                hit("2024-01-10", "10:00:00", "10.0.0.1", "/"),
+                "https://news.example.org/b",
+            ),
+            hit("2024-01-10", "10:07:00", "10.0.0.1", "/about"),
+            hit("2024-01-10", "10:10:00", "10.0.0.1", "/"),
+            hit("2024-01-10", "11:00:00", "10.0.0.2", "/"),
+            hit("2024-01-11", "09:00:00", "10.0.0.1", "/"),
+            hit("2024-01-11", "09:30:00", "10.0.0.1", "/"),
+            with_agent(
+                hit("2024-01-10", "06:00:00", "10.0.0.3", "/feed.xml"),
+                feedly,
+            ),
+            with_agent(
+                hit("2024-01-11", "06:00:00", "10.0.0.3", "/feed.xml"),
+                feedly,
+            ),
+            with_agent(hit("2024-01-10", "03:00:00", "10.0.0.4", "/"), "curl/8.0"),
+            with_agent(hit("2024-01-10", "03:01:00", "10.0.0.4", "/"), "curl/8.0"),
+            // After the cutoff, left as is.
+            hit("2024-02-05", "10:00:00", "10.0.0.1", "/"),
+            hit("2024-02-05", "10:01:00", "10.0.0.1", "/"),
+        ];
+        state.store.insert(lines).await.expect("insert");
+
+        let (from, to) = (date("2024-01-01"), date("2024-12-31"));
+        let views = [
+            HashMap::new(),
+            params(&[("host", "example.com")]),
+            params(&[("path", "/")]),
+            params(&[("ref_domain", "news.example.org")]),
+            params(&[("type", "bot")]),
+            params(&[("period", "week")]),
+        ];
+        let mut before = Vec::new();
+        for view in &views {
+            before.push(render_json(&state, view, from, to).await.expect("render"));
+        }
+        let bounces = bounce_rate(&state.store, "TRUE", &[])
+            .await
+            .expect("bounce rate");
+        let rows = row_count(&state.store).await;
+
+        let removed = state
+            .store
+            .compact(date("2024-02-01"))
+            .await
+            .expect("compact");
+        // `/` from news.example.org on the 10th, `/` on the 11th and the bot.
+        assert_eq!(removed, 3);
+        assert_eq!(row_count(&state.store).await, rows - removed as i64);
+        for (view, before) in views.iter().zip(before) {
+            let after = render_json(&state, view, from, to).await.expect("render");
+            assert_eq!(after, before, "view {:?}", view);
+        }
+        assert_eq!(
+            bounce_rate(&state.store, "TRUE", &[])
+                .await
+                .expect("bounce rate"),
+            bounces
+        );
+    }
+
+    #[tokio::test]
+    async fn major_versions_group_minor_versions() {
+        let store = memory_store();
+        let chrome = |ip: &str, version: &str| {
+            let mut line = hit("2024-01-10", "10:00:00", ip, "/");
+            line.user_agent = format!(
+                "Mozilla/5.0 (X11; Linux x86_64) AppleWebKit/537.36 \
+                 (KHTML, like Gecko) Chrome/{} Safari/537.36",
+                version
+            );
+            line
+        };
+        store
+            .insert(vec![
+                chrome("10.0.0.1", "120.0.6099.109"),
+                chrome("10.0.0.2", "120.0.6099.71"),
+                chrome("10.0.0.3", "119.0.6045.199"),
+            ])
+            .await
+            .expect("insert");
+        let rows = top_n_uniq(
+            &store,
+            AGENT_MAJOR_VERSION,
+            "type = 'browser'",
+            &[],
+            10,
+            0,
+            TableSort::Count,
+        )
+        .await
+        .expect("top browsers");
+        let rows: Vec<(&str, i64)> = rows
+            .iter()
+            .map(|row| (row.value.as_str(), row.count))
+            .collect();
+        assert_eq!(rows, vec![("Chrome 120", 2), ("Chrome 119", 1)]);
+    }
+
+    /// A Prometheus sample: metric name, labels in order, and value.
+    type Sample = (String, Vec<(String, String)>, f64);
+
+    /// Samples of a Prometheus text exposition, panicking on anything the
+    /// format doesn't allow, or on samples of a metric whose type wasn't
+    /// declared before them.
+    fn parse_prom(text: &str) -> Vec<Sample> {
+        let mut typed = Vec::new();
+        let mut samples = Vec::new();
+        for line in text.lines() {
+            if let Some(rest) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = rest.split_once(' ').expect("type line");
+                assert_eq!(kind, "gauge", "{}", line);
+                typed.push(name.to_string());
+                continue;
+            }
+            if line.starts_with("# HELP ") || line.is_empty() {
+                continue;
+            }
+            let name_end = line.find(['{', ' ']).expect("sample name");
+            let name = &line[..name_end];
+            assert!(
+                name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+                "{}",
+                line
+            );
+            assert!(typed.iter().any(|t| t == name), "untyped {}", line);
+            let mut rest = &line[name_end..];
+            let mut labels = Vec::new();
+            if let Some(mut inner) = rest.strip_prefix('{') {
+                while !inner.starts_with('}') {
+                    let (key, after) = inner.split_once("=\"").expect("label");
+                    let mut value = String::new();
+                    let mut chars = after.char_indices();
+                    let end = loop {
+                        match chars.next().expect("closing quote") {
+                            (idx, '"') => break idx,
+                            (_, '\\') => value.push(match chars.next().expect("escape").1 {
+                                'n' => '\n',
+                                c @ ('\\' | '"') => c,
+                                c => panic!("bad escape \\{} in {}", c, line),
+                            }),
+                            (_, c) => value.push(c),
+                        }
+                    };
+                    labels.push((key.trim_start_matches(',').to_string(), value));
+                    inner = &after[end + 1..];
+                }
+                rest = &inner[1..];
+            }
+            let value = rest
+                .trim()
+                .parse()
+                .unwrap_or_else(|_| panic!("value in {}", line));
+            samples.push((name.to_string(), labels, value));
+        }
+        samples
+    }
+
+    #[tokio::test]
+    async fn prom_export_parses_with_expected_metrics() {
+        let store = memory_store();
+        let mut feed = hit("2024-01-10", "06:00:00", "10.0.0.3", "/feed.xml");
+        feed.user_agent =
+            "Feedly/1.0 (+http://www.feedly.com/fetcher.html; 3 subscribers)".to_string();
+        let mut quoted = hit("2024-01-10", "10:01:00", "10.0.0.2", "/say \"hi\"\\now");
+        quoted.referrer = "https://news.example.org/".to_string();
+        store
+            .insert(vec![
+                hit("2024-01-10", "10:00:00", "10.0.0.1", "/"),
+                quoted,
+                feed,
+            ])
+            .await
+            .expect("insert");
+
+        let text = render_prom(&store, "TRUE", &[]).await.expect("render");
+        let samples = parse_prom(&text);
+        for name in [
+            "banan_stats_uniques",
+            "banan_stats_top_path_hits",
+            "banan_stats_top_referrer_hits",
+            "banan_stats_top_agent_uniques",
+        ] {
+            assert!(
+                samples.iter().any(|(n, _, _)| n == name),
+                "{} in {}",
+                name,
+                text
+            );
+        }
+        let label = |key: &str, value: &str| vec![(key.to_string(), value.to_string())];
+        assert!(samples.contains(&(
+            "banan_stats_uniques".to_string(),
+            label("type", "browser"),
+            2.0
+        )));
+        assert!(samples.contains(&(
+            "banan_stats_top_path_hits".to_string(),
+            label("path", "/say \"hi\"\\now"),
+            1.0
+        )));
+        assert!(samples.contains(&(
+            "banan_stats_top_agent_uniques".to_string(),
+            vec![
+                ("type".to_string(), "feed".to_string()),
+                ("agent".to_string(), "Feedly".to_string())
+            ],
+            3.0
+        )));
+    }
+
+    #[tokio::test]
+    async fn protocol_breakdown_counts_visitors_per_protocol() {
+        let state = state(memory_store());
+        let visit = |ip: &str, protocol: &str, tls_version: &str| {
+            let mut line = hit("2024-01-10", "10:00:00", ip, "/");
+            line.protocol = protocol.to_string();
+            line.tls_version = tls_version.to_string();
+            line
+        };
+        state
+            .store
+            .insert(vec![
+                visit("10.0.0.1", "HTTP/2.0", "TLS 1.3"),
+                visit("10.0.0.1", "HTTP/2.0", "TLS 1.3"),
+                visit("10.0.0.2", "HTTP/2.0", "TLS 1.3"),
+                visit("10.0.0.3", "HTTP/1.1", "TLS 1.2"),
+                visit("10.0.0.4", "HTTP/3.0", "TLS 1.3"),
+                visit("10.0.0.5", "HTTP/1.1", ""),
+                // Unknown, left out of both tables.
+                visit("10.0.0.6", "", ""),
+            ])
+            .await
+            .expect("insert");
+
+        let json = render_json(
+            &state,
+            &HashMap::new(),
+            date("2024-01-01"),
+            date("2024-01-31"),
+        )
+        .await
+        .expect("render");
+        assert_eq!(
+            json["tables"]["protocols"],
+            serde_json::json!([
+                {"value": "HTTP/1.1", "count": 2},
+                {"value": "HTTP/2.0", "count": 2},
+                {"value": "HTTP/3.0", "count": 1},
+            ])
+        );
+        assert_eq!(
+            json["tables"]["tls_versions"],
+            serde_json::json!([
+                {"value": "TLS 1.3", "count": 3},
+                {"value": "TLS 1.2", "count": 1},
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn metrics_sum_and_average_by_path() {
+        let store = memory_store();
+        let revenue = |ip: &str, path: &str, value: f64| {
+            let mut line = hit("2024-01-10", "10:00:00", ip, path);
+            line.metric = "revenue".to_string();
+            line.metric_value = Some(value);
+            line
+        };
+        store
+            .insert(vec![
+                revenue("10.0.0.1", "/checkout", 40.0),
+                revenue("10.0.0.2", "/checkout", 2.5),
+                revenue("10.0.0.3", "/cart", 10.0),
+                // A plain visit, counted but left out of the sums.
+                hit("2024-01-10", "10:00:00", "10.0.0.4", "/checkout"),
+            ])
+            .await
+            .expect("insert");
+
+        let rows = top_metrics(&store, "path", "metric = ?", &["revenue".to_string()])
+            .await
+            .expect("metrics");
+        let rows: Vec<(&str, f64, f64)> = rows
+            .iter()
+            .map(|row| (row.value.as_str(), row.sum, row.avg))
+            .collect();
+        assert_eq!(
+            rows,
+            vec![("/checkout", 42.5, 21.25), ("/cart", 10.0, 10.0)]
+        );
+        assert_eq!(row_count(&store).await, 4);
+    }
+
+    #[test]
+    fn type_filter_picks_the_tables_it_describes() {
+        let params = params(&[("type", "feed")]);
+        let specs = table_specs("date BETWEEN ? AND ? AND type = ?", &params);
+        let names: Vec<&str> = specs.iter().map(|spec| spec.name).collect();
+        assert!(names.contains(&"paths"));
+        assert!(names.contains(&"feeds"));
+        assert!(!names.contains(&"browsers"));
+        assert!(!names.contains(&"scrapers"));
+        for spec in &specs {
+            assert!(
+                !spec.where_clause.contains("type = 'browser'"),
+                "{}: {}",
+                spec.name,
+                spec.where_clause
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn type_filter_shows_feed_data() {
+        let state = state(memory_store());
+        let mut feed = hit("2024-01-10", "06:00:00", "10.0.0.3", "/feed.xml");
+        feed.user_agent =
+            "Feedly/1.0 (+http://www.feedly.com/fetcher.html; 3 subscribers)".to_string();
+        state
+            .store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/"), feed])
+            .await
+            .expect("insert");
+
+        let json = render_json(
+            &state,
+            &params(&[("type", "feed")]),
+            date("2024-01-01"),
+            date("2024-01-31"),
+        )
+        .await
+        .expect("render");
+        assert_eq!(
+            json["tables"]["paths"],
+            serde_json::json!([{"value": "/feed.xml", "count": 1}])
+        );
+        assert_eq!(
+            json["tables"]["feeds"],
+            serde_json::json!([{"value": "Feedly", "count": 3}])
+        );
+        assert!(json["tables"].get("browsers").is_none());
+        assert_eq!(json["totals"]["browser"], 0);
+        assert_eq!(json["totals"]["feed"], 3);
+    }
+
+    #[tokio::test]
+    async fn exports_include_custom_types() {
+        let state = state(memory_store());
+        let mut api = hit("2024-01-10", "10:00:00", "10.0.0.2", "/api");
+        api.r#type = "api".to_string();
+        state
+            .store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/"), api])
+            .await
+            .expect("insert");
+
+        let json = render_json(&state, &params(&[]), date("2024-01-01"), date("2024-01-31"))
+            .await
+            .expect("render");
+        assert_eq!(json["totals"]["api"], 1);
+        assert_eq!(json["totals"]["bot"], 0);
+        assert_eq!(
+            json["timelines"]["api"],
+            serde_json::json!([{"date": "2024-01-10", "count": 1}])
+        );
+
+        let text = render_prom(&state.store, "TRUE", &[])
+            .await
+            .expect("render");
+        let samples = parse_prom(&text);
+        assert!(samples.contains(&(
+            "banan_stats_top_agent_uniques".to_string(),
+            vec![
+                ("type".to_string(), "api".to_string()),
+                ("agent".to_string(), "Firefox".to_string())
+            ],
+            1.0
+        )));
+    }
+
+    #[test]
+    fn first_run_example_sends_known_event_fields() {
+        let mut out = String::new();
+        append_first_run(&mut out, &HeaderMap::new(), false);
+        let body = out
+            .split("--data-binary '")
+            .nth(1)
+            .and_then(|rest| rest.split('\'').next())
+            .expect("example body");
+        let event: serde_json::Map<String, serde_json::Value> =
+            serde_json::from_str(body).expect("example JSON");
+        assert!(event.contains_key("userAgent"));
+        for field in event.keys() {
+            assert!(
+                crate::ingest::EVENT_FIELDS.contains(&field.as_str()),
+                "{}",
+                field
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn cached_hosts_follow_the_store_version() {
+        let state = state(memory_store());
+        let mut line = hit("2024-01-10", "10:00:00", "10.0.0.1", "/");
+        line.host = "a.example".to_string();
+        state
+            .store
+            .insert(vec![line.clone()])
+            .await
+            .expect("insert");
+        assert_eq!(state.pages.hosts(&state.store).await, vec!["a.example"]);
+
+        line.host = "b.example".to_string();
+        state.store.insert(vec![line]).await.expect("insert");
+        assert_eq!(
+            state.pages.hosts(&state.store).await,
+            vec!["a.example", "b.example"]
+        );
+    }
+
+    const SCRIPT_PATH: &str = "/<script>alert(1)</script>";
+
+    #[test]
+    fn table_values_and_filters_are_escaped() {
+        let params = params(&[("path", SCRIPT_PATH), ("<b>", "'\"><i>")]);
+        let mut out = String::new();
+        append_table(
+            &mut out,
+            "Pages",
+            vec![RowCount {
+                value: SCRIPT_PATH.to_string(),
+                count: 1,
+            }],
+            &params,
+            "path",
+            None,
+            "",
+        );
+        append_table(
+            &mut out,
+            "Referrers",
+            vec![RowCount {
+                value: "evil.example/'><script>".to_string(),
+                count: 1,
+            }],
+            &params,
+            "ref_domain",
+            Some(|value| format!("https://{}", value)),
+            "",
+        );
+        append_active_filters(&mut out, &params, false);
+        append_host_filters(&mut out, &params, &["<b>host</b>".to_string()]);
+        out.push_str(&hidden_inputs(&params, &[]));
+        assert!(!out.contains("<script"), "{}", out);
+        assert!(!out.contains("<b>") && !out.contains("<i>"), "{}", out);
+        assert!(!out.contains("evil.example/'"), "{}", out);
+        assert!(!out.contains("'\""), "{}", out);
+        assert!(
+            out.contains("/&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "{}",
+            out
+        );
+    }
+
+    #[tokio::test]
+    async fn stored_and_requested_values_are_escaped_in_the_page() {
+        let store = memory_store();
+        let mut line = hit("2024-01-10", "10:00:00", "10.0.0.1", SCRIPT_PATH);
+        line.referrer = "https://evil.example/'><script>alert(2)</script>".to_string();
+        line.metric = "<script>alert(3)</script>".to_string();
+        line.metric_value = Some(1.0);
+        store.insert(vec![line]).await.expect("insert");
+        let query = format!(
+            "from=2024-01-01&to=2024-01-31&metric={}&utm_source=%3Cscript%3Ealert(4)",
+            url::form_urlencoded::byte_serialize(b"<script>alert(3)</script>").collect::<String>()
+        );
+        let response =
+            stats_handler(State(state(store)), HeaderMap::new(), RawQuery(Some(query))).await;
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("body")
+            .to_bytes();
+        let html = String::from_utf8(body.to_vec()).expect("utf-8");
+        assert!(!html.contains("<script>alert"), "{}", html);
+        assert!(
+            html.contains("/&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "{}",
+            html
+        );
+        assert!(html.contains("&lt;script&gt;alert(3)"), "{}", html);
+        assert!(html.contains("&lt;script&gt;alert(4)"), "{}", html);
+    }
+
+    /// Renders `/stats` for January 2024 with `Host: host` and `extra` params.
+    async fn stats_page(store: Store, host: &str, extra: &str) -> String {
+        let mut headers = HeaderMap::new();
+        headers.insert("Host", host.parse().expect("header"));
+        let query = format!("from=2024-01-01&to=2024-01-31{}", extra);
+        let response = stats_handler(State(state(store)), headers, RawQuery(Some(query))).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .expect("body")
+            .to_bytes();
+        String::from_utf8(body.to_vec()).expect("utf-8")
+    }
+
+    async fn two_host_store() -> Store {
+        let store = memory_store();
+        let mut blog = hit("2024-01-10", "10:00:00", "10.0.0.1", "/blog-post");
+        blog.host = "Blog.example.com".to_string();
+        let shop = hit("2024-01-10", "10:00:00", "10.0.0.2", "/shop-item");
+        store.insert(vec![blog, shop]).await.expect("insert");
+        store
+    }
+
+    #[tokio::test]
+    async fn the_host_header_scopes_the_dashboard() {
+        let html = stats_page(two_host_store().await, "blog.EXAMPLE.com:7070", "").await;
+        assert!(html.contains("/blog-post"), "{}", html);
+        assert!(!html.contains("/shop-item"), "{}", html);
+
+        // Hosts without rows get the summary of every host.
+        let html = stats_page(two_host_store().await, "stats.internal", "").await;
+        assert!(html.contains("Unique visitors by host"), "{}", html);
+        assert!(!html.contains("/blog-post"), "{}", html);
+    }
+
+    #[tokio::test]
+    async fn host_params_override_the_host_header() {
+        let html = stats_page(
+            two_host_store().await,
+            "blog.example.com",
+            "&host=example.com",
+        )
+        .await;
+        assert!(html.contains("/shop-item"), "{}", html);
+        assert!(!html.contains("/blog-post"), "{}", html);
+
+        let html = stats_page(two_host_store().await, "blog.example.com", "&host=*").await;
+        assert!(html.contains("/shop-item"), "{}", html);
+        assert!(html.contains("/blog-post"), "{}", html);
+    }
+
+    /// Gets the January 2024 dashboard with `headers`, returning the status
+    /// code and the `ETag`.
+    async fn get_stats(addr: std::net::SocketAddr, headers: &str) -> (u16, Option<String>) {
+        let head = format!(
+            "GET /stats?from=2024-01-01&to=2024-01-31 HTTP/1.1\r\n{}",
+            headers
+        );
+        let response = crate::admin::tests::send(addr, head).await;
+        let status = response
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .expect("status");
+        let etag = response
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("etag")
+                    .then(|| value.trim().to_string())
+            });
+        (status, etag)
+    }
+
+    #[tokio::test]
+    async fn unchanged_dashboards_are_not_modified() {
+        let store = memory_store();
+        store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/")])
+            .await
+            .expect("insert");
+        let mut state = state(store);
+        state.dashboard = Arc::new(Config {
+            token: Some("secret".to_string()),
+            ..Config::default()
+        });
+        let store = state.store.clone();
+        let addr = crate::admin::tests::serve(router(state)).await;
+        let auth = "Authorization: Bearer secret\r\n";
+
+        let (status, etag) = get_stats(addr, auth).await;
+        assert_eq!(status, 200);
+        let etag = etag.expect("etag");
+        for tag in [
+            etag.clone(),
+            format!("W/{}", etag),
+            format!("\"other\", {}", etag),
+            "*".into(),
+        ] {
+            let (status, sent) =
+                get_stats(addr, &format!("{}If-None-Match: {}\r\n", auth, tag)).await;
+            assert_eq!(status, 304, "{}", tag);
+            assert_eq!(sent.as_ref(), Some(&etag));
+        }
+        let (status, _) = get_stats(addr, &format!("{}If-None-Match: \"other\"\r\n", auth)).await;
+        assert_eq!(status, 200);
+
+        // Matching tags don't get around the token.
+        let (status, sent) = get_stats(addr, &format!("If-None-Match: {}\r\n", etag)).await;
+        assert_eq!(status, 401);
+        assert_eq!(sent, None);
+
+        store
+            .insert(vec![hit("2024-01-11", "10:00:00", "10.0.0.2", "/")])
+            .await
+            .expect("insert");
+        let (status, changed) =
+            get_stats(addr, &format!("{}If-None-Match: {}\r\n", auth, etag)).await;
+        assert_eq!(status, 200);
+        assert_ne!(changed.expect("etag"), etag);
+    }
+
+    #[test]
+    fn custom_type_titles_are_escaped() {
+        let (mut data, mut totals) = browser_visits(&[("2024-03-01", 4)]);
+        let typ = "<i>api</i>";
+        data.insert(
+            typ.to_string(),
+            HashMap::from([(date("2024-03-01").and_time(NaiveTime::MIN), 2)]),
+        );
+        totals.insert(typ.to_string(), 2);
+        let mut out = String::new();
+        append_timelines(
+            &mut out,
+            &Config::default(),
+            &data,
+            &totals,
+            None,
+            None,
+            None,
+            &HashMap::new(),
+            date("2024-03-01"),
+            date("2024-03-07"),
+        );
+        assert!(
+            out.contains("Unique &lt;i&gt;api&lt;/i&gt; visitors"),
+            "{}",
+            out
+        );
+        assert!(!out.contains("<i>"), "{}", out);
+    }
+
+    /// Checks the rollup's timelines against the raw rows', across hosts and
+    /// per host.
+    async fn assert_rollup_matches_raw(store: &Store) {
+        let bucket = Period::Day.bucket_sql(Weekday::Mon);
+        for host in [None, Some("example.com"), Some("blog.example.com")] {
+            let filters: HashMap<String, String> = host
+                .map(|host| ("host".to_string(), host.to_string()))
+                .into_iter()
+                .collect();
+            let (where_clause, args) = build_where("2024-01-01", "2024-01-31", &filters);
+            let rows = rollup_rows(Period::Day, &filters);
+            assert!(rows.is_some(), "{:?}", host);
+            let rollup = visits_by_type_date(store, &bucket, rows, &where_clause, &args)
+                .await
+                .expect("rollup");
+            let raw = visits_by_type_date(store, &bucket, None, &where_clause, &args)
+                .await
+                .expect("raw");
+            assert!(!raw.is_empty(), "{:?}", host);
+            assert_eq!(rollup, raw, "{:?}", host);
+        }
+    }
+
+    #[tokio::test]
+    async fn rollup_timelines_match_the_raw_rows() {
+        let store = memory_store();
+        let cookie = "6f1c1a52-58a1-4a8e-9d0c-1f4f0f5b7a05";
+        let mut first = hit("2024-01-10", "10:00:00", "10.0.0.1", "/");
+        first.set_cookie = cookie.to_string();
+        // The same visitor on another host, and on none.
+        let mut blog = hit("2024-01-10", "10:05:00", "10.0.0.1", "/");
+        blog.host = "blog.example.com".to_string();
+        let mut hostless = hit("2024-01-10", "10:06:00", "10.0.0.1", "/");
+        hostless.host = String::new();
+        store
+            .insert(vec![
+                first,
+                blog,
+                hostless,
+                hit("2024-01-10", "11:00:00", "10.0.0.2", "/"),
+                hit("2024-01-10", "11:01:00", "10.0.0.2", "/about"),
+                hit("2024-01-11", "09:00:00", "10.0.0.3", "/"),
+            ])
+            .await
+            .expect("insert");
+        assert_rollup_matches_raw(&store).await;
+
+        // Relinks the first visit, a day earlier, to the cookie.
+        let mut second = hit("2024-01-11", "09:30:00", "10.0.0.4", "/about");
+        second.uniq = cookie.to_string();
+        second.second_visit = true;
+        store.insert(vec![second]).await.expect("insert");
+        assert_rollup_matches_raw(&store).await;
+
+        store
+            .compact(NaiveDate::from_ymd_opt(2024, 2, 1).unwrap())
+            .await
+            .expect("compact");
+        assert!(row_count(&store).await < 7);
+        assert_rollup_matches_raw(&store).await;
+
+        store.rebuild_rollup().await.expect("rebuild");
+        assert_rollup_matches_raw(&store).await;
+    }
+}
@@ -0,0 +1,37 @@
+use crate::state::AppState;
+use axum::{extract::State, http::StatusCode, routing::get, Router};
+
+/// Probes for load balancers and orchestrators. Unlike the dashboard they
+/// never require the token.
+pub fn router(state: AppState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .with_state(state)
+}
+
+/// The process is up.
+async fn healthz_handler() -> &'static str {
+    "ok"
+}
+
+/// The database answers a query and the sidecar isn't shutting down.
+async fn readyz_handler(State(state): State<AppState>) -> (StatusCode, &'static str) {
+    if state.store.is_closing() {
+        return (StatusCode::SERVICE_UNAVAILABLE, "shutting down");
+    }
+    let result = state
+        .store
+        .with_conn(|conn| {
+            conn.query_row("SELECT 1", [], |row| row.get::<_, i32>(0))?;
+            Ok(())
+        })
+        .await;
+    match result {
+        Ok(()) => (StatusCode::OK, "ok"),
+        Err(err) => {
+            eprintln!("readiness check failed: {}", err);
+            (StatusCode::SERVICE_UNAVAILABLE, "database unavailable")
+        }
+    }
+}
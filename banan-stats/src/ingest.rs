@@ -1,25 +1,207 @@
-use crate::analyzer::Line;
+use crate::analyzer::{self, Line};
 use crate::state::AppState;
+use crate::store::Store;
 use axum::{
     body::Body,
-    extract::State,
-    http::StatusCode,
+    extract::{ConnectInfo, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{IntoResponse, Response},
-    routing::post,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
-use chrono::{DateTime, Utc};
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Utc};
+use chrono_tz::Tz;
+use flate2::read::MultiGzDecoder;
 use futures_util::StreamExt;
 use http_body_util::BodyExt;
-use serde::Deserialize;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use url::Url;
+
+/// Marker appended to fields cut to their configured maximum length.
+const TRUNCATED_MARKER: &str = "…";
+
+/// Name of metrics sent with a `value` but no `metric`.
+const DEFAULT_METRIC: &str = "value";
+
+/// A transparent 1x1 GIF, the response of the tracking pixel.
+const PIXEL_GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff\x21\xf9\x04\x01\x00\x00\x00\x00\x2c\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02\x44\x01\x00\x3b";
+
+/// Ingest options, fixed at startup.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Maximum stored length in bytes of each field; 0 disables the limit.
+    pub max_path_len: usize,
+    pub max_query_len: usize,
+    pub max_referrer_len: usize,
+    pub max_user_agent_len: usize,
+    /// Events buffered before they are handed to the store, so a large body
+    /// is inserted in chunks; 0 buffers the whole body.
+    pub max_batch_lines: usize,
+    /// Maximum length in bytes of a body line; longer ones are rejected. 0
+    /// disables the limit.
+    pub max_line_len: usize,
+    /// Maximum length in bytes of a JSON array body, which is only parsed
+    /// once complete; 0 disables the limit.
+    pub max_array_len: usize,
+    /// Skip events without a timestamp instead of stamping them with now.
+    pub require_timestamp: bool,
+    /// Latest accepted `timestamp`, in seconds after now.
+    pub max_future_secs: u64,
+    /// Earliest accepted `timestamp` year.
+    pub min_timestamp_year: i32,
+    /// What becomes of events whose `timestamp` is outside that window.
+    pub out_of_range_timestamps: OutOfRangeTimestamps,
+    /// Strip query strings and trailing slashes from `path`.
+    pub normalize_path: bool,
+    /// Key required in the `X-Api-Key` header of every request.
+    pub api_key: Option<String>,
+    /// Zone the `date` and `time` columns are written in.
+    pub timezone: Tz,
+    /// Peers whose forwarded headers are believed when finding the client
+    /// IP of an event that doesn't carry one.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// Handling of an event `timestamp` too far in the future or the past, as
+/// sent by clients with a skewed clock.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutOfRangeTimestamps {
+    /// Stamp the event with the current time, as if it had none.
+    #[default]
+    Now,
+    /// Skip the event, counting it as rejected.
+    Reject,
+}
+
+/// Loopback only: trusting a private network would let any host on it
+/// choose the client IP of its requests, so proxies there are opted into
+/// with `--trusted-proxies`.
+const DEFAULT_TRUSTED_PROXIES: &[&str] = &["127.0.0.0/8", "::1/128"];
+
+/// An IP network such as `10.0.0.0/8`; a bare address is a single host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(u32::from(net).into(), u32::from(ip).into(), 32, self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(u128::from(net), u128::from(ip), 128, self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether the first `prefix` of the `bits` low bits of `net` and `ip` agree.
+fn prefix_matches(net: u128, ip: u128, bits: u32, prefix: u8) -> bool {
+    let shift = bits - u32::from(prefix);
+    shift >= bits || net >> shift == ip >> shift
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid network {:?}, expected e.g. 10.0.0.0/8", value);
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr = addr
+            .trim()
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            max_path_len: 2048,
+            max_query_len: 2048,
+            max_referrer_len: 2048,
+            max_user_agent_len: 1024,
+            max_batch_lines: 10_000,
+            max_line_len: 64 * 1024,
+            max_array_len: 8 * 1024 * 1024,
+            require_timestamp: false,
+            max_future_secs: 24 * 60 * 60,
+            min_timestamp_year: 2000,
+            out_of_range_timestamps: OutOfRangeTimestamps::Now,
+            normalize_path: false,
+            api_key: None,
+            timezone: Tz::UTC,
+            trusted_proxies: DEFAULT_TRUSTED_PROXIES
+                .iter()
+                .map(|net| net.parse().expect("network"))
+                .collect(),
+        }
+    }
+}
 
 pub fn router(state: AppState) -> Router {
     Router::new()
         .route("/ingest", post(ingest_handler))
+        .route("/ingest/pixel.gif", get(pixel_handler))
         .with_state(state)
 }
 
-#[derive(Deserialize)]
+/// Names of the `IngestEvent` fields, as sent; a form post needs at least one.
+pub(crate) const EVENT_FIELDS: &[&str] = &[
+    "eventId",
+    "timestamp",
+    "host",
+    "path",
+    "query",
+    "ip",
+    "userAgent",
+    "referrer",
+    "contentType",
+    "acceptLanguage",
+    "screenSize",
+    "protocol",
+    "tlsVersion",
+    "metric",
+    "value",
+    "setCookie",
+    "uniq",
+    "secondVisit",
+];
+
+#[derive(Default, Deserialize)]
 #[serde(rename_all = "camelCase")]
 struct IngestEvent {
     #[serde(default)]
@@ -41,6 +223,20 @@ struct IngestEvent {
     #[serde(default)]
     content_type: String,
     #[serde(default)]
+    status: Option<u16>,
+    #[serde(default)]
+    accept_language: String,
+    #[serde(default)]
+    screen_size: String,
+    #[serde(default)]
+    protocol: String,
+    #[serde(default)]
+    tls_version: String,
+    #[serde(default)]
+    metric: String,
+    #[serde(default)]
+    value: Option<f64>,
+    #[serde(default)]
     set_cookie: String,
     #[serde(default)]
     uniq: String,
@@ -48,63 +244,785 @@ struct IngestEvent {
     second_visit: bool,
 }
 
-async fn ingest_handler(State(state): State<AppState>, body: Body) -> Response {
-    match ingest_stream(state, body).await {
-        Ok(()) => StatusCode::ACCEPTED.into_response(),
+/// Key of a tracking pixel request, which can't send headers.
+#[derive(Deserialize)]
+struct PixelKey {
+    key: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct IngestParams {
+    #[serde(default)]
+    strict: bool,
+    /// Ingest key of a form post, which can't send headers.
+    key: Option<String>,
+}
+
+async fn ingest_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(IngestParams { strict, key }): Query<IngestParams>,
+    body: Body,
+) -> Response {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok());
+    let form = content_type.is_some_and(is_form_content_type);
+    let key = headers
+        .get("x-api-key")
+        .and_then(|v| v.to_str().ok())
+        .or(key.as_deref().filter(|_| form));
+    if !has_api_key(&state.ingest, key) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    if state.store.is_closing() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    if let Some(content_type) = content_type.filter(|v| !form && !is_json_content_type(v)) {
+        return (
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!(
+                "unsupported Content-Type {}; send application/x-ndjson, application/json \
+                 or application/x-www-form-urlencoded",
+                content_type
+            ),
+        )
+            .into_response();
+    }
+    let gzip = match headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("") | Some("identity") => false,
+        Some("gzip") | Some("x-gzip") => true,
+        Some(_) => return StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response(),
+    };
+    let client = client_ip(addr.ip(), &headers, &state.ingest.trusted_proxies);
+    let result = if form {
+        ingest_form(state, gzip, &headers, client.to_string(), body).await
+    } else {
+        ingest_stream(state, gzip, strict, client.to_string(), body).await
+    };
+    match result {
+        Ok(summary) => Json(summary).into_response(),
         Err(err) => {
             eprintln!("ingest failed: {}", err);
-            StatusCode::BAD_REQUEST.into_response()
+            err.into_response()
         }
     }
 }
 
-async fn ingest_stream(state: AppState, body: Body) -> Result<(), anyhow::Error> {
+/// Whether an ingest body of this `Content-Type` can be read. An empty one
+/// is read as NDJSON, like a missing one.
+fn is_json_content_type(value: &str) -> bool {
+    let media_type = value.split(';').next().unwrap_or_default().trim();
+    media_type.is_empty()
+        || [
+            "application/x-ndjson",
+            "application/json",
+            "application/jsonlines",
+        ]
+        .iter()
+        .any(|allowed| media_type.eq_ignore_ascii_case(allowed))
+}
+
+/// Whether an ingest body of this `Content-Type` is an HTML form post.
+fn is_form_content_type(value: &str) -> bool {
+    let media_type = value.split(';').next().unwrap_or_default().trim();
+    media_type.eq_ignore_ascii_case("application/x-www-form-urlencoded")
+}
+
+/// Records a single event described by the query string, for `<img>`
+/// beacons. The user agent, IP and language come from the request headers,
+/// and the path and host default to those of the embedding page.
+async fn pixel_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(PixelKey { key }): Query<PixelKey>,
+    Query(mut evt): Query<IngestEvent>,
+) -> Response {
+    if !has_api_key(&state.ingest, key.as_deref()) {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    if state.store.is_closing() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+
+    let client = client_ip(addr.ip(), &headers, &state.ingest.trusted_proxies);
+    fill_from_headers(&mut evt, &headers);
+    if let Some(line) = event_to_line(evt, &state.ingest, &client.to_string())
+        && let Err(err) = state.store.insert(vec![line]).await
+    {
+        eprintln!("pixel ingest failed: {}", err);
+    }
+    (
+        [
+            (header::CONTENT_TYPE, "image/gif"),
+            (
+                header::CACHE_CONTROL,
+                "no-store, no-cache, must-revalidate, max-age=0",
+            ),
+            (header::PRAGMA, "no-cache"),
+            (header::EXPIRES, "0"),
+        ],
+        PIXEL_GIF,
+    )
+        .into_response()
+}
+
+/// Fills in what a browser request tells about an event its page couldn't
+/// describe itself: the user agent and language from the request headers,
+/// and the host, path and query of the page from its `Referer`.
+fn fill_from_headers(evt: &mut IngestEvent, headers: &HeaderMap) {
+    let header_value = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default()
+            .to_string()
+    };
+    if evt.user_agent.is_empty() {
+        evt.user_agent = header_value(header::USER_AGENT.as_str());
+    }
+    if evt.accept_language.is_empty() {
+        evt.accept_language = header_value(header::ACCEPT_LANGUAGE.as_str());
+    }
+    if let Ok(page) = Url::parse(&header_value(header::REFERER.as_str())) {
+        if evt.host.is_empty() {
+            evt.host = page.host_str().unwrap_or_default().to_string();
+        }
+        if evt.path.is_empty() {
+            evt.path = page.path().to_string();
+            evt.query = page.query().unwrap_or_default().to_string();
+        }
+    }
+}
+
+/// Address of the client behind `peer`. Forwarded headers are only believed
+/// when `peer` is a trusted proxy, and `X-Forwarded-For` is read from the
+/// right, skipping further trusted proxies, since the hops left of the first
+/// untrusted one are whatever the client chose to send. `X-Real-IP` is used
+/// when there is no `X-Forwarded-For`.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+    let mut client = peer.to_canonical();
+    if !is_trusted(client) {
+        return client;
+    }
+    let hops: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .collect();
+    if hops.is_empty() {
+        return headers
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<IpAddr>().ok())
+            .map_or(client, |ip| ip.to_canonical());
+    }
+    for hop in hops.iter().rev() {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip.to_canonical();
+        if !is_trusted(client) {
+            break;
+        }
+    }
+    client
+}
+
+/// Whether `key` matches the configured ingest key, if any.
+fn has_api_key(config: &Config, key: Option<&str>) -> bool {
+    config
+        .api_key
+        .as_deref()
+        .is_none_or(|expected| key == Some(expected))
+}
+
+/// Reads newline-delimited JSON events from `body`, inflating it on the fly
+/// when `gzip` is set. A body starting with `[` is instead read whole as a
+/// JSON array of events. Malformed lines are counted and skipped, unless
+/// `strict` is set, in which case they fail the whole request.
+///
+/// A corrupt or truncated gzip body fails with the batch in progress unsent;
+/// earlier batches stay stored, and sending the body again only inserts the
+/// events they lacked, as stored event ids are skipped.
+async fn ingest_stream(
+    state: AppState,
+    gzip: bool,
+    strict: bool,
+    client_ip: String,
+    body: Body,
+) -> Result<IngestSummary, IngestError> {
     let mut stream = body.into_data_stream();
+    let mut inflater = gzip.then(Inflater::new);
+    let mut received;
     let mut buffer: Vec<u8> = Vec::new();
-    let mut lines = Vec::new();
+    let mut batch = Batch {
+        strict,
+        client_ip,
+        ..Batch::default()
+    };
+    // Unknown until the first non-whitespace byte arrives.
+    let mut is_array = None;
+    // Set while skipping the rest of a line that grew past `max_line_len`.
+    let mut discarding = false;
+    let config = &state.ingest;
 
-    while let Some(chunk) = stream.next().await {
-        let bytes = chunk?;
-        buffer.extend_from_slice(&bytes);
+    loop {
+        let bytes: &[u8] = match inflater.as_mut() {
+            Some(inflater) => match inflater.next(&mut stream).await? {
+                Some(bytes) => bytes,
+                None => break,
+            },
+            None => match stream.next().await {
+                Some(chunk) => {
+                    received = chunk.context("failed to read body")?;
+                    &received
+                }
+                None => break,
+            },
+        };
+        buffer.extend_from_slice(bytes);
+        if is_array.is_none() {
+            is_array = starts_array(&buffer);
+        }
+        if is_array == Some(true) {
+            let limit = config.max_array_len;
+            if limit > 0 && buffer.len() > limit {
+                return Err(anyhow::anyhow!("JSON array body over {} bytes", limit).into());
+            }
+            continue;
+        }
         while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
             let line = buffer.drain(..=pos).collect::<Vec<u8>>();
-            let trimmed = line
-                .iter()
-                .filter(|b| **b != b'\n' && **b != b'\r')
-                .copied()
-                .collect::<Vec<u8>>();
-            if trimmed.is_empty() {
+            if discarding {
+                discarding = false;
                 continue;
             }
-            let evt: IngestEvent = serde_json::from_slice(&trimmed)?;
-            lines.push(event_to_line(evt));
+            batch.parse_line(&line, config)?;
+            if batch.is_full(config) {
+                batch
+                    .flush(&state.store)
+                    .await
+                    .map_err(IngestError::Store)?;
+            }
+        }
+        // The line in progress is already too long: drop what arrived of it
+        // rather than buffering it until its newline shows up.
+        if config.max_line_len > 0 && buffer.len() > config.max_line_len {
+            if !discarding {
+                batch.line += 1;
+                batch.reject_oversized(config)?;
+                discarding = true;
+            }
+            buffer.clear();
         }
     }
 
-    if !buffer.is_empty() {
-        let trimmed = buffer
+    if is_array.is_none() {
+        is_array = starts_array(&buffer);
+    }
+    if is_array == Some(true) {
+        let events: Vec<IngestEvent> =
+            serde_json::from_slice(&buffer).context("invalid JSON array body")?;
+        for evt in events {
+            batch.push(evt, config);
+            if batch.is_full(config) {
+                batch
+                    .flush(&state.store)
+                    .await
+                    .map_err(IngestError::Store)?;
+            }
+        }
+    } else {
+        let mut lines = buffer.split(|b| *b == b'\n');
+        if discarding {
+            lines.next();
+        }
+        for line in lines {
+            batch.parse_line(line, config)?;
+            if batch.is_full(config) {
+                batch
+                    .flush(&state.store)
+                    .await
+                    .map_err(IngestError::Store)?;
+            }
+        }
+    }
+
+    if batch.malformed > 0 {
+        eprintln!("ingest rejected {} malformed lines", batch.malformed);
+    }
+    if batch.oversized > 0 {
+        eprintln!(
+            "ingest rejected {} lines over {} bytes",
+            batch.oversized, config.max_line_len
+        );
+    }
+    if batch.skipped > 0 {
+        eprintln!(
+            "ingest skipped {} events without a usable timestamp or event id",
+            batch.skipped
+        );
+    }
+
+    batch
+        .flush(&state.store)
+        .await
+        .map_err(IngestError::Store)?;
+    Ok(IngestSummary {
+        accepted: batch.accepted,
+        inserted: batch.inserted,
+        rejected: batch.malformed + batch.oversized + batch.skipped,
+    })
+}
+
+/// Bytes of the body inflated at a time, which bounds how far a small gzip
+/// body can expand before `max_line_len` and `max_array_len` are checked.
+const INFLATE_CHUNK: usize = 64 * 1024;
+
+/// Inflates a gzip body as it streams in, `INFLATE_CHUNK` bytes at a time.
+/// Concatenated gzip members are read one after the other, as `gzip -d`
+/// does.
+struct Inflater {
+    decoder: MultiGzDecoder<Received>,
+    out: Vec<u8>,
+}
+
+impl Inflater {
+    fn new() -> Self {
+        Inflater {
+            decoder: MultiGzDecoder::new(Received::default()),
+            out: vec![0; INFLATE_CHUNK],
+        }
+    }
+
+    /// The next piece of the inflated body, or `None` once the body has been
+    /// read to its end. Fails as soon as the gzip stream turns out corrupt,
+    /// or, when truncated, once the body ends.
+    async fn next(
+        &mut self,
+        stream: &mut axum::body::BodyDataStream,
+    ) -> Result<Option<&[u8]>, anyhow::Error> {
+        loop {
+            match self.decoder.read(&mut self.out) {
+                Ok(0) => return Ok(None),
+                Ok(n) => return Ok(Some(&self.out[..n])),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(anyhow::Error::new(err).context("invalid gzip body")),
+            }
+            let received = self.decoder.get_mut();
+            match stream.next().await {
+                Some(chunk) => received
+                    .bytes
+                    .extend(chunk.context("failed to read body")?.iter()),
+                None => received.ended = true,
+            }
+        }
+    }
+}
+
+/// Body bytes received but not inflated yet. Running out of them before the
+/// body ends reads as `WouldBlock`, which the decoder passes on with its
+/// state intact, so it carries on once the next chunk is in.
+#[derive(Default)]
+struct Received {
+    bytes: VecDeque<u8>,
+    ended: bool,
+}
+
+impl Read for Received {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.bytes.is_empty() && !self.ended {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        self.bytes.read(buf)
+    }
+}
+
+/// Records the single event of an HTML form post, whose field names are
+/// those of a JSON event; other fields, such as a submit button, are
+/// ignored, but a form with none of them is rejected. Like the tracking
+/// pixel, it falls back on the request headers for what the form leaves out.
+async fn ingest_form(
+    state: AppState,
+    gzip: bool,
+    headers: &HeaderMap,
+    client_ip: String,
+    body: Body,
+) -> Result<IngestSummary, IngestError> {
+    let config = &state.ingest;
+    let limit = match config.max_line_len {
+        0 => usize::MAX,
+        max => max,
+    };
+    let mut bytes = axum::body::to_bytes(body, limit)
+        .await
+        .map_err(|err| anyhow::anyhow!("failed to read form body: {}", err))?
+        .to_vec();
+    if gzip {
+        let mut inflated = Vec::new();
+        MultiGzDecoder::new(bytes.as_slice())
+            .read_to_end(&mut inflated)
+            .context("invalid gzip body")?;
+        bytes = inflated;
+    }
+    let fields: Vec<(String, String)> =
+        serde_urlencoded::from_bytes(&bytes).context("invalid form body")?;
+    if !fields
+        .iter()
+        .any(|(name, _)| EVENT_FIELDS.contains(&name.as_str()))
+    {
+        return Err(anyhow::anyhow!(
+            "form has none of the event fields ({})",
+            EVENT_FIELDS.join(", ")
+        )
+        .into());
+    }
+    let mut evt: IngestEvent = serde_urlencoded::from_bytes(&bytes).context("invalid form body")?;
+    fill_from_headers(&mut evt, headers);
+
+    let mut batch = Batch {
+        client_ip,
+        ..Batch::default()
+    };
+    batch.push(evt, config);
+    if batch.skipped > 0 {
+        eprintln!("ingest skipped a form event without a usable timestamp or event id");
+    }
+    batch
+        .flush(&state.store)
+        .await
+        .map_err(IngestError::Store)?;
+    Ok(IngestSummary {
+        accepted: batch.accepted,
+        inserted: batch.inserted,
+        rejected: batch.skipped,
+    })
+}
+
+/// `ip - user [time] "METHOD target PROTOCOL" status bytes "referrer" "user agent"`,
+/// the last two being optional as in the common log format.
+static RE_COMBINED_LOG: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r#"^(\S+) \S+ \S+ \[([^\]]+)\] "(\S+) (\S+)[^"]*" (\d{3}) \S+(?: "((?:[^"\\]|\\.)*)" "((?:[^"\\]|\\.)*)")?"#)
+        .expect("re")
+});
+
+/// Outcome of `import_log`.
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Requests other than successful `GET`s, and events `event_to_line`
+    /// skipped.
+    pub skipped: usize,
+    pub malformed: usize,
+}
+
+/// Inserts the successful `GET` requests of an Apache/nginx combined log
+/// file, gzipped when its name ends in `.gz`, as visits to `host`. Event ids
+/// are derived from the log lines, so importing a file again doesn't
+/// duplicate rows.
+pub async fn import_log(
+    store: &Store,
+    config: &Config,
+    path: &str,
+    host: &str,
+) -> Result<ImportSummary, anyhow::Error> {
+    let file = std::fs::File::open(path).with_context(|| format!("failed to open {}", path))?;
+    let reader: Box<dyn BufRead> = if path.ends_with(".gz") {
+        Box::new(BufReader::new(MultiGzDecoder::new(file)))
+    } else {
+        Box::new(BufReader::new(file))
+    };
+    let mut batch = Batch::default();
+    let mut filtered = 0;
+    for line in reader.split(b'\n') {
+        let line = line.with_context(|| format!("failed to read {}", path))?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_combined_log(line, host) {
+            Some((evt, "GET", 200)) => batch.push(evt, config),
+            Some(_) => filtered += 1,
+            None => batch.malformed += 1,
+        }
+        if batch.is_full(config) {
+            batch.flush(store).await?;
+        }
+    }
+    batch.flush(store).await?;
+    Ok(ImportSummary {
+        imported: batch.accepted,
+        skipped: filtered + batch.skipped,
+        malformed: batch.malformed,
+    })
+}
+
+/// Parses a combined log line into an event, along with the request method
+/// and response status. The timestamp is `[dd/Mon/yyyy:HH:MM:SS +0000]`, and
+/// `-` stands for a missing referrer or user agent.
+fn parse_combined_log<'a>(line: &'a str, host: &str) -> Option<(IngestEvent, &'a str, u16)> {
+    let caps = RE_COMBINED_LOG.captures(line)?;
+    let timestamp = DateTime::parse_from_str(&caps[2], "%d/%b/%Y:%H:%M:%S %z").ok()?;
+    let status = caps[5].parse().ok()?;
+    let logged = |idx| {
+        let value = caps.get(idx).map_or("", |m| m.as_str());
+        if value == "-" {
+            String::new()
+        } else {
+            value.replace("\\\"", "\"")
+        }
+    };
+    let (path, query) = match caps[4].split_once('?') {
+        Some((path, query)) => (path.to_string(), query.to_string()),
+        None => (caps[4].to_string(), String::new()),
+    };
+    let evt = IngestEvent {
+        event_id: analyzer::hash_uuid(line),
+        timestamp: Some(timestamp.with_timezone(&Utc)),
+        host: host.to_string(),
+        path,
+        query,
+        ip: caps[1].to_string(),
+        user_agent: logged(7),
+        referrer: logged(6),
+        ..IngestEvent::default()
+    };
+    Some((evt, caps.get(3).map_or("", |m| m.as_str()), status))
+}
+
+/// Whether `buffer` holds a JSON array, or `None` while it's only whitespace.
+fn starts_array(buffer: &[u8]) -> Option<bool> {
+    buffer
+        .iter()
+        .find(|b| !b.is_ascii_whitespace())
+        .map(|b| *b == b'[')
+}
+
+/// Response of `POST /ingest`.
+#[derive(Serialize)]
+struct IngestSummary {
+    /// Events passed on to the store.
+    accepted: usize,
+    /// Accepted events that were stored, leaving out those whose `event_id`
+    /// was already stored.
+    inserted: usize,
+    /// Malformed or oversized lines and events `event_to_line` skipped.
+    rejected: usize,
+}
+
+/// Why `POST /ingest` failed: answered as `{"error": ..., "line": N}`, with
+/// `400` when the body is at fault and `500` when storing it failed, so
+/// clients can tell what is worth retrying.
+#[derive(Debug)]
+enum IngestError {
+    /// The body couldn't be read; `line` is the 1-based line of the body at
+    /// fault, when it is a single one.
+    Body {
+        error: anyhow::Error,
+        line: Option<usize>,
+    },
+    Store(anyhow::Error),
+}
+
+impl From<anyhow::Error> for IngestError {
+    fn from(error: anyhow::Error) -> Self {
+        IngestError::Body { error, line: None }
+    }
+}
+
+impl fmt::Display for IngestError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IngestError::Body {
+                error,
+                line: Some(line),
+            } => write!(f, "line {}: {:#}", line, error),
+            IngestError::Body { error, line: None } => write!(f, "{:#}", error),
+            IngestError::Store(error) => write!(f, "storing events: {:#}", error),
+        }
+    }
+}
+
+impl IntoResponse for IngestError {
+    fn into_response(self) -> Response {
+        match self {
+            IngestError::Body { error, line } => {
+                let mut body = serde_json::json!({ "error": format!("{:#}", error) });
+                if let Some(line) = line {
+                    body["line"] = line.into();
+                }
+                (StatusCode::BAD_REQUEST, Json(body)).into_response()
+            }
+            // The details stay in the log; retrying may well succeed.
+            IngestError::Store(_) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Json(serde_json::json!({ "error": "failed to store events" })),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// Rows parsed from a request body and not yet handed to the store.
+#[derive(Default)]
+struct Batch {
+    lines: Vec<Line>,
+    /// Rows already handed to the store.
+    accepted: usize,
+    /// Rows the store didn't have yet.
+    inserted: usize,
+    /// Lines that aren't a valid event.
+    malformed: usize,
+    /// Lines longer than `max_line_len`.
+    oversized: usize,
+    /// Events `event_to_line` skipped.
+    skipped: usize,
+    /// Lines of the body seen so far, blank ones included.
+    line: usize,
+    /// Fail on the first malformed line instead of counting it.
+    strict: bool,
+    /// IP of events sent without one.
+    client_ip: String,
+}
+
+impl Batch {
+    /// Parses one line of the body, ignoring blank lines.
+    fn parse_line(&mut self, line: &[u8], config: &Config) -> Result<(), IngestError> {
+        self.line += 1;
+        let trimmed = line
             .iter()
             .filter(|b| **b != b'\n' && **b != b'\r')
             .copied()
             .collect::<Vec<u8>>();
-        if !trimmed.is_empty() {
-            let evt: IngestEvent = serde_json::from_slice(&trimmed)?;
-            lines.push(event_to_line(evt));
+        if trimmed.is_empty() {
+            return Ok(());
+        }
+        if config.max_line_len > 0 && trimmed.len() > config.max_line_len {
+            return self.reject_oversized(config);
+        }
+        match serde_json::from_slice::<IngestEvent>(&trimmed) {
+            Ok(evt) => self.push(evt, config),
+            Err(err) if self.strict => {
+                return Err(IngestError::Body {
+                    error: err.into(),
+                    line: Some(self.line),
+                });
+            }
+            Err(_) => self.malformed += 1,
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, evt: IngestEvent, config: &Config) {
+        match event_to_line(evt, config, &self.client_ip) {
+            Some(line) => self.lines.push(line),
+            None => self.skipped += 1,
         }
     }
 
-    if !lines.is_empty() {
-        state.store.insert(lines).await?;
+    /// Counts the current line as oversized, or fails on it when strict.
+    fn reject_oversized(&mut self, config: &Config) -> Result<(), IngestError> {
+        if self.strict {
+            return Err(IngestError::Body {
+                error: anyhow::anyhow!("line over {} bytes", config.max_line_len),
+                line: Some(self.line),
+            });
+        }
+        self.oversized += 1;
+        Ok(())
+    }
+
+    fn is_full(&self, config: &Config) -> bool {
+        config.max_batch_lines > 0 && self.lines.len() >= config.max_batch_lines
+    }
+
+    /// Inserts the buffered rows.
+    async fn flush(&mut self, store: &Store) -> Result<(), anyhow::Error> {
+        if self.lines.is_empty() {
+            return Ok(());
+        }
+        let lines = std::mem::take(&mut self.lines);
+        self.accepted += lines.len();
+        self.inserted += store.insert(lines).await?;
+        Ok(())
+    }
+}
+
+/// Whether `ts` is at most `max_future_secs` ahead of `now` and not before
+/// `min_timestamp_year`.
+fn is_plausible_timestamp(ts: DateTime<Utc>, now: DateTime<Utc>, config: &Config) -> bool {
+    // A window too large to represent is no limit.
+    let latest = i64::try_from(config.max_future_secs)
+        .ok()
+        .and_then(chrono::Duration::try_seconds)
+        .and_then(|ahead| now.checked_add_signed(ahead));
+    ts.year() >= config.min_timestamp_year && latest.is_none_or(|latest| ts <= latest)
+}
+
+/// Moves a query string left in `path` over to `query`, unless one was sent
+/// separately, and drops trailing slashes, so `/foo/?v=1` and `/foo?v=2`
+/// are both stored as `/foo`. The root path stays `/`.
+fn normalize_path(evt: &mut IngestEvent) {
+    if let Some((path, query)) = evt.path.split_once('?') {
+        if evt.query.is_empty() {
+            evt.query = query.to_string();
+        }
+        evt.path.truncate(path.len());
     }
-    Ok(())
+    let trimmed = evt.path.trim_end_matches('/').len();
+    evt.path.truncate(trimmed.max(1).min(evt.path.len()));
 }
 
-fn event_to_line(evt: IngestEvent) -> Line {
-    let ts = evt.timestamp.unwrap_or_else(Utc::now);
-    Line {
+/// Converts an event into a row, or `None` when it has no timestamp and
+/// `require_timestamp` is set, an out-of-range one while rejecting those, or
+/// an `eventId` that isn't a UUID. `client_ip` fills in a missing `ip`.
+fn event_to_line(mut evt: IngestEvent, config: &Config, client_ip: &str) -> Option<Line> {
+    // Unlike a cookie, the id can't just be dropped: the event would then be
+    // stored again on every retry.
+    if !evt.event_id.is_empty() && !is_uuid(&evt.event_id) {
+        return None;
+    }
+    if evt.ip.is_empty() {
+        evt.ip = client_ip.to_string();
+    }
+    if config.normalize_path {
+        normalize_path(&mut evt);
+    }
+    truncate_field(&mut evt.path, config.max_path_len);
+    truncate_field(&mut evt.query, config.max_query_len);
+    truncate_field(&mut evt.referrer, config.max_referrer_len);
+    truncate_field(&mut evt.user_agent, config.max_user_agent_len);
+    // Both are UUID columns; a malformed value would fail the whole batch.
+    if !is_uuid(&evt.set_cookie) {
+        evt.set_cookie.clear();
+    }
+    if !is_uuid(&evt.uniq) {
+        evt.uniq.clear();
+    }
+
+    let now = Utc::now();
+    let ts = match evt.timestamp {
+        Some(ts) if is_plausible_timestamp(ts, now, config) => ts,
+        Some(_) if config.out_of_range_timestamps == OutOfRangeTimestamps::Reject => return None,
+        Some(_) => now,
+        None if config.require_timestamp => return None,
+        None => now,
+    }
+    .with_timezone(&config.timezone);
+    Some(Line {
         event_id: evt.event_id,
         date: ts.format("%Y-%m-%d").to_string(),
-        time: ts.format("%H:%M:%S").to_string(),
+        time: ts.format("%H:%M:%S%.3f").to_string(),
         host: evt.host,
         path: evt.path,
         query: evt.query,
@@ -112,14 +1030,61 @@ fn event_to_line(evt: IngestEvent) -> Line {
         user_agent: evt.user_agent,
         referrer: evt.referrer,
         r#type: content_type_to_type(&evt.content_type),
+        content_type: evt.content_type,
+        status: evt.status,
+        accept_language: evt.accept_language,
+        screen_size: evt.screen_size,
+        protocol: evt.protocol,
+        tls_version: evt.tls_version,
+        metric: match evt.value {
+            Some(_) if evt.metric.is_empty() => DEFAULT_METRIC.to_string(),
+            Some(_) => evt.metric,
+            None => String::new(),
+        },
+        metric_value: evt.value,
         agent: String::new(),
+        agent_version: String::new(),
         os: String::new(),
+        device: String::new(),
         ref_domain: String::new(),
+        ref_channel: String::new(),
+        utm_source: String::new(),
+        utm_campaign: String::new(),
+        country: String::new(),
         mult: 0,
         set_cookie: evt.set_cookie,
         uniq: evt.uniq,
         second_visit: evt.second_visit,
+    })
+}
+
+/// Cuts `value` to at most `max` bytes on a char boundary, ending it with
+/// `TRUNCATED_MARKER` so shortened values are recognizable, unless `max` is
+/// too small to hold the marker.
+fn truncate_field(value: &mut String, max: usize) {
+    if max == 0 || value.len() <= max {
+        return;
     }
+    let marker = if max >= TRUNCATED_MARKER.len() {
+        TRUNCATED_MARKER
+    } else {
+        ""
+    };
+    let mut end = max - marker.len();
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+    value.truncate(end);
+    value.push_str(marker);
+}
+
+/// Whether `value` is a UUID in its hyphenated form.
+fn is_uuid(value: &str) -> bool {
+    value.len() == 36
+        && value.char_indices().all(|(idx, c)| match idx {
+            8 | 13 | 18 | 23 => c == '-',
+            _ => c.is_ascii_hexdigit(),
+        })
 }
 
 fn content_type_to_type(content_type: &str) -> String {
@@ -130,3 +1095,360 @@ fn content_type_to_type(content_type: &str) -> String {
         String::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn truncated(value: &str, max: usize) -> String {
+        let mut value = value.to_string();
+        truncate_field(&mut value, max);
+        value
+    }
+
+    #[test]
+    fn truncates_with_marker() {
+        assert_eq!(truncated("/abcdefghij", 8), "/abcd…");
+        assert_eq!(truncated("/abcdefghij", 3), "…");
+    }
+
+    #[test]
+    fn keeps_values_within_max() {
+        assert_eq!(truncated("/abc", 4), "/abc");
+        assert_eq!(truncated("/abc", 0), "/abc");
+    }
+
+    #[test]
+    fn truncates_without_marker_below_its_length() {
+        assert_eq!(truncated("/abcdefghij", 2), "/a");
+        assert_eq!(truncated("/abcdefghij", 1), "/");
+    }
+
+    #[test]
+    fn never_exceeds_max_or_splits_chars() {
+        let value = "/héllo/wörld/ünïcode";
+        for max in 1..=value.len() {
+            let out = truncated(value, max);
+            assert!(out.len() <= max, "{:?} is longer than {}", out, max);
+        }
+        assert_eq!(truncated("/é", 2), "/");
+        assert_eq!(truncated("/ééé", 5), "/…");
+    }
+
+    #[test]
+    fn event_paths_are_truncated_before_storage() {
+        let config = Config {
+            max_path_len: 16,
+            ..Config::default()
+        };
+        let evt = IngestEvent {
+            path: format!("/{}", "a".repeat(4096)),
+            ..IngestEvent::default()
+        };
+        let line = event_to_line(evt, &config, "203.0.113.7").expect("line");
+        assert_eq!(line.path.len(), 16);
+        assert_eq!(line.path, format!("/{}…", "a".repeat(12)));
+    }
+
+    #[tokio::test]
+    async fn malformed_cookies_are_stored_as_null() {
+        let good = "0b6b1c5e-6a7f-4c2d-9d3e-2f1a5b8c7d90";
+        let evt = |set_cookie: &str, path: &str| IngestEvent {
+            path: path.to_string(),
+            user_agent: crate::store::tests::FIREFOX.to_string(),
+            set_cookie: set_cookie.to_string(),
+            uniq: "not-a-uuid".to_string(),
+            ..IngestEvent::default()
+        };
+        let lines: Vec<Line> = [evt("'; DROP TABLE stats; --", "/bad"), evt(good, "/good")]
+            .into_iter()
+            .map(|evt| event_to_line(evt, &Config::default(), "203.0.113.7").expect("line"))
+            .collect();
+        assert_eq!(lines[0].set_cookie, "");
+        assert_eq!(lines[0].uniq, "");
+        assert_eq!(lines[1].set_cookie, good);
+
+        let store = crate::store::tests::memory_store();
+        store.insert(lines).await.expect("insert");
+        let cookies: Vec<(String, Option<String>)> = store
+            .with_conn(|conn| {
+                let mut stmt =
+                    conn.prepare("SELECT path, set_cookie::VARCHAR FROM stats ORDER BY path")?;
+                let mut rows = stmt.query([])?;
+                let mut cookies = Vec::new();
+                while let Some(row) = rows.next()? {
+                    cookies.push((row.get(0)?, row.get(1)?));
+                }
+                Ok(cookies)
+            })
+            .await
+            .expect("cookies");
+        assert_eq!(
+            cookies,
+            vec![
+                ("/bad".to_string(), None),
+                ("/good".to_string(), Some(good.to_string())),
+            ]
+        );
+    }
+
+    #[test]
+    fn strict_mode_skips_events_without_timestamp() {
+        let config = Config {
+            require_timestamp: true,
+            ..Config::default()
+        };
+        assert!(event_to_line(IngestEvent::default(), &config, "203.0.113.7").is_none());
+
+        let evt = IngestEvent {
+            timestamp: Some(Utc::now()),
+            ..IngestEvent::default()
+        };
+        assert!(event_to_line(evt, &config, "203.0.113.7").is_some());
+    }
+
+    #[test]
+    fn lenient_mode_stamps_events_without_timestamp_with_now() {
+        let config = Config::default();
+        let before = Utc::now().with_timezone(&config.timezone).naive_local();
+        let line = event_to_line(IngestEvent::default(), &config, "203.0.113.7").expect("line");
+        let after = Utc::now().with_timezone(&config.timezone).naive_local();
+        let stamped = chrono::NaiveDateTime::parse_from_str(
+            &format!("{} {}", line.date, line.time),
+            "%Y-%m-%d %H:%M:%S%.3f",
+        )
+        .expect("date and time");
+        assert!(
+            stamped >= before - chrono::Duration::milliseconds(1) && stamped <= after,
+            "{} not between {} and {}",
+            stamped,
+            before,
+            after
+        );
+    }
+
+    /// State over a database opened with `--db-path :memory:`.
+    fn memory_state(config: Config) -> AppState {
+        AppState {
+            store: std::sync::Arc::new(crate::store::tests::memory_store()),
+            dashboard: Default::default(),
+            pages: Default::default(),
+            ingest: std::sync::Arc::new(config),
+        }
+    }
+
+    #[tokio::test]
+    async fn ingests_and_queries_in_memory() {
+        let state = memory_state(Config::default());
+        let body = [
+            r#"{"path": "/", "timestamp": "2024-01-10T10:00:00Z", "ip": "203.0.113.7"}"#,
+            r#"{"path": "/about", "timestamp": "2024-01-10T10:01:00Z", "ip": "203.0.113.7"}"#,
+            r#"{"path": "/", "timestamp": "2024-01-11T09:00:00Z", "ip": "198.51.100.9"}"#,
+        ]
+        .join("\n");
+        let summary = ingest_stream(state.clone(), false, false, "127.0.0.1".into(), body.into())
+            .await
+            .unwrap_or_else(|_| panic!("ingest failed"));
+        assert_eq!((summary.accepted, summary.inserted), (3, 3));
+
+        // Read back on the reader connections, which share the database.
+        for _ in 0..2 {
+            let hits: Vec<(String, i64)> = state
+                .store
+                .with_conn(|conn| {
+                    let mut stmt = conn
+                        .prepare("SELECT path, COUNT(*) FROM stats GROUP BY path ORDER BY path")?;
+                    let mut rows = stmt.query([])?;
+                    let mut hits = Vec::new();
+                    while let Some(row) = rows.next()? {
+                        hits.push((row.get(0)?, row.get(1)?));
+                    }
+                    Ok(hits)
+                })
+                .await
+                .expect("query");
+            assert_eq!(hits, vec![("/".to_string(), 2), ("/about".to_string(), 1)]);
+        }
+    }
+
+    #[test]
+    fn events_with_malformed_ids_are_rejected() {
+        let evt = |event_id: &str| IngestEvent {
+            event_id: event_id.to_string(),
+            path: "/".to_string(),
+            ..IngestEvent::default()
+        };
+        let config = Config::default();
+        for event_id in ["", "0b6b1c5e-6a7f-4c2d-9d3e-2f1a5b8c7d90"] {
+            assert!(event_to_line(evt(event_id), &config, "203.0.113.7").is_some());
+        }
+        for event_id in [
+            "42",
+            "0b6b1c5e6a7f4c2d9d3e2f1a5b8c7d90",
+            "'; DROP TABLE stats; --",
+        ] {
+            assert!(
+                event_to_line(evt(event_id), &config, "203.0.113.7").is_none(),
+                "{}",
+                event_id
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_event_ids_count_as_rejected() {
+        let state = memory_state(Config::default());
+        let body = [
+            r#"{"eventId": "0b6b1c5e-6a7f-4c2d-9d3e-2f1a5b8c7d90", "path": "/"}"#,
+            r#"{"eventId": "not-a-uuid", "path": "/"}"#,
+        ]
+        .join("\n");
+        let summary = ingest_stream(state, false, false, "127.0.0.1".into(), body.into())
+            .await
+            .unwrap_or_else(|_| panic!("ingest failed"));
+        assert_eq!(
+            (summary.accepted, summary.inserted, summary.rejected),
+            (1, 1, 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn array_bodies_have_their_own_size_limit() {
+        let state = memory_state(Config {
+            max_array_len: 64,
+            ..Config::default()
+        });
+        let event = r#"{"path": "/"}"#;
+        let small = Body::from(format!("[{}]", event));
+        let summary = ingest_stream(state.clone(), false, false, "203.0.113.7".into(), small)
+            .await
+            .unwrap_or_else(|_| panic!("small array rejected"));
+        assert_eq!(summary.accepted, 1);
+
+        let large = Body::from(format!("[{}]", [event; 8].join(",")));
+        let result = ingest_stream(state, false, false, "203.0.113.7".into(), large).await;
+        assert!(matches!(result, Err(IngestError::Body { .. })));
+    }
+
+    #[test]
+    fn only_loopback_proxies_are_trusted_by_default() {
+        let trusted = Config::default().trusted_proxies;
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "198.51.100.9".parse().unwrap());
+        let forwarded: IpAddr = "198.51.100.9".parse().unwrap();
+        for proxy in ["127.0.0.1", "::1"] {
+            let peer = proxy.parse().unwrap();
+            assert_eq!(client_ip(peer, &headers, &trusted), forwarded, "{}", proxy);
+        }
+        for peer in ["10.0.0.5", "172.17.0.2", "192.168.1.1", "fd00::1"] {
+            let peer: IpAddr = peer.parse().unwrap();
+            assert_eq!(client_ip(peer, &headers, &trusted), peer);
+        }
+
+        let docker: Vec<IpNet> = vec!["172.16.0.0/12".parse().unwrap()];
+        let peer = "172.17.0.2".parse().unwrap();
+        assert_eq!(client_ip(peer, &headers, &docker), forwarded);
+    }
+
+    fn gzipped(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).expect("compress");
+        encoder.finish().expect("compress")
+    }
+
+    /// `bytes` as a body arriving a few bytes at a time.
+    fn trickled(bytes: Vec<u8>) -> Body {
+        let chunks = bytes
+            .chunks(3)
+            .map(|chunk| Ok::<_, io::Error>(chunk.to_vec()))
+            .collect::<Vec<_>>();
+        Body::from_stream(futures_util::stream::iter(chunks))
+    }
+
+    const TWO_EVENTS: &str = concat!(
+        r#"{"path": "/", "timestamp": "2024-01-10T10:00:00Z", "ip": "203.0.113.7"}"#,
+        "\n",
+        r#"{"path": "/about", "timestamp": "2024-01-10T10:01:00Z", "ip": "203.0.113.7"}"#,
+        "\n",
+    );
+
+    #[tokio::test]
+    async fn gzip_bodies_are_inflated_as_they_arrive() {
+        let state = memory_state(Config::default());
+        let body = trickled(gzipped(TWO_EVENTS.as_bytes()));
+        let summary = ingest_stream(state, true, false, "127.0.0.1".into(), body)
+            .await
+            .unwrap_or_else(|_| panic!("ingest failed"));
+        assert_eq!((summary.accepted, summary.rejected), (2, 0));
+    }
+
+    #[tokio::test]
+    async fn every_gzip_member_is_read() {
+        let state = memory_state(Config::default());
+        let mut bytes = gzipped(TWO_EVENTS.as_bytes());
+        bytes.extend(gzipped(
+            br#"{"path": "/", "timestamp": "2024-01-11T09:00:00Z", "ip": "198.51.100.9"}"#,
+        ));
+        let summary = ingest_stream(state, true, false, "127.0.0.1".into(), trickled(bytes))
+            .await
+            .unwrap_or_else(|_| panic!("ingest failed"));
+        assert_eq!((summary.accepted, summary.rejected), (3, 0));
+    }
+
+    #[tokio::test]
+    async fn corrupt_and_truncated_gzip_bodies_are_bad_requests() {
+        let state = memory_state(Config::default());
+        let bytes = gzipped(TWO_EVENTS.as_bytes());
+        let truncated = bytes[..bytes.len() - 4].to_vec();
+        let mut corrupt = bytes.clone();
+        corrupt[bytes.len() - 8] ^= 0xff;
+        for (name, body) in [("truncated", truncated), ("corrupt", corrupt)] {
+            let result = ingest_stream(
+                state.clone(),
+                true,
+                false,
+                "127.0.0.1".into(),
+                trickled(body),
+            )
+            .await;
+            let Err(err) = result else {
+                panic!("{} body accepted", name);
+            };
+            assert_eq!(
+                err.into_response().status(),
+                StatusCode::BAD_REQUEST,
+                "{}",
+                name
+            );
+        }
+        assert_eq!(crate::store::tests::row_count(&state.store).await, 0);
+    }
+
+    #[tokio::test]
+    async fn inflated_lines_over_max_line_len_are_rejected() {
+        let config = Config {
+            max_line_len: 1024,
+            ..Config::default()
+        };
+        // Inflates to 16 MiB without a newline, in a body of a few KiB.
+        let mut data = vec![b'a'; 16 * 1024 * 1024];
+        data.push(b'\n');
+        data.extend_from_slice(TWO_EVENTS.as_bytes());
+        let bytes = gzipped(&data);
+        assert!(bytes.len() < 64 * 1024);
+
+        let state = memory_state(config.clone());
+        let summary = ingest_stream(state, true, false, "127.0.0.1".into(), bytes.clone().into())
+            .await
+            .unwrap_or_else(|_| panic!("ingest failed"));
+        assert_eq!((summary.accepted, summary.rejected), (2, 1));
+
+        let state = memory_state(config);
+        let result = ingest_stream(state, true, true, "127.0.0.1".into(), bytes.into()).await;
+        assert!(matches!(
+            result,
+            Err(IngestError::Body { line: Some(1), .. })
+        ));
+    }
+}
@@ -1,53 +1,859 @@
+mod admin;
 mod analyzer;
 mod dashboard;
+mod health;
 mod ingest;
+mod query;
+mod rules;
 mod store;
 mod state;
 
 use anyhow::Context;
-use clap::Parser;
-use std::net::SocketAddr;
+use chrono::Utc;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use futures_util::FutureExt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(name = "banan-stats")]
 struct Args {
+    #[arg(long)]
+    config: Option<String>,
     #[arg(long, default_value = ":7070")]
     listen: String,
     #[arg(long, default_value = "clj_simple_stats.duckdb")]
     db_path: String,
+    #[arg(long, value_enum, default_value_t = store::Shard::Single)]
+    shard: store::Shard,
+    #[arg(long, default_value_t = 0)]
+    compact_after_days: u32,
+    #[arg(long, default_value_t = 0)]
+    retention_days: u32,
+    #[arg(long, default_value_t = 0)]
+    checkpoint_interval_secs: u64,
+    #[arg(long, value_enum, default_value_t = analyzer::FeedUniq::Agent)]
+    feed_uniq: analyzer::FeedUniq,
+    #[arg(long, value_enum, default_value_t = analyzer::LocalReferrers::Keep)]
+    local_referrers: analyzer::LocalReferrers,
+    #[arg(long = "internal-domain", value_delimiter = ',')]
+    internal_domains: Vec<String>,
+    #[arg(long, value_enum, default_value_t = analyzer::StoreUserAgent::Full)]
+    store_ua: analyzer::StoreUserAgent,
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true", default_value_t = false)]
+    html_as_browser: bool,
+    #[arg(long, value_enum, value_delimiter = ',')]
+    uniq_fields: Vec<analyzer::UniqField>,
+    #[arg(long)]
+    rules: Option<String>,
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true", default_value_t = false)]
+    raw_agents: bool,
+    #[arg(long)]
+    geoip_db: Option<String>,
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true", default_value_t = false)]
+    no_store_ip: bool,
+    #[arg(long, default_value_t = 0)]
+    uniq_salt_days: u32,
+    #[arg(long, default_value_t = 100_000)]
+    max_subscribers: i64,
+    #[arg(long, default_value_t = 4)]
+    read_pool_size: usize,
+    #[arg(long, default_value_t = ingest::Config::default().max_path_len)]
+    max_path_len: usize,
+    #[arg(long, default_value_t = ingest::Config::default().max_query_len)]
+    max_query_len: usize,
+    #[arg(long, default_value_t = ingest::Config::default().max_referrer_len)]
+    max_referrer_len: usize,
+    #[arg(long, default_value_t = ingest::Config::default().max_user_agent_len)]
+    max_user_agent_len: usize,
+    #[arg(long, default_value_t = ingest::Config::default().max_batch_lines)]
+    max_batch_lines: usize,
+    #[arg(long, default_value_t = ingest::Config::default().max_line_len)]
+    max_line_len: usize,
+    #[arg(long, default_value_t = ingest::Config::default().max_array_len)]
+    max_array_len: usize,
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true", default_value_t = false)]
+    require_timestamp: bool,
+    #[arg(long, default_value_t = ingest::Config::default().max_future_secs)]
+    max_future_secs: u64,
+    #[arg(long, default_value_t = ingest::Config::default().min_timestamp_year)]
+    min_timestamp_year: i32,
+    #[arg(long, value_enum, default_value_t = ingest::OutOfRangeTimestamps::Now)]
+    out_of_range_timestamps: ingest::OutOfRangeTimestamps,
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true", default_value_t = false)]
+    normalize_path: bool,
+    #[arg(long)]
+    ingest_key: Option<String>,
+    #[arg(long, value_delimiter = ',', default_values_t = ingest::Config::default().trusted_proxies)]
+    trusted_proxies: Vec<ingest::IpNet>,
+    #[arg(long)]
+    dashboard_token: Option<String>,
+    #[arg(long, default_value = dashboard::DEFAULT_MONTH_FORMAT, value_parser = dashboard::parse_date_format)]
+    date_format: String,
+    #[arg(long, default_value = dashboard::DEFAULT_DAY_FORMAT, value_parser = dashboard::parse_date_format)]
+    day_format: String,
+    #[arg(long, default_value_t = dashboard::Config::default().prom_range_days)]
+    prom_range_days: u32,
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true", default_value_t = false)]
+    show_empty_sections: bool,
+    #[arg(long, action = ArgAction::Set, num_args = 0..=1, require_equals = true, default_missing_value = "true", default_value_t = false)]
+    feed_average_active_only: bool,
+    #[arg(long, default_value_t = dashboard::Config::default().week_start)]
+    week_start: chrono::Weekday,
+    #[arg(long, default_value_t = dashboard::Config::default().max_bars)]
+    max_bars: usize,
+    #[arg(long, default_value_t = dashboard::Config::default().context_days)]
+    context_days: u32,
+    #[arg(long, default_value_t = chrono_tz::Tz::UTC)]
+    timezone: chrono_tz::Tz,
+    #[arg(long, default_value_t = dashboard::Config::default().cache_ttl_secs)]
+    dashboard_cache_ttl: u64,
+    #[arg(long, value_enum, default_value_t = dashboard::DefaultRange::Year)]
+    default_range: dashboard::DefaultRange,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Re-run the analyzer over stored rows, optionally scoped by date and filters
+    Reanalyze {
+        #[arg(long, default_value = "0001-01-01")]
+        from: String,
+        #[arg(long, default_value = "9999-12-31")]
+        to: String,
+        /// Only reanalyze rows where COLUMN = VALUE (repeatable)
+        #[arg(long = "where", value_name = "COLUMN=VALUE", value_parser = parse_filter)]
+        filters: Vec<(String, String)>,
+    },
+    /// Recompute the daily rollup behind the unfiltered timelines
+    RebuildRollup,
+    /// Import the successful GET requests of an Apache/nginx combined access log
+    Import {
+        /// Log file, read as gzip when it ends in .gz
+        path: String,
+        /// Host the requests were served for, which the log doesn't record
+        #[arg(long)]
+        host: String,
+    },
+    /// Inspect the classification rules
+    Rules {
+        #[command(subcommand)]
+        command: RulesCommand,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum RulesCommand {
+    /// Print the effective rules (built-in plus --rules) as JSON
+    Dump,
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let args = Args::parse();
-    let store = Arc::new(store::Store::open(&args.db_path)?);
-    let http_addr = normalize_listen_addr(&args.listen)?;
+    let args = Args::parse_from(args_with_config(std::env::args_os().collect())?);
+    let mut rules = rules::Rules::builtin();
+    if let Some(path) = &args.rules {
+        rules.merge(rules::Rules::load(path)?);
+    }
+    let rules = rules.compile()?;
+
+    if let Some(Command::Rules {
+        command: RulesCommand::Dump,
+    }) = args.command
+    {
+        println!("{}", serde_json::to_string_pretty(rules.rules())?);
+        return Ok(());
+    }
+
+    let geoip = match &args.geoip_db {
+        Some(path) => Some(Arc::new(
+            maxminddb::Reader::open_readfile(path)
+                .with_context(|| format!("failed to open GeoIP database {}", path))?,
+        )),
+        None => None,
+    };
+    let analyzer_config = analyzer::Config {
+        feed_uniq: args.feed_uniq,
+        local_referrers: args.local_referrers,
+        internal_domains: args.internal_domains,
+        store_ua: args.store_ua,
+        html_as_browser: args.html_as_browser,
+        uniq_fields: args.uniq_fields,
+        rules,
+        raw_agents: args.raw_agents,
+        geoip,
+        uniq_salt: match uniq_salt_days(args.uniq_salt_days, args.no_store_ip) {
+            Some(days) => Some(Arc::new(analyzer::UniqSalt::new(
+                days,
+                uniq_salt_path(&args.db_path),
+            )?)),
+            None => None,
+        },
+        drop_ip: args.no_store_ip,
+        max_subscribers: args.max_subscribers,
+    };
+    let store = Arc::new(store::Store::open(
+        &args.db_path,
+        analyzer_config,
+        args.read_pool_size,
+        args.shard,
+    )?);
+
+    let ingest_config = ingest::Config {
+        max_path_len: args.max_path_len,
+        max_query_len: args.max_query_len,
+        max_referrer_len: args.max_referrer_len,
+        max_user_agent_len: args.max_user_agent_len,
+        max_batch_lines: args.max_batch_lines,
+        max_line_len: args.max_line_len,
+        max_array_len: args.max_array_len,
+        require_timestamp: args.require_timestamp,
+        max_future_secs: args.max_future_secs,
+        min_timestamp_year: args.min_timestamp_year,
+        out_of_range_timestamps: args.out_of_range_timestamps,
+        normalize_path: args.normalize_path,
+        api_key: args.ingest_key.filter(|key| !key.is_empty()),
+        timezone: args.timezone,
+        trusted_proxies: args.trusted_proxies,
+    };
+
+    if let Some(Command::Import { path, host }) = &args.command {
+        let summary = ingest::import_log(&store, &ingest_config, path, host).await?;
+        println!(
+            "imported {} events, skipped {}, {} malformed lines",
+            summary.imported, summary.skipped, summary.malformed
+        );
+        return Ok(());
+    }
+    if let Some(Command::Reanalyze { from, to, filters }) = args.command {
+        let filters: HashMap<String, String> = filters.into_iter().collect();
+        let (where_clause, where_args) = dashboard::build_where(&from, &to, &filters);
+        let updated = store.reanalyze(where_clause, where_args).await?;
+        println!("reanalyzed {} rows", updated);
+        return Ok(());
+    }
+    if let Some(Command::RebuildRollup) = args.command {
+        store.rebuild_rollup().await?;
+        println!("rebuilt daily rollup");
+        return Ok(());
+    }
 
-    let app_state = state::AppState { store: store.clone() };
-    let http_app = dashboard::router(app_state.clone()).merge(ingest::router(app_state));
-    let http_listener = tokio::net::TcpListener::bind(http_addr).await?;
-    let http_server = axum::serve(http_listener, http_app).with_graceful_shutdown(shutdown_signal());
+    let http_addrs = args
+        .listen
+        .split(',')
+        .map(|listen| normalize_listen_addr(listen.trim()))
+        .collect::<Result<Vec<_>, _>>()?;
 
-    println!("banan-stats listening: http={}", http_addr);
+    if args.compact_after_days > 0 {
+        spawn_compactor(store.clone(), args.compact_after_days, args.timezone);
+    }
+    if args.retention_days > 0 {
+        spawn_pruner(store.clone(), args.retention_days, args.timezone);
+    }
+    if args.checkpoint_interval_secs > 0 {
+        spawn_checkpointer(store.clone(), args.checkpoint_interval_secs);
+    }
 
-    let http_task = async { http_server.await.map_err(anyhow::Error::from) };
-    tokio::try_join!(http_task)?;
+    let dashboard_config = dashboard::Config {
+        token: args.dashboard_token.filter(|token| !token.is_empty()),
+        month_format: args.date_format,
+        day_format: args.day_format,
+        prom_range_days: args.prom_range_days,
+        show_empty_sections: args.show_empty_sections,
+        feed_average_active_only: args.feed_average_active_only,
+        week_start: args.week_start,
+        max_bars: args.max_bars,
+        context_days: args.context_days,
+        timezone: args.timezone,
+        default_range: args.default_range,
+        cache_ttl_secs: args.dashboard_cache_ttl,
+    };
+    let app_state = state::AppState {
+        store: store.clone(),
+        dashboard: Arc::new(dashboard_config),
+        pages: Arc::new(dashboard::PageCache::default()),
+        ingest: Arc::new(ingest_config),
+    };
+    let http_app = dashboard::router(app_state.clone())
+        .merge(ingest::router(app_state.clone()))
+        .merge(admin::router(app_state.clone()))
+        .merge(health::router(app_state));
+    let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+    let mut http_servers = Vec::new();
+    for addr in &http_addrs {
+        let mut shutdown_rx = shutdown_rx.clone();
+        match addr {
+            ListenAddr::Tcp(addr) => {
+                let http_listener = bind_tcp(*addr).await?;
+                let http_server = axum::serve(
+                    http_listener,
+                    http_app
+                        .clone()
+                        .into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(async move {
+                    let _ = shutdown_rx.wait_for(|closing| *closing).await;
+                });
+                http_servers.push(
+                    async { http_server.await.map_err(anyhow::Error::from) }.boxed(),
+                );
+            }
+            #[cfg(unix)]
+            ListenAddr::Unix(path) => {
+                let http_listener = bind_unix(path)?;
+                http_servers.push(
+                    serve_unix(http_listener, path.clone(), http_app.clone(), shutdown_rx).boxed(),
+                );
+            }
+            #[cfg(not(unix))]
+            ListenAddr::Unix(path) => {
+                anyhow::bail!("cannot listen on {}: Unix sockets need a Unix system", path.display());
+            }
+        }
+    }
+    let shutdown_store = store.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown_store.close();
+        let _ = shutdown_tx.send(true);
+    });
+
+    let listening: Vec<_> = http_addrs.iter().map(|addr| addr.to_string()).collect();
+    println!("banan-stats listening: http={}", listening.join(","));
+
+    futures_util::future::try_join_all(http_servers).await?;
+    // Requests still streaming when the signal came have been served; let
+    // their inserts commit before exiting.
+    store.drain().await;
     Ok(())
 }
 
-fn normalize_listen_addr(listen: &str) -> Result<SocketAddr, anyhow::Error> {
+/// Command line `args` with those of the `--config` TOML file inserted
+/// before them. Keys mirror the flag names (`db-path = "..."`); flags also
+/// given on the command line keep their command line values.
+fn args_with_config(mut args: Vec<OsString>) -> Result<Vec<OsString>, anyhow::Error> {
+    let Some(path) = flag_value(&args, "--config") else {
+        return Ok(args);
+    };
+    let text =
+        std::fs::read_to_string(&path).with_context(|| format!("failed to read config {}", path))?;
+    let table: toml::Table = text
+        .parse()
+        .with_context(|| format!("failed to parse config {}", path))?;
+    let mut file_args = Vec::new();
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        if long == "config" || has_flag(&args, &long) {
+            continue;
+        }
+        let flag = format!("--{}", long);
+        let values = match value {
+            // Switches take `=false` too, to turn off one that defaults on.
+            toml::Value::Boolean(set) => {
+                file_args.push(OsString::from(format!("{}={}", flag, set)));
+                continue;
+            }
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(_) | toml::Value::Float(_) => value.to_string(),
+                _ => anyhow::bail!("unsupported value for {} in config {}", key, path),
+            };
+            file_args.push(OsString::from(format!("{}={}", flag, value)));
+        }
+    }
+    args.splice(1..1, file_args);
+    Ok(args)
+}
+
+/// Value of `flag` on the command line, given as `--flag VALUE` or
+/// `--flag=VALUE`.
+fn flag_value(args: &[OsString], flag: &str) -> Option<String> {
+    let mut iter = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = iter.next() {
+        if arg == flag {
+            return iter.next().map(|value| value.into_owned());
+        }
+        if let Some(value) = arg.strip_prefix(flag).and_then(|rest| rest.strip_prefix('=')) {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Whether the option named `--long` is on the command line, under that name,
+/// one of its aliases or its short flag.
+fn has_flag(args: &[OsString], long: &str) -> bool {
+    let mut command = Args::command();
+    // Adds `--help` and `--version`.
+    command.build();
+    let option = command
+        .get_arguments()
+        .find(|option| option.get_long() == Some(long));
+    let mut longs = vec![format!("--{}", long)];
+    let mut shorts = Vec::new();
+    if let Some(option) = option {
+        longs.extend(
+            option
+                .get_all_aliases()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|alias| format!("--{}", alias)),
+        );
+        shorts.extend(option.get_short().map(|short| format!("-{}", short)));
+        shorts.extend(
+            option
+                .get_all_short_aliases()
+                .unwrap_or_default()
+                .into_iter()
+                .map(|short| format!("-{}", short)),
+        );
+    }
+    args.iter().skip(1).any(|arg| {
+        let arg = arg.to_string_lossy();
+        longs.iter().any(|flag| {
+            arg == flag.as_str() || arg.strip_prefix(flag.as_str()).is_some_and(|rest| rest.starts_with('='))
+        }) || shorts.iter().any(|flag| arg.starts_with(flag.as_str()))
+    })
+}
+
+/// Rotation period of the visitor hash salt, if any. Without stored IPs the
+/// hash always needs one, daily unless configured otherwise.
+fn uniq_salt_days(configured: u32, no_store_ip: bool) -> Option<u32> {
+    match configured {
+        0 if no_store_ip => Some(1),
+        0 => None,
+        days => Some(days),
+    }
+}
+
+/// File the visitor hash salts are kept in, next to the database; none for
+/// an in-memory one.
+fn uniq_salt_path(db_path: &str) -> Option<PathBuf> {
+    if db_path == store::MEMORY_PATH {
+        return None;
+    }
+    Some(PathBuf::from(format!("{}.salts", db_path.trim_end_matches('/'))))
+}
+
+fn parse_filter(value: &str) -> Result<(String, String), String> {
+    let (key, val) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected COLUMN=VALUE, got {:?}", value))?;
+    if !dashboard::ALLOWED_FILTERS.contains(&key) {
+        return Err(format!(
+            "unknown column {:?}, expected one of {}",
+            key,
+            dashboard::ALLOWED_FILTERS.join(", ")
+        ));
+    }
+    Ok((key.to_string(), val.to_string()))
+}
+
+/// Where one of the `--listen` servers accepts connections.
+#[derive(Debug)]
+enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// Parses `:PORT` (every address, as `[::]:PORT`), `IP:PORT`, `HOST:PORT`
+/// or `unix:PATH`; a host name is resolved and its first address used.
+fn normalize_listen_addr(listen: &str) -> Result<ListenAddr, anyhow::Error> {
+    if let Some(path) = listen.strip_prefix("unix:") {
+        if path.is_empty() {
+            anyhow::bail!("invalid listen address {}: missing socket path", listen);
+        }
+        return Ok(ListenAddr::Unix(PathBuf::from(path)));
+    }
     if listen.starts_with(':') {
-        let normalized = format!("0.0.0.0{}", listen);
+        let normalized = format!("[::]{}", listen);
         return normalized
             .parse()
+            .map(ListenAddr::Tcp)
             .with_context(|| format!("invalid listen address {}", listen));
     }
+    if let Ok(addr) = listen.parse() {
+        return Ok(ListenAddr::Tcp(addr));
+    }
     listen
-        .parse()
-        .with_context(|| format!("invalid listen address {}", listen))
+        .to_socket_addrs()
+        .with_context(|| format!("invalid listen address {}", listen))?
+        .next()
+        .map(ListenAddr::Tcp)
+        .with_context(|| format!("listen address {} resolved to no addresses", listen))
+}
+
+/// Binds `addr`. The unspecified IPv6 address, which on Linux also takes
+/// IPv4 clients, falls back to `0.0.0.0` on hosts without IPv6, such as
+/// containers that have it disabled.
+async fn bind_tcp(addr: SocketAddr) -> Result<tokio::net::TcpListener, anyhow::Error> {
+    let err = match tokio::net::TcpListener::bind(addr).await {
+        Ok(listener) => return Ok(listener),
+        Err(err) => err,
+    };
+    let Some(fallback) = ipv4_fallback(addr, &err) else {
+        return Err(err).with_context(|| format!("failed to listen on {}", addr));
+    };
+    eprintln!(
+        "cannot listen on {} ({}), listening on {} instead",
+        addr, err, fallback
+    );
+    tokio::net::TcpListener::bind(fallback)
+        .await
+        .with_context(|| format!("failed to listen on {}", fallback))
+}
+
+/// Address `bind_tcp` retries on when binding `addr` failed with `err`: the
+/// IPv4 one for the unspecified IPv6 address, unless the port is taken, which
+/// the retry wouldn't fix.
+fn ipv4_fallback(addr: SocketAddr, err: &std::io::Error) -> Option<SocketAddr> {
+    (addr.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) && err.kind() != std::io::ErrorKind::AddrInUse)
+        .then(|| SocketAddr::from((Ipv4Addr::UNSPECIFIED, addr.port())))
+}
+
+/// Binds the Unix socket at `path`, first removing a socket file left behind
+/// by a previous run. Other kinds of files are left alone.
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> Result<tokio::net::UnixListener, anyhow::Error> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(meta) = std::fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            anyhow::bail!("cannot listen on {}: not a socket", path.display());
+        }
+        std::fs::remove_file(path)
+            .with_context(|| format!("failed to remove stale socket {}", path.display()))?;
+    }
+    tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("failed to listen on {}", path.display()))
+}
+
+/// Serves `app` over HTTP/1 on `listener` until shutdown is signalled, then
+/// lets open connections finish and removes the socket file.
+#[cfg(unix)]
+async fn serve_unix(
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+    app: axum::Router,
+    mut shutdown_rx: tokio::sync::watch::Receiver<bool>,
+) -> Result<(), anyhow::Error> {
+    // Handlers read the client address; a socket peer runs on this host, so
+    // it is treated like a proxy on loopback.
+    let app = app.layer(axum::Extension(axum::extract::ConnectInfo(SocketAddr::from((
+        [127, 0, 0, 1],
+        0,
+    )))));
+    let mut connections = tokio::task::JoinSet::new();
+    loop {
+        let socket = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => socket,
+                Err(err) => {
+                    eprintln!("failed to accept on {}: {}", path.display(), err);
+                    continue;
+                }
+            },
+            _ = closed(&mut shutdown_rx) => break,
+        };
+        let service = hyper_util::service::TowerToHyperService::new(app.clone());
+        let mut shutdown_rx = shutdown_rx.clone();
+        connections.spawn(async move {
+            let conn = hyper::server::conn::http1::Builder::new()
+                .serve_connection(hyper_util::rt::TokioIo::new(socket), service)
+                .with_upgrades();
+            tokio::pin!(conn);
+            let result = tokio::select! {
+                result = conn.as_mut() => result,
+                _ = closed(&mut shutdown_rx) => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(err) = result {
+                eprintln!("connection error: {}", err);
+            }
+        });
+    }
+    while connections.join_next().await.is_some() {}
+    let _ = std::fs::remove_file(&path);
+    Ok(())
+}
+
+/// First day not older than `days` days, today being taken in `timezone`,
+/// the zone the rows are dated in.
+fn cutoff_date(days: u32, timezone: chrono_tz::Tz) -> chrono::NaiveDate {
+    Utc::now().with_timezone(&timezone).date_naive() - chrono::Duration::days(i64::from(days))
+}
+
+fn spawn_compactor(store: Arc<store::Store>, after_days: u32, timezone: chrono_tz::Tz) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            let before = cutoff_date(after_days, timezone);
+            match store.compact(before).await {
+                Ok(removed) => println!("compacted {} rows dated before {}", removed, before),
+                Err(err) => eprintln!("compaction failed: {}", err),
+            }
+        }
+    });
+}
+
+fn spawn_pruner(store: Arc<store::Store>, retention_days: u32, timezone: chrono_tz::Tz) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(24 * 60 * 60));
+        loop {
+            interval.tick().await;
+            let before = cutoff_date(retention_days, timezone);
+            match store.prune(before).await {
+                Ok(removed) => println!("pruned {} rows dated before {}", removed, before),
+                Err(err) => eprintln!("pruning failed: {}", err),
+            }
+        }
+    });
 }
 
+fn spawn_checkpointer(store: Arc<store::Store>, interval_secs: u64) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(interval_secs));
+        loop {
+            interval.tick().await;
+            if let Err(err) = store.checkpoint().await {
+                eprintln!("checkpoint failed: {}", err);
+            }
+        }
+    });
+}
+
+/// Completes once shutdown is signalled on `rx`.
+async fn closed(rx: &mut tokio::sync::watch::Receiver<bool>) {
+    let _ = rx.wait_for(|closing| *closing).await;
+}
+
+/// Completes on Ctrl-C, or on SIGTERM (sent by container orchestrators) on Unix.
 async fn shutdown_signal() {
-    let _ = tokio::signal::ctrl_c().await;
+    let ctrl_c = async {
+        let _ = tokio::signal::ctrl_c().await;
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(err) => {
+                eprintln!("failed to listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(listen: &str) -> SocketAddr {
+        match normalize_listen_addr(listen).expect("listen address") {
+            ListenAddr::Tcp(addr) => addr,
+            ListenAddr::Unix(path) => panic!("{} parsed as unix:{}", listen, path.display()),
+        }
+    }
+
+    #[test]
+    fn bare_ports_listen_on_every_address() {
+        assert_eq!(tcp(":7070"), "[::]:7070".parse().unwrap());
+        assert_eq!(tcp("0.0.0.0:7070"), "0.0.0.0:7070".parse().unwrap());
+        assert_eq!(tcp("[::1]:7070"), "[::1]:7070".parse().unwrap());
+        assert!(normalize_listen_addr(":http").is_err());
+    }
+
+    #[test]
+    fn host_names_resolve_to_their_first_address() {
+        let addr = tcp("localhost:7070");
+        assert!(addr.ip().is_loopback(), "{}", addr);
+        assert_eq!(addr.port(), 7070);
+    }
+
+    #[test]
+    fn unresolvable_host_names_are_rejected() {
+        let err = normalize_listen_addr("stats.invalid:7070").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("stats.invalid:7070"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn only_the_unspecified_ipv6_address_falls_back_to_ipv4() {
+        let any: SocketAddr = "[::]:7070".parse().unwrap();
+        let unavailable = std::io::Error::from(std::io::ErrorKind::AddrNotAvailable);
+        assert_eq!(
+            ipv4_fallback(any, &unavailable),
+            Some("0.0.0.0:7070".parse().unwrap())
+        );
+        let in_use = std::io::Error::from(std::io::ErrorKind::AddrInUse);
+        assert_eq!(ipv4_fallback(any, &in_use), None);
+        let loopback: SocketAddr = "[::1]:7070".parse().unwrap();
+        assert_eq!(ipv4_fallback(loopback, &unavailable), None);
+    }
+
+    #[tokio::test]
+    async fn bare_ports_bind_with_or_without_ipv6() {
+        let listener = bind_tcp(tcp(":0")).await.expect("bind");
+        let addr = listener.local_addr().expect("local address");
+        assert!(addr.ip().is_unspecified(), "{}", addr);
+        assert_ne!(addr.port(), 0);
+    }
+
+    /// `args` after the options of a config file holding `toml`.
+    fn with_config(name: &str, toml: &str, args: &[&str]) -> Result<Vec<OsString>, anyhow::Error> {
+        let path = std::env::temp_dir().join(format!(
+            "banan-stats-config-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, toml).unwrap();
+        let mut argv = vec![
+            OsString::from("banan-stats"),
+            OsString::from("--config"),
+            path.clone().into(),
+        ];
+        argv.extend(args.iter().map(OsString::from));
+        let result = args_with_config(argv);
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    const CONFIG: &str = r#"
+        db-path = "/data/stats.duckdb"
+        retention-days = 400
+        require-timestamp = true
+        normalize-path = false
+        trusted-proxies = ["10.0.0.0/8", "::1"]
+    "#;
+
+    #[test]
+    fn config_files_come_before_the_command_line() {
+        let args = Args::try_parse_from(with_config("plain", CONFIG, &[]).unwrap()).unwrap();
+        assert_eq!(args.db_path, "/data/stats.duckdb");
+        assert_eq!(args.retention_days, 400);
+        assert!(args.require_timestamp);
+        assert!(!args.normalize_path);
+        assert_eq!(
+            args.trusted_proxies,
+            vec!["10.0.0.0/8".parse().unwrap(), "::1".parse().unwrap()]
+        );
+
+        let args = Args::try_parse_from(
+            with_config(
+                "overridden",
+                CONFIG,
+                &[
+                    "--retention-days=30",
+                    "--require-timestamp=false",
+                    "--normalize-path",
+                    "--trusted-proxies",
+                    "192.168.0.0/16",
+                ],
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(args.db_path, "/data/stats.duckdb");
+        assert_eq!(args.retention_days, 30);
+        assert!(!args.require_timestamp);
+        assert!(args.normalize_path);
+        assert_eq!(args.trusted_proxies, vec!["192.168.0.0/16".parse().unwrap()]);
+    }
+
+    #[test]
+    fn false_switches_are_passed_on() {
+        let args = with_config("false", "normalize-path = false\n", &[]).unwrap();
+        assert!(args.contains(&OsString::from("--normalize-path=false")));
+    }
+
+    #[test]
+    fn short_flags_override_the_config_file() {
+        let args: Vec<OsString> = ["banan-stats", "-h"].iter().map(OsString::from).collect();
+        assert!(has_flag(&args, "help"));
+        assert!(!has_flag(&args, "db-path"));
+    }
+
+    #[test]
+    fn unsupported_config_values_are_rejected() {
+        for toml in ["since = 2024-01-01\n", "listen = { port = 7070 }\n"] {
+            let err = with_config("unsupported", toml, &[]).unwrap_err();
+            assert!(err.to_string().contains("unsupported value"), "{:#}", err);
+        }
+    }
+
+    #[test]
+    fn args_are_well_formed() {
+        Args::command().debug_assert();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn unix_sockets_replace_stale_files_and_serve_as_loopback() {
+        use axum::extract::ConnectInfo;
+        use std::io::{Read, Write};
+
+        let path =
+            std::env::temp_dir().join(format!("banan-stats-{}.sock", std::process::id()));
+        // Left behind as if by a previous run.
+        drop(std::os::unix::net::UnixListener::bind(&path).expect("stale socket"));
+        let listener = bind_unix(&path).expect("bind over the stale socket");
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get(|ConnectInfo(peer): ConnectInfo<SocketAddr>| async move {
+                peer.ip().to_string()
+            }),
+        );
+        let (shutdown_tx, shutdown_rx) = tokio::sync::watch::channel(false);
+        let server = tokio::spawn(serve_unix(listener, path.clone(), app, shutdown_rx));
+
+        let client_path = path.clone();
+        let response = tokio::task::spawn_blocking(move || {
+            let mut stream = std::os::unix::net::UnixStream::connect(client_path)?;
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok::<_, std::io::Error>(response)
+        })
+        .await
+        .unwrap()
+        .expect("request");
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("127.0.0.1"), "{}", response);
+
+        shutdown_tx.send(true).unwrap();
+        server.await.unwrap().expect("serve");
+        assert!(!path.exists());
+    }
 }
@@ -0,0 +1,201 @@
+use crate::state::AppState;
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use duckdb::Connection;
+use std::time::Duration;
+
+/// Rows returned at most; `truncated` tells when more were left out.
+const MAX_ROWS: usize = 10_000;
+
+/// How long a query may run before it is interrupted.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Runs the single `SELECT` or `WITH` statement sent as the body and answers
+/// its rows as JSON, every value as a string. It runs in a read-only
+/// transaction, like the dashboard's own queries. Only served when a dashboard
+/// token is configured, since the statement can read anything DuckDB can.
+pub async fn query_handler(State(state): State<AppState>, body: String) -> Response {
+    answer(&state, &body, TIMEOUT).await
+}
+
+/// `query_handler`, interrupting the query after `timeout`.
+async fn answer(state: &AppState, body: &str, timeout: Duration) -> Response {
+    if state.dashboard.token.is_none() {
+        return (
+            StatusCode::FORBIDDEN,
+            "the query endpoint requires --dashboard-token",
+        )
+            .into_response();
+    }
+    let sql = match read_only_sql(body) {
+        Ok(sql) => sql.to_string(),
+        Err(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+    };
+    let result = state
+        .store
+        .with_conn_timeout(timeout, move |conn| run_query(conn, &sql))
+        .await;
+    match result {
+        Ok(Some(body)) => {
+            let mut headers = HeaderMap::new();
+            headers.insert("Content-Type", "application/json".parse().expect("header"));
+            (headers, body.to_string()).into_response()
+        }
+        Ok(None) => (StatusCode::REQUEST_TIMEOUT, "query timed out").into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    }
+}
+
+/// `sql` without its trailing `;`, if it is a single statement starting with
+/// `SELECT` or `WITH`. Any other `;` is refused, even inside a string.
+fn read_only_sql(sql: &str) -> Result<&str, &'static str> {
+    let sql = sql.trim();
+    let sql = sql.strip_suffix(';').unwrap_or(sql).trim_end();
+    if sql.contains(';') {
+        return Err("only a single statement is allowed");
+    }
+    let keyword: String = sql
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    if !keyword.eq_ignore_ascii_case("select") && !keyword.eq_ignore_ascii_case("with") {
+        return Err("only SELECT and WITH statements are allowed");
+    }
+    Ok(sql)
+}
+
+fn run_query(conn: &Connection, sql: &str) -> Result<serde_json::Value, anyhow::Error> {
+    // Nested as a subquery, anything but a query fails to parse. The
+    // newlines keep a trailing `--` comment from swallowing the wrapper.
+    let columns = {
+        let mut stmt = conn.prepare(&format!("SELECT * FROM (\n{}\n) LIMIT 0", sql))?;
+        stmt.query([])?;
+        stmt.column_names()
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT COLUMNS(*)::VARCHAR FROM (\n{}\n) LIMIT {}",
+        sql,
+        MAX_ROWS + 1
+    ))?;
+    let mut rows = stmt.query([])?;
+    let mut values = Vec::new();
+    while let Some(row) = rows.next()? {
+        values.push(
+            (0..columns.len())
+                .map(|idx| row.get::<_, Option<String>>(idx))
+                .collect::<Result<Vec<_>, _>>()?,
+        );
+    }
+    let truncated = values.len() > MAX_ROWS;
+    values.truncate(MAX_ROWS);
+    Ok(serde_json::json!({
+        "columns": columns,
+        "rows": values,
+        "truncated": truncated,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::tests::memory_store;
+    use http_body_util::BodyExt;
+    use std::sync::Arc;
+
+    fn state(token: Option<&str>) -> AppState {
+        AppState {
+            store: Arc::new(memory_store()),
+            dashboard: Arc::new(crate::dashboard::Config {
+                token: token.map(str::to_string),
+                ..Default::default()
+            }),
+            pages: Default::default(),
+            ingest: Default::default(),
+        }
+    }
+
+    async fn json(response: Response) -> serde_json::Value {
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        serde_json::from_slice(&body).expect("json")
+    }
+
+    #[test]
+    fn only_single_queries_are_accepted() {
+        for sql in [
+            "SELECT 1",
+            " select 1; ",
+            "WITH t AS (SELECT 1 AS x) SELECT x FROM t",
+            "SELECT 1 -- trailing comment",
+        ] {
+            assert!(read_only_sql(sql).is_ok(), "{}", sql);
+        }
+        assert_eq!(read_only_sql("SELECT 1;"), Ok("SELECT 1"));
+        for sql in [
+            "SELECT 1; SELECT 2",
+            "SELECT 1; DROP TABLE stats",
+            "SELECT ';'",
+            "SELECT 1;;",
+            "DELETE FROM stats",
+            "INSERT INTO stats SELECT * FROM stats",
+            "COPY stats TO 'out.csv'",
+            "ATTACH 'other.duckdb'",
+            "PRAGMA database_list",
+            "-- comment\nSELECT 1",
+            "/* comment */ SELECT 1",
+            "(SELECT 1)",
+            "",
+        ] {
+            assert!(read_only_sql(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[tokio::test]
+    async fn writes_hidden_in_with_fail() {
+        let state = state(Some("secret"));
+        let response = answer(
+            &state,
+            "WITH t AS (SELECT 1) INSERT INTO stats (path) SELECT 'x' FROM t",
+            TIMEOUT,
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(crate::store::tests::row_count(&state.store).await, 0);
+    }
+
+    #[tokio::test]
+    async fn queries_need_a_dashboard_token() {
+        let response = answer(&state(None), "SELECT 1", TIMEOUT).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn rows_are_capped() {
+        let state = state(Some("secret"));
+        let body = json(answer(&state, "SELECT 42 AS answer", TIMEOUT).await).await;
+        assert_eq!(
+            body,
+            serde_json::json!({"columns": ["answer"], "rows": [["42"]], "truncated": false})
+        );
+
+        let sql = format!("SELECT * FROM range({})", MAX_ROWS + 1);
+        let body = json(answer(&state, &sql, TIMEOUT).await).await;
+        assert_eq!(body["rows"].as_array().unwrap().len(), MAX_ROWS);
+        assert_eq!(body["truncated"], true);
+    }
+
+    #[tokio::test]
+    async fn slow_queries_time_out() {
+        let state = state(Some("secret"));
+        let response = answer(
+            &state,
+            "SELECT COUNT(*) FROM range(100000000) a, range(100000000) b",
+            Duration::from_millis(200),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::REQUEST_TIMEOUT);
+    }
+}
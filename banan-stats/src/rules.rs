@@ -0,0 +1,190 @@
+use anyhow::Context;
+use regex::{RegexSet, RegexSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Classification rules, loadable from a JSON file. The entries of a file
+/// are added to the built-in ones, so a file only needs what's missing.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rules {
+    /// Agent names always classified as browsers, e.g. `Firefox`.
+    pub browsers: Vec<String>,
+    /// Case-insensitive regexes on the user agent marking feed readers.
+    pub feeds: Vec<String>,
+    /// Case-insensitive regexes on the user agent marking bots. They are
+    /// regexes, not substrings, so a literal `+` or `.` must be escaped.
+    pub bots: Vec<String>,
+    /// Agent tokens recorded under another name, e.g. `OPR` as `Opera`.
+    pub agent_aliases: BTreeMap<String, String>,
+}
+
+impl Rules {
+    pub fn builtin() -> Self {
+        let strings = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        Self {
+            browsers: strings(&[
+                "Chrome",
+                "Firefox",
+                "Edg",
+                "EdgA",
+                "EdgiOS",
+                "Safari",
+                "OPR",
+                "YaBrowser",
+                "Vivaldi",
+                "SamsungBrowser",
+                "UCBrowser",
+            ]),
+            feeds: strings(&["rss"]),
+            bots: strings(&[
+                "bot", "crawl", "fetch", "node", "ruby", ".rb", "python", "curl", "okhttp",
+                "spider", "scan", "nutch", "mastodon", r"\+http",
+            ]),
+            agent_aliases: [
+                ("Edg", "Edge"),
+                ("EdgA", "Edge"),
+                ("EdgiOS", "Edge"),
+                ("OPR", "Opera"),
+            ]
+            .into_iter()
+            .map(|(token, name)| (token.to_string(), name.to_string()))
+            .collect(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Self, anyhow::Error> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read rules file {}", path))?;
+        serde_json::from_str(&text).with_context(|| format!("invalid rules file {}", path))
+    }
+
+    /// Adds the entries of `other` that aren't already present. Its agent
+    /// aliases replace existing ones for the same token.
+    pub fn merge(&mut self, other: Rules) {
+        for (list, extra) in [
+            (&mut self.browsers, other.browsers),
+            (&mut self.feeds, other.feeds),
+            (&mut self.bots, other.bots),
+        ] {
+            for item in extra {
+                if !list.contains(&item) {
+                    list.push(item);
+                }
+            }
+        }
+        self.agent_aliases.extend(other.agent_aliases);
+    }
+
+    pub fn compile(self) -> Result<RuleSet, anyhow::Error> {
+        let build = |patterns: &[String], name: &str| {
+            RegexSetBuilder::new(patterns)
+                .case_insensitive(true)
+                .build()
+                .with_context(|| format!("invalid {} pattern", name))
+        };
+        Ok(RuleSet {
+            feeds: build(&self.feeds, "feed")?,
+            bots: build(&self.bots, "bot")?,
+            rules: self,
+        })
+    }
+}
+
+/// Compiled `Rules`, as used by the analyzer.
+#[derive(Clone, Debug)]
+pub struct RuleSet {
+    rules: Rules,
+    feeds: RegexSet,
+    bots: RegexSet,
+}
+
+impl RuleSet {
+    pub fn rules(&self) -> &Rules {
+        &self.rules
+    }
+
+    /// Whether `agent`, or the name it is aliased to, is a browser.
+    pub fn is_browser(&self, agent: &str) -> bool {
+        let alias = self.agent_alias(agent);
+        self.rules
+            .browsers
+            .iter()
+            .any(|name| name == agent || Some(name.as_str()) == alias)
+    }
+
+    /// Name `agent` is recorded under, when it has an alias.
+    pub fn agent_alias(&self, agent: &str) -> Option<&str> {
+        self.rules.agent_aliases.get(agent).map(String::as_str)
+    }
+
+    pub fn is_feed(&self, user_agent: &str) -> bool {
+        self.feeds.is_match(user_agent)
+    }
+
+    pub fn is_bot(&self, user_agent: &str) -> bool {
+        self.bots.is_match(user_agent)
+    }
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Rules::builtin().compile().expect("built-in rules")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MONITOR: &str = "Mozilla/5.0 (compatible; AcmeMonitor/2.1)";
+
+    fn custom() -> Rules {
+        Rules {
+            bots: vec!["AcmeMonitor".to_string()],
+            agent_aliases: [("Ladybird".to_string(), "Ladybird Browser".to_string())].into(),
+            ..Rules::default()
+        }
+    }
+
+    #[test]
+    fn dumped_rules_load_back_unchanged() {
+        let mut rules = Rules::builtin();
+        rules.merge(custom());
+        let path =
+            std::env::temp_dir().join(format!("banan-stats-rules-{}.json", std::process::id()));
+        std::fs::write(&path, serde_json::to_string_pretty(&rules).expect("dump")).expect("write");
+        let loaded = Rules::load(path.to_str().expect("utf-8 path"));
+        let _ = std::fs::remove_file(&path);
+        let loaded = loaded.expect("load");
+        assert_eq!(loaded, rules);
+
+        // Loading a dump on top of the built-in rules adds nothing twice.
+        let mut merged = Rules::builtin();
+        merged.merge(loaded);
+        assert_eq!(merged, rules);
+    }
+
+    #[test]
+    fn custom_bot_pattern_takes_effect() {
+        assert!(!RuleSet::default().is_bot(MONITOR));
+        let mut rules = Rules::builtin();
+        rules.merge(custom());
+        let rules = rules.compile().expect("compile");
+        assert!(rules.is_bot(MONITOR));
+        assert!(rules.is_bot("acmemonitor"));
+        assert!(
+            !rules.is_bot("Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0")
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_invalid_patterns() {
+        assert!(serde_json::from_str::<Rules>(r#"{"bot": ["typo"]}"#).is_err());
+        let rules = Rules {
+            bots: vec!["(unclosed".to_string()],
+            ..Rules::default()
+        };
+        assert!(rules.compile().is_err());
+    }
+}
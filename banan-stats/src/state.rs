@@ -1,7 +1,12 @@
+use crate::dashboard;
+use crate::ingest;
 use crate::store::Store;
 use std::sync::Arc;
 
 #[derive(Clone)]
 pub struct AppState {
     pub store: Arc<Store>,
+    pub dashboard: Arc<dashboard::Config>,
+    pub pages: Arc<dashboard::PageCache>,
+    pub ingest: Arc<ingest::Config>,
 }
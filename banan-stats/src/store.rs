@@ -1,117 +1,1199 @@
 use crate::analyzer::{self, Line};
 use anyhow::Context;
-use duckdb::{params, Connection};
+use chrono::{NaiveDate, Utc};
+use duckdb::{params, params_from_iter, Connection};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use tokio::sync::{RwLock, broadcast};
+
+/// Path opening a database that only lives in memory, e.g. for demos.
+pub const MEMORY_PATH: &str = ":memory:";
+
+/// Number of inserts buffered per live subscriber before it is dropped.
+const EVENTS_CAPACITY: usize = 1024;
+
+/// Daily unique visitors per type, across hosts under a NULL `host` and per
+/// host, computed exactly like the dashboard's daily timeline, unfiltered or
+/// filtered on `host`, so `stats_daily` can stand in for it.
+const ROLLUP_SELECT: &str = "SELECT date, NULL, type, SUM(mult)
+     FROM (
+         SELECT date, type, MAX(mult) AS mult
+         FROM stats
+         GROUP BY date, type, uniq
+     )
+     GROUP BY date, type
+     HAVING SUM(mult) <> 0
+     UNION ALL
+     SELECT date, host, type, SUM(mult)
+     FROM (
+         SELECT date, host, type, MAX(mult) AS mult
+         FROM stats
+         WHERE host IS NOT NULL
+         GROUP BY date, host, type, uniq
+     )
+     GROUP BY date, host, type
+     HAVING SUM(mult) <> 0";
+
+/// Visitors looked up per query by `rollup_counts`, two parameters each.
+const VISITORS_PER_QUERY: usize = 1000;
+
+/// How the rows of `stats` are laid out on disk.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Shard {
+    /// Every row in the one `--db-path` file.
+    #[default]
+    Single,
+    /// One `stats-YYYY-MM.duckdb` file per month of the event date, in the
+    /// `--db-path` directory, read through a `stats` view over all of them.
+    Monthly,
+}
+
+/// Rollup table of a sharded store, kept in memory next to the `stats` view
+/// and rebuilt at startup.
+const SHARDED_ROLLUP_TABLE: &str = "CREATE TABLE IF NOT EXISTS stats_daily (
+         date       DATE,
+         type       VARCHAR,
+         visitors   BIGINT,
+         host       VARCHAR
+     )";
 
 pub struct Store {
     conn: Arc<Mutex<Connection>>,
+    /// Directory of the monthly shards, when sharded.
+    shard_dir: Option<PathBuf>,
+    /// Extra connections to the same database serving `with_conn`, so reads
+    /// neither wait on writes nor on each other. They can't write; see
+    /// `with_conn`.
+    readers: Arc<Vec<Mutex<Connection>>>,
+    next_reader: AtomicUsize,
+    analyzer: Arc<analyzer::Config>,
+    /// Lines of each insert, sent once per insert so that a large one
+    /// doesn't overrun the channel on its own.
+    events: broadcast::Sender<Arc<[Line]>>,
+    /// Set once shutdown starts; see `close`.
+    closing: AtomicBool,
+    /// Held shared by every running insert, so `drain` can wait them out.
+    inserts: Arc<RwLock<()>>,
+    /// When the store was opened, in microseconds, and how many writes ran
+    /// since; see `version`.
+    opened: i64,
+    writes: AtomicU64,
 }
 
 impl Store {
-    pub fn open(path: &str) -> Result<Self, anyhow::Error> {
-        let conn = Connection::open(path).with_context(|| format!("open db {}", path))?;
-        for stmt in [
-            "CREATE TYPE agent_type_t AS ENUM ('feed', 'bot', 'browser')",
-            "CREATE TYPE agent_os_t AS ENUM ('Android', 'Windows', 'iOS', 'macOS', 'Linux')",
-        ] {
-            if let Err(err) = conn.execute(stmt, []) {
-                if !is_existing_type_error(&err) {
-                    return Err(err.into());
+    pub fn open(
+        path: &str,
+        analyzer: analyzer::Config,
+        readers: usize,
+        shard: Shard,
+    ) -> Result<Self, anyhow::Error> {
+        if shard == Shard::Monthly && path == MEMORY_PATH {
+            anyhow::bail!("monthly shards need a directory, not {}", MEMORY_PATH);
+        }
+        // `:memory:` keeps the database in memory for as long as the store
+        // lives; the readers are clones, so they share it. Shards are
+        // attached to an in-memory database holding the view and rollup.
+        let mut conn = if path == MEMORY_PATH || shard == Shard::Monthly {
+            Connection::open_in_memory()
+        } else {
+            Connection::open(path)
+        }
+        .with_context(|| format!("open db {}", path))?;
+        create_types(&conn)?;
+        let shard_dir = match shard {
+            Shard::Single => {
+                migrate(&mut conn).with_context(|| format!("migrate {}", path))?;
+                verify_schema(&conn)
+                    .with_context(|| format!("incompatible stats table in {}", path))?;
+                None
+            }
+            Shard::Monthly => {
+                let dir = PathBuf::from(path);
+                std::fs::create_dir_all(&dir)
+                    .with_context(|| format!("create shard directory {}", path))?;
+                let mut months = BTreeSet::new();
+                for entry in std::fs::read_dir(&dir)
+                    .with_context(|| format!("read shard directory {}", path))?
+                {
+                    if let Some(month) = entry?.file_name().to_str().and_then(shard_month) {
+                        months.insert(month);
+                    }
                 }
+                // The current month always has a shard, so the view never
+                // unions nothing.
+                months.insert(Utc::now().format("%Y-%m").to_string());
+                for month in &months {
+                    attach_shard(&mut conn, &dir, month)?;
+                }
+                refresh_view(&conn)?;
+                conn.execute_batch(SHARDED_ROLLUP_TABLE)?;
+                Some(dir)
             }
+        };
+
+        // A missing rollup (new table, or a database from before it existed)
+        // is built from scratch; from then on writes keep it current.
+        let rollup_rows: i64 =
+            conn.query_row("SELECT COUNT(*) FROM stats_daily", [], |row| row.get(0))?;
+        if rollup_rows == 0 {
+            let tx = conn.transaction()?;
+            rebuild_rollup(&tx)?;
+            tx.commit()?;
         }
 
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS stats (
-                 event_id   UUID,
-                 date       DATE,
-                 time       TIME,
-                 host       VARCHAR,
-                 path       VARCHAR,
-                 query      VARCHAR,
-                 ip         VARCHAR,
-                 user_agent VARCHAR,
-                 referrer   VARCHAR,
-                 type       agent_type_t,
-                 agent      VARCHAR,
-                 os         agent_os_t,
-                 ref_domain VARCHAR,
-                 mult       INTEGER,
-                 set_cookie UUID,
-                 uniq       UUID
-             );
-             ALTER TABLE stats ADD COLUMN IF NOT EXISTS event_id UUID;
-             ALTER TABLE stats ADD COLUMN IF NOT EXISTS host VARCHAR;
-             CREATE INDEX IF NOT EXISTS idx_stats_host_date ON stats(host, date);
-             CREATE UNIQUE INDEX IF NOT EXISTS idx_stats_event_id ON stats(event_id);",
-        )?;
+        let readers = (0..readers.max(1))
+            .map(|_| conn.try_clone().map(Mutex::new))
+            .collect::<Result<Vec<_>, _>>()?;
 
+        let (events, _) = broadcast::channel(EVENTS_CAPACITY);
         Ok(Self {
             conn: Arc::new(Mutex::new(conn)),
+            shard_dir,
+            readers: Arc::new(readers),
+            next_reader: AtomicUsize::new(0),
+            analyzer: Arc::new(analyzer),
+            events,
+            closing: AtomicBool::new(false),
+            inserts: Arc::new(RwLock::new(())),
+            opened: Utc::now().timestamp_micros(),
+            writes: AtomicU64::new(0),
         })
     }
 
-    pub async fn insert(&self, lines: Vec<Line>) -> Result<(), anyhow::Error> {
+    /// Changes whenever the stored rows may have, without a query: every
+    /// write method bumps it, whether or not it succeeded. It includes when
+    /// the store was opened, so versions of two runs never collide.
+    pub fn version(&self) -> String {
+        format!("{}-{}", self.opened, self.writes.load(Ordering::SeqCst))
+    }
+
+    fn bump_version(&self) {
+        self.writes.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Marks the store as shutting down, so callers can turn away new work.
+    /// Inserts already running, or started by requests already in flight,
+    /// still go through.
+    pub fn close(&self) {
+        self.closing.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::SeqCst)
+    }
+
+    /// Waits until every running insert has committed or failed.
+    pub async fn drain(&self) {
+        let _ = self.inserts.write().await;
+    }
+
+    /// Subscribes to lines as they are committed, after analysis, receiving
+    /// those of each insert together.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<[Line]>> {
+        self.events.subscribe()
+    }
+
+    /// Analyzes and stores `lines` in one transaction, returning how many
+    /// were new; events whose `event_id` is already stored are skipped.
+    pub async fn insert(&self, mut lines: Vec<Line>) -> Result<usize, anyhow::Error> {
         let conn = self.conn.clone();
-        tokio::task::spawn_blocking(move || -> Result<(), anyhow::Error> {
+        let analyzer = self.analyzer.clone();
+        let shard_dir = self.shard_dir.clone();
+        // Moved into the blocking task, which runs to the end even if this
+        // future is dropped.
+        let guard = self.inserts.clone().read_owned().await;
+        let inserted = tokio::task::spawn_blocking(move || -> Result<Vec<Line>, anyhow::Error> {
+            let _guard = guard;
             let mut conn = conn.lock().expect("db lock");
+            let clamped = lines
+                .iter_mut()
+                .map(|line| analyzer::analyze(line, &analyzer))
+                .filter(|&clamped| clamped)
+                .count();
+            log_clamped_subscribers(clamped, &analyzer);
+            // The rollup moves by what these visitors count for before and
+            // after the insert, rather than being recomputed for whole days.
+            let visitors = touched_visitors(&conn, &lines)?;
+            let before = rollup_counts(&conn, &visitors)?;
+            let mut inserted = Vec::with_capacity(lines.len());
+            let mut second_visits = BTreeSet::new();
+
+            let Some(dir) = shard_dir else {
+                let tx = conn.transaction()?;
+                insert_rows(&tx, "stats", lines, &mut inserted, &mut second_visits)?;
+                relink_second_visits(&tx, "stats", &second_visits)?;
+                update_rollup(&tx, &visitors, &before)?;
+                tx.commit()?;
+                return Ok(inserted);
+            };
+
+            // A DuckDB transaction writes to a single database, so each
+            // shard commits on its own, and the rollup last, with what the
+            // shards committed even when one of them failed.
+            let written = insert_shards(&mut conn, &dir, lines, &mut inserted, &mut second_visits);
             let tx = conn.transaction()?;
+            update_rollup(&tx, &visitors, &before)?;
+            tx.commit()?;
+            written?;
+            Ok(inserted)
+        })
+        .await;
+        // Shards commit one at a time, so even a failed insert may have
+        // stored rows.
+        self.bump_version();
+        let inserted = inserted??;
 
-            let mut stmt = tx.prepare(
-                "INSERT INTO stats
-                 (event_id, date, time, host, path, query, ip, user_agent, referrer, type, agent, os, ref_domain, mult, set_cookie, uniq)
-                 VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-                 ON CONFLICT(event_id) DO NOTHING",
-            )?;
-            let mut upd_stmt = tx.prepare("UPDATE stats SET uniq = ? WHERE set_cookie = ?")?;
-
-            for mut line in lines {
-                analyzer::analyze(&mut line);
-                stmt.execute(params![
-                    null_str(&line.event_id),
-                    null_str(&line.date),
-                    null_str(&line.time),
-                    null_str(&line.host),
-                    null_str(&line.path),
-                    null_str(&line.query),
-                    null_str(&line.ip),
-                    null_str(&line.user_agent),
-                    null_str(&line.referrer),
-                    null_str(&line.r#type),
-                    null_str(&line.agent),
-                    null_str(&line.os),
-                    null_str(&line.ref_domain),
-                    line.mult,
-                    null_str(&line.set_cookie),
-                    null_str(&line.uniq),
-                ])?;
-
-                if line.second_visit && !line.uniq.is_empty() {
-                    upd_stmt.execute(params![line.uniq, line.uniq])?;
-                }
+        let count = inserted.len();
+        if self.events.receiver_count() > 0 && !inserted.is_empty() {
+            let _ = self.events.send(inserted.into());
+        }
+        Ok(count)
+    }
+
+    /// Merges rows dated before `before` that only differ in per-hit details
+    /// (time, ip, raw user agent, referrer) into a single row carrying the
+    /// summed `hits` and the earliest time, so entry pages stay put. Every
+    /// column the dashboard groups or filters on is part of the merge key, so
+    /// aggregates are unchanged, and so is `set_cookie`, which a later second
+    /// visit relinks by. Rows carrying a metric value are left alone so
+    /// metric sums stay exact. Returns the number of rows removed.
+    pub async fn compact(&self, before: NaiveDate) -> Result<usize, anyhow::Error> {
+        let conn = self.conn.clone();
+        let sharded = self.shard_dir.is_some();
+        let removed = tokio::task::spawn_blocking(move || -> Result<usize, anyhow::Error> {
+            let mut conn = conn.lock().expect("db lock");
+            write_tables(
+                &mut conn,
+                sharded,
+                |tx, table| {
+                    tx.execute(
+                        &format!(
+                            "UPDATE {table} SET hits = groups.hits, time = groups.time
+                             FROM (
+                                 SELECT MIN(rowid) AS keep, SUM(COALESCE(hits, 1)) AS hits,
+                                        MIN(time) AS time
+                                 FROM {table}
+                                 WHERE date < ? AND metric_value IS NULL
+                                 GROUP BY {key}
+                                 HAVING COUNT(*) > 1
+                             ) AS groups
+                             WHERE stats.rowid = groups.keep",
+                            table = table,
+                            key = COMPACT_KEY
+                        ),
+                        params![before],
+                    )?;
+                    Ok(tx.execute(
+                        &format!(
+                            "DELETE FROM {table}
+                             WHERE date < ? AND metric_value IS NULL
+                               AND rowid NOT IN (
+                                   SELECT MIN(rowid) FROM {table}
+                                   WHERE date < ? AND metric_value IS NULL
+                                   GROUP BY {key}
+                               )",
+                            table = table,
+                            key = COMPACT_KEY
+                        ),
+                        params![before, before],
+                    )?)
+                },
+                |_| Ok(()),
+            )
+        })
+        .await;
+        self.bump_version();
+        removed?
+    }
+
+    /// Deletes every row dated before `before`. Returns the number of rows
+    /// removed.
+    pub async fn prune(&self, before: NaiveDate) -> Result<usize, anyhow::Error> {
+        let conn = self.conn.clone();
+        let sharded = self.shard_dir.is_some();
+        let removed = tokio::task::spawn_blocking(move || -> Result<usize, anyhow::Error> {
+            let mut conn = conn.lock().expect("db lock");
+            write_tables(
+                &mut conn,
+                sharded,
+                |tx, table| {
+                    Ok(tx.execute(
+                        &format!("DELETE FROM {} WHERE date < ?", table),
+                        params![before],
+                    )?)
+                },
+                |tx| {
+                    tx.execute("DELETE FROM stats_daily WHERE date < ?", params![before])?;
+                    Ok(())
+                },
+            )
+        })
+        .await;
+        self.bump_version();
+        removed?
+    }
+
+    /// Moves the write-ahead log into the database file. It takes the write
+    /// connection like `insert`, so it runs between transactions, never
+    /// inside one. Shards are checkpointed each in turn.
+    pub async fn checkpoint(&self) -> Result<(), anyhow::Error> {
+        let conn = self.conn.clone();
+        let sharded = self.shard_dir.is_some();
+        tokio::task::spawn_blocking(move || -> Result<(), anyhow::Error> {
+            let conn = conn.lock().expect("db lock");
+            if !sharded {
+                conn.execute_batch("CHECKPOINT")?;
+                return Ok(());
             }
+            for database in shard_databases(&conn)? {
+                conn.execute_batch(&format!("CHECKPOINT {}", database))?;
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Copies the database into a new DuckDB file at `dest`, as of a single
+    /// snapshot, and returns its size in bytes. It runs on a read
+    /// connection, outside of `with_conn`'s read-only transaction since it
+    /// writes the copy, so inserts and other reads carry on meanwhile.
+    pub async fn backup(&self, dest: String) -> Result<u64, anyhow::Error> {
+        if self.shard_dir.is_some() {
+            anyhow::bail!("backups copy a single file; copy the monthly shard files instead");
+        }
+        if Path::new(&dest).exists() {
+            anyhow::bail!("{} already exists", dest);
+        }
+        // Backups may run side by side on different readers, and attached
+        // databases are shared by all of them.
+        static BACKUPS: AtomicUsize = AtomicUsize::new(0);
+        let alias = format!("banan_backup_{}", BACKUPS.fetch_add(1, Ordering::Relaxed));
+        self.with_reader(move |conn| {
+            let source: String =
+                conn.query_row("SELECT current_database()", [], |row| row.get(0))?;
+            conn.execute_batch(&format!(
+                "ATTACH '{}' AS {}",
+                dest.replace('\'', "''"),
+                alias
+            ))?;
+            let copied = conn
+                .execute_batch(&format!(
+                    "COPY FROM DATABASE \"{}\" TO {}",
+                    source.replace('"', "\"\""),
+                    alias
+                ))
+                .with_context(|| format!("copy database to {}", dest));
+            let detached = conn.execute_batch(&format!("DETACH {}", alias));
+            let size = copied
+                .and_then(|()| Ok(detached?))
+                .and_then(|()| Ok(std::fs::metadata(&dest)?.len()));
+            if size.is_err() {
+                // A partial copy would pass for a backup.
+                let _ = std::fs::remove_file(&dest);
+                let _ = std::fs::remove_file(format!("{}.wal", dest));
+            }
+            size
+        })
+        .await
+    }
+
+    /// Re-runs the analyzer over the rows matching `where_clause` and rewrites
+    /// their derived columns (type, agent, agent_version, os, device,
+    /// ref_domain, ref_channel, utm_source, utm_campaign, mult, and country
+    /// when a GeoIP database is configured). `uniq` is kept since it may come
+    /// from a cookie, and so is a `feed` type since it may come from the
+    /// response content type, which isn't stored, and any type other than
+    /// `browser` and `bot` since it came from the shipper; `browser` rows are analyzed
+    /// as HTML responses, which they were if `--html-as-browser` typed
+    /// them. Returns the number of rows updated. Rows whose raw user agent
+    /// wasn't stored are skipped, since their derived columns can't be
+    /// recomputed.
+    pub async fn reanalyze(
+        &self,
+        where_clause: String,
+        args: Vec<String>,
+    ) -> Result<usize, anyhow::Error> {
+        let conn = self.conn.clone();
+        let analyzer = self.analyzer.clone();
+        let sharded = self.shard_dir.is_some();
+        let updated = tokio::task::spawn_blocking(move || -> Result<usize, anyhow::Error> {
+            let mut conn = conn.lock().expect("db lock");
+            let mut clamped = 0;
+            let updated = write_tables(
+                &mut conn,
+                sharded,
+                |tx, table| {
+                    reanalyze_rows(tx, table, &where_clause, &args, &analyzer, &mut clamped)
+                },
+                // Types and multipliers may have changed on any day of the range.
+                rebuild_rollup,
+            );
+            log_clamped_subscribers(clamped, &analyzer);
+            updated
+        })
+        .await;
+        self.bump_version();
+        updated?
+    }
 
+    /// Recomputes `stats_daily` from every row of `stats`.
+    pub async fn rebuild_rollup(&self) -> Result<(), anyhow::Error> {
+        let conn = self.conn.clone();
+        let rebuilt = tokio::task::spawn_blocking(move || -> Result<(), anyhow::Error> {
+            let mut conn = conn.lock().expect("db lock");
+            let tx = conn.transaction()?;
+            rebuild_rollup(&tx)?;
             tx.commit()?;
             Ok(())
         })
-        .await??;
-        Ok(())
+        .await;
+        self.bump_version();
+        rebuilt?
     }
 
+    /// Runs a read query on one of the reader connections, taken in turn.
+    /// Writes go through the other methods, on the writer connection.
+    ///
+    /// `func` runs in a read-only transaction, rolled back afterwards, so any
+    /// write it attempts fails. The readers are clones of the writer, since
+    /// DuckDB won't open the database a second time with
+    /// `access_mode=read_only` in the process that writes to it.
     pub async fn with_conn<T, F>(&self, func: F) -> Result<T, anyhow::Error>
     where
         T: Send + 'static,
         F: FnOnce(&Connection) -> Result<T, anyhow::Error> + Send + 'static,
     {
-        let conn = self.conn.clone();
+        self.with_reader(move |conn| read_only(conn, func)).await
+    }
+
+    /// `with_conn` for queries that may run long, e.g. ones sent by users:
+    /// once `func` has run for `timeout`, its running statement is
+    /// interrupted, freeing the reader, and `None` is returned. Time spent
+    /// waiting for the reader doesn't count.
+    pub async fn with_conn_timeout<T, F>(
+        &self,
+        timeout: std::time::Duration,
+        func: F,
+    ) -> Result<Option<T>, anyhow::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, anyhow::Error> + Send + 'static,
+    {
+        let readers = self.readers.clone();
+        let idx = self.next_reader.fetch_add(1, Ordering::Relaxed) % readers.len();
+        // Sent as `func` starts, and only used while it runs, so the interrupt
+        // can't hit `BEGIN`, the rollback or another request's query.
+        let (handle_tx, handle_rx) = tokio::sync::oneshot::channel();
+        let running = Arc::new(Mutex::new(true));
+        let still_running = running.clone();
+        let mut task = tokio::task::spawn_blocking(move || {
+            let conn = readers[idx].lock().expect("db lock");
+            read_only(&conn, |conn| {
+                let _ = handle_tx.send(conn.interrupt_handle());
+                let result = func(conn);
+                *still_running.lock().expect("running lock") = false;
+                result
+            })
+        });
+        // The clock starts with `func`, not while waiting for the reader.
+        let Ok(handle) = handle_rx.await else {
+            // `func` never ran, as the transaction failed to start.
+            return task.await?.map(Some);
+        };
+        match tokio::time::timeout(timeout, &mut task).await {
+            Ok(result) => result?.map(Some),
+            Err(_) => {
+                let interrupted = {
+                    let running = running.lock().expect("running lock");
+                    if *running {
+                        handle.interrupt();
+                    }
+                    *running
+                };
+                // Interrupted, the statement fails; its error is moot.
+                let result = task.await?;
+                if interrupted {
+                    Ok(None)
+                } else {
+                    result.map(Some)
+                }
+            }
+        }
+    }
+
+    /// Runs `func` on one of the reader connections, taken in turn.
+    async fn with_reader<T, F>(&self, func: F) -> Result<T, anyhow::Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Connection) -> Result<T, anyhow::Error> + Send + 'static,
+    {
+        let readers = self.readers.clone();
+        let idx = self.next_reader.fetch_add(1, Ordering::Relaxed) % readers.len();
         tokio::task::spawn_blocking(move || {
-            let conn = conn.lock().expect("db lock");
+            let conn = readers[idx].lock().expect("db lock");
             func(&conn)
         })
         .await?
     }
 }
 
+/// Runs `func` in a read-only transaction, rolled back afterwards.
+fn read_only<T>(
+    conn: &Connection,
+    func: impl FnOnce(&Connection) -> Result<T, anyhow::Error>,
+) -> Result<T, anyhow::Error> {
+    conn.execute_batch("BEGIN TRANSACTION READ ONLY")?;
+    let result = func(conn);
+    let rollback = conn.execute_batch("ROLLBACK");
+    let value = result?;
+    rollback?;
+    Ok(value)
+}
+
+/// Schema changes in order: a database at version N has had the first N
+/// applied, as recorded in `schema_version`. Version 1 is idempotent so
+/// databases from before versioning, whatever columns they had, reach it
+/// without losing rows. Append new steps; never edit one that shipped.
+const MIGRATIONS: &[&str] = &[
+    // 1: the schema as of the introduction of versioning.
+    "CREATE TABLE IF NOT EXISTS stats (
+         event_id   UUID,
+         date       DATE,
+         time       TIME,
+         host       VARCHAR,
+         path       VARCHAR,
+         query      VARCHAR,
+         ip         VARCHAR,
+         user_agent VARCHAR,
+         referrer   VARCHAR,
+         type       agent_type_t,
+         agent      VARCHAR,
+         agent_version VARCHAR,
+         os         agent_os_t,
+         device     VARCHAR,
+         ref_domain VARCHAR,
+         ref_channel VARCHAR,
+         utm_source VARCHAR,
+         utm_campaign VARCHAR,
+         country    VARCHAR,
+         mult       INTEGER,
+         protocol   VARCHAR,
+         tls_version VARCHAR,
+         metric     VARCHAR,
+         metric_value DOUBLE,
+         set_cookie UUID,
+         uniq       UUID
+     );
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS event_id UUID;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS host VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS hits INTEGER DEFAULT 1;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS agent_version VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS protocol VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS tls_version VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS metric VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS metric_value DOUBLE;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS country VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS device VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS ref_channel VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS utm_source VARCHAR;
+     ALTER TABLE stats ADD COLUMN IF NOT EXISTS utm_campaign VARCHAR;
+     CREATE INDEX IF NOT EXISTS idx_stats_host_date ON stats(host, date);
+     CREATE UNIQUE INDEX IF NOT EXISTS idx_stats_event_id ON stats(event_id);
+     CREATE TABLE IF NOT EXISTS stats_daily (
+         date       DATE,
+         type       agent_type_t,
+         visitors   BIGINT
+     );",
+    // 2: `type` as plain text, so types other than the three of the enum can
+    // be stored. DuckDB won't alter a table its indexes depend on.
+    "ALTER TABLE stats ADD COLUMN IF NOT EXISTS type VARCHAR;
+     DROP INDEX IF EXISTS idx_stats_host_date;
+     DROP INDEX IF EXISTS idx_stats_event_id;
+     ALTER TABLE stats ALTER COLUMN type SET DATA TYPE VARCHAR;
+     ALTER TABLE stats_daily ALTER COLUMN type SET DATA TYPE VARCHAR;
+     CREATE INDEX idx_stats_host_date ON stats(host, date);
+     CREATE UNIQUE INDEX idx_stats_event_id ON stats(event_id);",
+    // 3: per-host rows in `stats_daily`, next to those across hosts. Emptied,
+    // the rollup is rebuilt on open.
+    "ALTER TABLE stats_daily ADD COLUMN IF NOT EXISTS host VARCHAR;
+     DELETE FROM stats_daily;",
+];
+
+/// Applies the migrations the database hasn't had yet, in one transaction.
+fn migrate(conn: &mut Connection) -> Result<(), anyhow::Error> {
+    conn.execute_batch("CREATE TABLE IF NOT EXISTS schema_version (version INTEGER NOT NULL)")?;
+    let version: Option<i64> =
+        conn.query_row("SELECT MAX(version) FROM schema_version", [], |row| {
+            row.get(0)
+        })?;
+    let version = version.unwrap_or(0) as usize;
+    if version > MIGRATIONS.len() {
+        anyhow::bail!(
+            "schema version {} is newer than this build supports ({})",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+    if version == MIGRATIONS.len() {
+        return Ok(());
+    }
+    let tx = conn.transaction()?;
+    for (idx, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        tx.execute_batch(sql)
+            .with_context(|| format!("schema version {}", idx + 1))?;
+    }
+    tx.execute("DELETE FROM schema_version", [])?;
+    tx.execute(
+        "INSERT INTO schema_version VALUES (?)",
+        params![MIGRATIONS.len() as i64],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Columns that must match for two rows to be merged by `Store::compact`.
+const COMPACT_KEY: &str = concat!(
+    "date, host, path, query, type, agent, agent_version, os, device, ref_domain, ref_channel, ",
+    "utm_source, utm_campaign, country, mult, protocol, tls_version, metric, set_cookie, uniq"
+);
+
+/// Columns of `stats` the store reads and writes, with the type each must
+/// have. Enum columns also accept plain `VARCHAR`, which takes the same values.
+const EXPECTED_COLUMNS: &[(&str, &str)] = &[
+    ("event_id", "UUID"),
+    ("date", "DATE"),
+    ("time", "TIME"),
+    ("host", "VARCHAR"),
+    ("path", "VARCHAR"),
+    ("query", "VARCHAR"),
+    ("ip", "VARCHAR"),
+    ("user_agent", "VARCHAR"),
+    ("referrer", "VARCHAR"),
+    ("type", "VARCHAR"),
+    ("agent", "VARCHAR"),
+    ("agent_version", "VARCHAR"),
+    ("os", "ENUM"),
+    ("device", "VARCHAR"),
+    ("ref_domain", "VARCHAR"),
+    ("ref_channel", "VARCHAR"),
+    ("utm_source", "VARCHAR"),
+    ("utm_campaign", "VARCHAR"),
+    ("country", "VARCHAR"),
+    ("mult", "INTEGER"),
+    ("protocol", "VARCHAR"),
+    ("tls_version", "VARCHAR"),
+    ("metric", "VARCHAR"),
+    ("metric_value", "DOUBLE"),
+    ("set_cookie", "UUID"),
+    ("uniq", "UUID"),
+    ("hits", "INTEGER"),
+];
+
+fn verify_schema(conn: &Connection) -> Result<(), anyhow::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT column_name, data_type
+         FROM information_schema.columns
+         WHERE table_catalog = current_database() AND table_schema = current_schema()
+           AND table_name = 'stats'",
+    )?;
+    let mut rows = stmt.query([])?;
+    let mut actual = HashMap::new();
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        let data_type: String = row.get(1)?;
+        actual.insert(name, data_type);
+    }
+
+    let mut problems = Vec::new();
+    for (name, expected) in EXPECTED_COLUMNS {
+        match actual.get(*name) {
+            None => problems.push(format!("missing column {}", name)),
+            Some(data_type) if !column_type_matches(data_type, expected) => problems.push(
+                format!("column {} is {}, expected {}", name, data_type, expected),
+            ),
+            Some(_) => {}
+        }
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{}", problems.join("; "));
+    }
+    Ok(())
+}
+
+fn column_type_matches(actual: &str, expected: &str) -> bool {
+    if expected == "ENUM" {
+        return actual.starts_with("ENUM") || actual.starts_with("agent_") || actual == "VARCHAR";
+    }
+    actual == expected
+}
+
+/// Inserts the analyzed `lines` into `table`, adding those that were new to
+/// `inserted`. The cookies reported by second visits are added to
+/// `second_visits`, for `relink_second_visits`.
+fn insert_rows(
+    conn: &Connection,
+    table: &str,
+    lines: Vec<Line>,
+    inserted: &mut Vec<Line>,
+    second_visits: &mut BTreeSet<String>,
+) -> Result<(), anyhow::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "INSERT INTO {}
+         (event_id, date, time, host, path, query, ip, user_agent, referrer, type, agent, agent_version, os, device, ref_domain, ref_channel, utm_source, utm_campaign, country, mult, protocol, tls_version, metric, metric_value, set_cookie, uniq)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+         ON CONFLICT(event_id) DO NOTHING",
+        table
+    ))?;
+    for line in lines {
+        let count = stmt.execute(params![
+            null_str(&line.event_id),
+            null_str(&line.date),
+            null_str(&line.time),
+            null_str(&line.host),
+            null_str(&line.path),
+            null_str(&line.query),
+            null_str(&line.ip),
+            null_str(&line.user_agent),
+            null_str(&line.referrer),
+            null_str(&line.r#type),
+            null_str(&line.agent),
+            null_str(&line.agent_version),
+            null_str(&line.os),
+            null_str(&line.device),
+            null_str(&line.ref_domain),
+            null_str(&line.ref_channel),
+            null_str(&line.utm_source),
+            null_str(&line.utm_campaign),
+            null_str(&line.country),
+            line.mult,
+            null_str(&line.protocol),
+            null_str(&line.tls_version),
+            null_str(&line.metric),
+            line.metric_value,
+            null_str(&line.set_cookie),
+            null_str(&line.uniq),
+        ])?;
+
+        if line.second_visit && !line.uniq.is_empty() {
+            second_visits.insert(line.uniq.clone());
+        }
+        if count > 0 {
+            inserted.push(line);
+        }
+    }
+    Ok(())
+}
+
+/// Inserts the analyzed `lines` into the shards of their months, attaching
+/// new ones, and relinks first visits in every shard, each committing on its
+/// own. `inserted` and `second_visits` are filled as by `insert_rows`.
+fn insert_shards(
+    conn: &mut Connection,
+    dir: &Path,
+    lines: Vec<Line>,
+    inserted: &mut Vec<Line>,
+    second_visits: &mut BTreeSet<String>,
+) -> Result<(), anyhow::Error> {
+    let mut by_month: BTreeMap<String, Vec<Line>> = BTreeMap::new();
+    for line in lines {
+        by_month
+            .entry(line_month(&line.date)?)
+            .or_default()
+            .push(line);
+    }
+    let attached = shard_tables(conn)?;
+    let mut new_shards = false;
+    for month in by_month.keys() {
+        if !attached.contains(&shard_table(month)) {
+            attach_shard(conn, dir, month)?;
+            new_shards = true;
+        }
+    }
+    if new_shards {
+        refresh_view(conn)?;
+    }
+    for (month, lines) in by_month {
+        let tx = conn.transaction()?;
+        insert_rows(&tx, &shard_table(&month), lines, inserted, second_visits)?;
+        tx.commit()?;
+    }
+    // The first visit may be in any earlier month.
+    if !second_visits.is_empty() {
+        for table in shard_tables(conn)? {
+            let tx = conn.transaction()?;
+            relink_second_visits(&tx, &table, second_visits)?;
+            tx.commit()?;
+        }
+    }
+    Ok(())
+}
+
+/// A second visit sends the cookie set on the first one as its `uniq`, so
+/// the first visit's rows in `table` are the ones whose `set_cookie` equals
+/// it. Running this once the whole batch is in also catches first visits
+/// that arrive later in the same batch.
+fn relink_second_visits(
+    conn: &Connection,
+    table: &str,
+    second_visits: &BTreeSet<String>,
+) -> Result<(), anyhow::Error> {
+    let mut upd_stmt = conn.prepare(&format!(
+        "UPDATE {} SET uniq = ? WHERE set_cookie = ?",
+        table
+    ))?;
+    for uniq in second_visits {
+        upd_stmt.execute(params![uniq, uniq])?;
+    }
+    Ok(())
+}
+
+/// Re-runs the analyzer over the rows of `table` matching `where_clause`
+/// for `Store::reanalyze`, returning how many were updated and adding the
+/// subscriber counts it clamped to `clamped`.
+fn reanalyze_rows(
+    conn: &Connection,
+    table: &str,
+    where_clause: &str,
+    args: &[String],
+    analyzer: &analyzer::Config,
+    clamped: &mut usize,
+) -> Result<usize, anyhow::Error> {
+    let mut lines = Vec::new();
+    {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT rowid, path, ip, user_agent, referrer, type::VARCHAR, uniq::VARCHAR, query,
+                    CAST(date AS VARCHAR)
+             FROM {}
+             WHERE {} AND user_agent IS NOT NULL",
+            table, where_clause
+        ))?;
+        let mut rows = stmt.query(params_from_iter(args.iter().map(|s| s.as_str())))?;
+        while let Some(row) = rows.next()? {
+            let rowid: i64 = row.get(0)?;
+            let typ: Option<String> = row.get(5)?;
+            let (content_type, status) = match typ.as_deref() {
+                Some("browser") => ("text/html", Some(200)),
+                _ => ("", None),
+            };
+            let line = Line {
+                path: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                ip: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                user_agent: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                referrer: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                // Only the types the analyzer can tell from the user agent
+                // are recomputed.
+                r#type: typ
+                    .filter(|t| t != "browser" && t != "bot")
+                    .unwrap_or_default(),
+                content_type: content_type.to_string(),
+                status,
+                uniq: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                query: row.get::<_, Option<String>>(7)?.unwrap_or_default(),
+                date: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+                ..Line::default()
+            };
+            lines.push((rowid, line));
+        }
+    }
+
+    let updated = lines.len();
+    let mut stmt = conn.prepare(&format!(
+        "UPDATE {}
+         SET type = ?, agent = ?, agent_version = ?, os = ?, device = ?, ref_domain = ?,
+             ref_channel = ?, utm_source = ?, utm_campaign = ?, mult = ?,
+             country = COALESCE(?, country)
+         WHERE rowid = ?",
+        table
+    ))?;
+    for (rowid, mut line) in lines {
+        if analyzer::analyze(&mut line, analyzer) {
+            *clamped += 1;
+        }
+        stmt.execute(params![
+            null_str(&line.r#type),
+            null_str(&line.agent),
+            null_str(&line.agent_version),
+            null_str(&line.os),
+            null_str(&line.device),
+            null_str(&line.ref_domain),
+            null_str(&line.ref_channel),
+            null_str(&line.utm_source),
+            null_str(&line.utm_campaign),
+            line.mult,
+            null_str(&line.country),
+            rowid,
+        ])?;
+    }
+    Ok(updated)
+}
+
+/// Reports the subscriber counts the analyzer clamped in one write.
+fn log_clamped_subscribers(clamped: usize, analyzer: &analyzer::Config) {
+    if clamped > 0 {
+        eprintln!(
+            "clamped {} subscriber counts to {}",
+            clamped, analyzer.max_subscribers
+        );
+    }
+}
+
+/// Runs `write` on every table holding `stats` rows, summing what it returns,
+/// and then `finish`. A single file does it all in one transaction; shards
+/// commit one by one, since a DuckDB transaction writes to a single
+/// database, and `finish` commits last.
+fn write_tables(
+    conn: &mut Connection,
+    sharded: bool,
+    mut write: impl FnMut(&Connection, &str) -> Result<usize, anyhow::Error>,
+    finish: impl FnOnce(&Connection) -> Result<(), anyhow::Error>,
+) -> Result<usize, anyhow::Error> {
+    if !sharded {
+        let tx = conn.transaction()?;
+        let count = write(&tx, "stats")?;
+        finish(&tx)?;
+        tx.commit()?;
+        return Ok(count);
+    }
+    let mut count = 0;
+    for table in shard_tables(conn)? {
+        let tx = conn.transaction()?;
+        count += write(&tx, &table)?;
+        tx.commit()?;
+    }
+    let tx = conn.transaction()?;
+    finish(&tx)?;
+    tx.commit()?;
+    Ok(count)
+}
+
+/// Creates the enum types of the current database. They come first and
+/// outside the migrations: DuckDB has no `CREATE TYPE IF NOT EXISTS`, and a
+/// failing statement would abort the migration transaction.
+fn create_types(conn: &Connection) -> Result<(), anyhow::Error> {
+    for stmt in [
+        "CREATE TYPE agent_type_t AS ENUM ('feed', 'bot', 'browser')",
+        "CREATE TYPE agent_os_t AS ENUM ('Android', 'Windows', 'iOS', 'macOS', 'Linux')",
+    ] {
+        if let Err(err) = conn.execute(stmt, []) {
+            if !is_existing_type_error(&err) {
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Attaches the shard of `month` (`YYYY-MM`) in `dir`, creating the file if
+/// needed, and brings it to the current schema.
+fn attach_shard(conn: &mut Connection, dir: &Path, month: &str) -> Result<(), anyhow::Error> {
+    let file = dir.join(format!("stats-{}.duckdb", month));
+    conn.execute_batch(&format!(
+        "ATTACH '{}' AS {}",
+        file.display().to_string().replace('\'', "''"),
+        shard_database(month)
+    ))
+    .with_context(|| format!("attach shard {}", file.display()))?;
+    // Types, migrations and the schema check work on the current database.
+    conn.execute_batch(&format!("USE {}", shard_database(month)))?;
+    let prepared = create_types(conn)
+        .and_then(|_| migrate(conn))
+        .and_then(|_| verify_schema(conn));
+    conn.execute_batch("USE memory")?;
+    prepared.with_context(|| format!("prepare shard {}", file.display()))
+}
+
+/// Points the `stats` view at every attached shard.
+fn refresh_view(conn: &Connection) -> Result<(), anyhow::Error> {
+    let selects: Vec<String> = shard_tables(conn)?
+        .iter()
+        .map(|table| format!("SELECT * FROM {}", table))
+        .collect();
+    conn.execute_batch(&format!(
+        "CREATE OR REPLACE VIEW memory.stats AS {}",
+        selects.join(" UNION ALL BY NAME ")
+    ))?;
+    Ok(())
+}
+
+/// Attached shard databases, oldest first.
+fn shard_databases(conn: &Connection) -> Result<Vec<String>, anyhow::Error> {
+    let mut stmt =
+        conn.prepare("SELECT database_name FROM duckdb_databases() ORDER BY database_name")?;
+    let mut rows = stmt.query([])?;
+    let mut databases = Vec::new();
+    while let Some(row) = rows.next()? {
+        let name: String = row.get(0)?;
+        if name.starts_with("stats_") {
+            databases.push(name);
+        }
+    }
+    Ok(databases)
+}
+
+fn shard_tables(conn: &Connection) -> Result<Vec<String>, anyhow::Error> {
+    Ok(shard_databases(conn)?
+        .iter()
+        .map(|database| format!("{}.stats", database))
+        .collect())
+}
+
+/// Database name the shard of `month` (`YYYY-MM`) is attached as.
+fn shard_database(month: &str) -> String {
+    format!("stats_{}", month.replace('-', "_"))
+}
+
+fn shard_table(month: &str) -> String {
+    format!("{}.stats", shard_database(month))
+}
+
+/// Month of a shard file named `stats-YYYY-MM.duckdb`.
+fn shard_month(file_name: &str) -> Option<String> {
+    let month = file_name.strip_prefix("stats-")?.strip_suffix(".duckdb")?;
+    NaiveDate::parse_from_str(&format!("{}-01", month), "%Y-%m-%d")
+        .ok()
+        .map(|date| date.format("%Y-%m").to_string())
+        .filter(|parsed| parsed == month)
+}
+
+/// Month (`YYYY-MM`) of an event `date` (`YYYY-MM-DD`), naming its shard.
+fn line_month(date: &str) -> Result<String, anyhow::Error> {
+    let date = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .with_context(|| format!("invalid event date {:?}", date))?;
+    Ok(date.format("%Y-%m").to_string())
+}
+
+fn rebuild_rollup(conn: &Connection) -> Result<(), anyhow::Error> {
+    conn.execute("DELETE FROM stats_daily", [])?;
+    conn.execute(
+        &format!(
+            "INSERT INTO stats_daily (date, host, type, visitors) {}",
+            ROLLUP_SELECT
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+/// Counts of `stats_daily` rows, keyed by date (`YYYY-MM-DD`), host (`None`
+/// across hosts) and type.
+type RollupCounts = HashMap<(String, Option<String>, Option<String>), i64>;
+
+/// The visitors, as `(date, uniq)`, whose share of `stats_daily` inserting the
+/// analyzed `lines` may change: their own, and for second visits, both the
+/// old and the new visitor of the first visits they relink.
+fn touched_visitors(
+    conn: &Connection,
+    lines: &[Line],
+) -> Result<BTreeSet<(String, String)>, anyhow::Error> {
+    let cookies: BTreeSet<&str> = lines
+        .iter()
+        .filter(|line| line.second_visit && !line.uniq.is_empty())
+        .map(|line| line.uniq.as_str())
+        .collect();
+    let mut visitors = BTreeSet::new();
+    for line in lines {
+        if !line.uniq.is_empty() {
+            visitors.insert((line.date.clone(), line.uniq.clone()));
+        }
+        if cookies.contains(line.set_cookie.as_str()) {
+            visitors.insert((line.date.clone(), line.set_cookie.clone()));
+        }
+    }
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT CAST(date AS VARCHAR), uniq::VARCHAR FROM stats WHERE set_cookie = ?",
+    )?;
+    for cookie in cookies {
+        let mut rows = stmt.query(params![cookie])?;
+        while let Some(row) = rows.next()? {
+            let date: String = row.get(0)?;
+            if let Some(uniq) = row.get::<_, Option<String>>(1)? {
+                visitors.insert((date.clone(), uniq));
+            }
+            visitors.insert((date, cookie.to_string()));
+        }
+    }
+    Ok(visitors)
+}
+
+/// What `visitors` add to each `stats_daily` row: `ROLLUP_SELECT` over their
+/// rows alone.
+fn rollup_counts(
+    conn: &Connection,
+    visitors: &BTreeSet<(String, String)>,
+) -> Result<RollupCounts, anyhow::Error> {
+    let visitors: Vec<_> = visitors.iter().collect();
+    let mut counts = RollupCounts::new();
+    // Highest `mult` of each visitor across hosts, by date and type.
+    let mut across: HashMap<(String, Option<String>, String), i64> = HashMap::new();
+    for chunk in visitors.chunks(VISITORS_PER_QUERY) {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT CAST(stats.date AS VARCHAR), host, type, stats.uniq::VARCHAR, MAX(mult)
+             FROM stats
+             JOIN (VALUES {}) AS visitors(date, uniq)
+               ON stats.date = CAST(visitors.date AS DATE)
+              AND stats.uniq = CAST(visitors.uniq AS UUID)
+             GROUP BY stats.date, host, type, stats.uniq",
+            vec!["(?, ?)"; chunk.len()].join(", ")
+        ))?;
+        let args = chunk
+            .iter()
+            .flat_map(|(date, uniq)| [date.as_str(), uniq.as_str()]);
+        let mut rows = stmt.query(params_from_iter(args))?;
+        while let Some(row) = rows.next()? {
+            let date: String = row.get(0)?;
+            let host: Option<String> = row.get(1)?;
+            let typ: Option<String> = row.get(2)?;
+            let uniq: String = row.get(3)?;
+            let mult = row.get::<_, Option<i64>>(4)?.unwrap_or(0);
+            if host.is_some() {
+                *counts.entry((date.clone(), host, typ.clone())).or_default() += mult;
+            }
+            let highest = across.entry((date, typ, uniq)).or_default();
+            *highest = (*highest).max(mult);
+        }
+    }
+    for ((date, typ, _), mult) in across {
+        *counts.entry((date, None, typ)).or_default() += mult;
+    }
+    Ok(counts)
+}
+
+/// Moves `stats_daily` by what a write changed `visitors` to count for, from
+/// their counts `before` it.
+fn update_rollup(
+    conn: &Connection,
+    visitors: &BTreeSet<(String, String)>,
+    before: &RollupCounts,
+) -> Result<(), anyhow::Error> {
+    let after = rollup_counts(conn, visitors)?;
+    let mut update = conn.prepare(
+        "UPDATE stats_daily SET visitors = visitors + ?
+         WHERE date = CAST(? AS DATE)
+           AND host IS NOT DISTINCT FROM ?
+           AND type IS NOT DISTINCT FROM ?",
+    )?;
+    let mut insert = conn.prepare(
+        "INSERT INTO stats_daily (date, host, type, visitors) VALUES (CAST(? AS DATE), ?, ?, ?)",
+    )?;
+    let keys: BTreeSet<_> = before.keys().chain(after.keys()).collect();
+    for key in keys {
+        let count = |counts: &RollupCounts| counts.get(key).copied().unwrap_or(0);
+        let delta = count(&after) - count(before);
+        if delta == 0 {
+            continue;
+        }
+        let (date, host, typ) = key;
+        if update.execute(params![delta, date, host, typ])? == 0 {
+            insert.execute(params![date, host, typ, delta])?;
+        }
+    }
+    // Like `ROLLUP_SELECT`, which leaves out empty days.
+    conn.execute("DELETE FROM stats_daily WHERE visitors = 0", [])?;
+    Ok(())
+}
+
 fn null_str(s: &str) -> Option<&str> {
     if s.is_empty() {
         None
@@ -124,3 +1206,448 @@ fn is_existing_type_error(err: &duckdb::Error) -> bool {
     let msg = err.to_string();
     msg.contains("already exists") || msg.contains("Type with name")
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) const FIREFOX: &str =
+        "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+
+    /// An empty store living in memory, with the default analyzer options.
+    pub(crate) fn memory_store() -> Store {
+        memory_store_with(analyzer::Config::default())
+    }
+
+    pub(crate) fn memory_store_with(analyzer: analyzer::Config) -> Store {
+        Store::open(MEMORY_PATH, analyzer, 2, Shard::Single).expect("open in-memory store")
+    }
+
+    /// A Firefox hit on `path` of example.com from `ip`.
+    pub(crate) fn hit(date: &str, time: &str, ip: &str, path: &str) -> Line {
+        Line {
+            date: date.to_string(),
+            time: time.to_string(),
+            host: "example.com".to_string(),
+            path: path.to_string(),
+            ip: ip.to_string(),
+            user_agent: FIREFOX.to_string(),
+            ..Line::default()
+        }
+    }
+
+    pub(crate) async fn row_count(store: &Store) -> i64 {
+        store
+            .with_conn(|conn| {
+                Ok(conn.query_row("SELECT COUNT(*) FROM stats", [], |row| row.get(0))?)
+            })
+            .await
+            .expect("count rows")
+    }
+
+    async fn types(store: &Store) -> Vec<(String, String, String)> {
+        store
+            .with_conn(|conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT CAST(date AS VARCHAR), path, type::VARCHAR FROM stats ORDER BY date, path",
+                )?;
+                let mut rows = stmt.query([])?;
+                let mut types = Vec::new();
+                while let Some(row) = rows.next()? {
+                    types.push((row.get(0)?, row.get(1)?, row.get(2)?));
+                }
+                Ok(types)
+            })
+            .await
+            .expect("types")
+    }
+
+    fn typed(date: &str, path: &str, typ: &str) -> (String, String, String) {
+        (date.to_string(), path.to_string(), typ.to_string())
+    }
+
+    #[test]
+    fn open_rejects_incompatible_stats_table() {
+        let path =
+            std::env::temp_dir().join(format!("banan-stats-schema-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().expect("utf-8 path").to_string();
+        Connection::open(&path)
+            .expect("create db")
+            .execute_batch("CREATE TABLE stats (date DATE, path VARCHAR, mult VARCHAR, uniq UUID)")
+            .expect("create table");
+
+        let err = match Store::open(&path, analyzer::Config::default(), 1, Shard::Single) {
+            Ok(_) => panic!("opened an incompatible stats table"),
+            Err(err) => format!("{:#}", err),
+        };
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.wal", path));
+        assert!(err.contains("incompatible stats table"), "{}", err);
+        assert!(err.contains("missing column user_agent"), "{}", err);
+        assert!(
+            err.contains("column mult is VARCHAR, expected INTEGER"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn version_one_databases_store_custom_types() {
+        let path = std::env::temp_dir().join(format!("banan-stats-v1-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let path = path.to_str().expect("utf-8 path").to_string();
+        {
+            let conn = Connection::open(&path).expect("create db");
+            create_types(&conn).expect("types");
+            conn.execute_batch(MIGRATIONS[0]).expect("version 1");
+            conn.execute_batch(
+                "CREATE TABLE schema_version (version INTEGER NOT NULL);
+                 INSERT INTO schema_version VALUES (1);
+                 INSERT INTO stats (date, path, type) VALUES ('2024-01-10', '/', 'bot');",
+            )
+            .expect("version 1 rows");
+        }
+
+        let store = Store::open(&path, analyzer::Config::default(), 1, Shard::Single);
+        let result = match store {
+            Ok(store) => {
+                let conn = store.conn.lock().expect("db lock");
+                conn.execute(
+                    "INSERT INTO stats (date, path, type) VALUES ('2024-01-11', '/api', 'api')",
+                    [],
+                )
+                .and_then(|_| {
+                    conn.query_row(
+                        "SELECT string_agg(type, ',' ORDER BY date) FROM stats",
+                        [],
+                        |row| row.get::<_, String>(0),
+                    )
+                })
+                .map_err(anyhow::Error::from)
+            }
+            Err(err) => Err(err),
+        };
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.wal", path));
+        assert_eq!(result.expect("custom type"), "bot,api");
+    }
+
+    #[tokio::test]
+    async fn parsed_user_agents_keep_only_derived_columns() {
+        let store = memory_store_with(analyzer::Config {
+            store_ua: analyzer::StoreUserAgent::Parsed,
+            ..analyzer::Config::default()
+        });
+        store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/")])
+            .await
+            .expect("insert");
+        let row: (Option<String>, Option<String>, Option<String>) = store
+            .with_conn(|conn| {
+                Ok(conn.query_row(
+                    "SELECT user_agent, agent, os::VARCHAR FROM stats",
+                    [],
+                    |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+                )?)
+            })
+            .await
+            .expect("row");
+        assert_eq!(
+            row,
+            (None, Some("Firefox".to_string()), Some("Linux".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn readers_cannot_write() {
+        let store = memory_store();
+        store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/")])
+            .await
+            .expect("insert");
+        let deleted = store
+            .with_conn(|conn| Ok(conn.execute("DELETE FROM stats", [])?))
+            .await;
+        assert!(deleted.is_err());
+        assert_eq!(row_count(&store).await, 1);
+    }
+
+    #[tokio::test]
+    async fn backups_copy_the_database_side_by_side() {
+        let store = memory_store();
+        store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/")])
+            .await
+            .expect("insert");
+        let dir = std::env::temp_dir();
+        // A quote in the path must not end the SQL string it is put in.
+        let dests: Vec<String> = ["a", "b'; DETACH memory; --"]
+            .iter()
+            .map(|name| {
+                let dest = dir.join(format!(
+                    "banan-stats-backup-{}-{}.db",
+                    std::process::id(),
+                    name
+                ));
+                dest.to_string_lossy().into_owned()
+            })
+            .collect();
+        let (first, second) = tokio::join!(
+            store.backup(dests[0].clone()),
+            store.backup(dests[1].clone())
+        );
+        first.expect("backup");
+        second.expect("backup");
+        for dest in &dests {
+            let rows: i64 = Connection::open(dest)
+                .expect("open backup")
+                .query_row("SELECT COUNT(*) FROM stats", [], |row| row.get(0))
+                .expect("count");
+            assert_eq!(rows, 1);
+            std::fs::remove_file(dest).unwrap();
+        }
+        store.backup(dests[0].clone()).await.expect("backup");
+        assert!(store.backup(dests[0].clone()).await.is_err());
+        // Only a copy this backup started is removed on failure.
+        assert!(Path::new(&dests[0]).exists());
+        std::fs::remove_file(&dests[0]).unwrap();
+    }
+
+    #[tokio::test]
+    async fn every_write_changes_the_version() {
+        let store = memory_store();
+        let mut versions = vec![store.version()];
+        store
+            .insert(vec![hit("2024-01-10", "10:00:00", "10.0.0.1", "/")])
+            .await
+            .expect("insert");
+        versions.push(store.version());
+        store
+            .compact(NaiveDate::from_ymd_opt(2024, 2, 1).unwrap())
+            .await
+            .expect("compact");
+        versions.push(store.version());
+        store
+            .prune(NaiveDate::from_ymd_opt(2024, 1, 1).unwrap())
+            .await
+            .expect("prune");
+        versions.push(store.version());
+        store.rebuild_rollup().await.expect("rebuild");
+        versions.push(store.version());
+        let count = versions.len();
+        versions.dedup();
+        assert_eq!(versions.len(), count);
+
+        // Reads leave it alone.
+        row_count(&store).await;
+        assert_eq!(store.version(), versions[count - 1]);
+        assert_ne!(memory_store().version(), versions[0]);
+    }
+
+    #[tokio::test]
+    async fn slow_queries_are_interrupted() {
+        let store = memory_store();
+        let timed_out = store
+            .with_conn_timeout(std::time::Duration::from_millis(200), |conn| {
+                Ok(conn.query_row(
+                    "SELECT COUNT(*) FROM range(100000000) a, range(100000000) b",
+                    [],
+                    |row| row.get::<_, i64>(0),
+                )?)
+            })
+            .await
+            .expect("query");
+        assert_eq!(timed_out, None);
+        // Every reader is free again and takes queries.
+        for _ in 0..4 {
+            assert_eq!(row_count(&store).await, 0);
+        }
+        let quick = store
+            .with_conn_timeout(std::time::Duration::from_secs(10), |conn| {
+                Ok(conn.query_row("SELECT 42", [], |row| row.get::<_, i64>(0))?)
+            })
+            .await
+            .expect("query");
+        assert_eq!(quick, Some(42));
+    }
+
+    #[tokio::test]
+    async fn reanalyze_only_updates_rows_in_scope() {
+        let store = memory_store();
+        let mut chrome = hit("2024-01-10", "10:00:00", "10.0.0.2", "/chrome");
+        chrome.user_agent = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+            (KHTML, like Gecko) Chrome/120.0.0.0 Safari/537.36"
+            .to_string();
+        store
+            .insert(vec![
+                hit("2024-01-10", "10:00:00", "10.0.0.1", "/in-scope"),
+                hit("2024-01-11", "10:00:00", "10.0.0.1", "/other-day"),
+                chrome,
+            ])
+            .await
+            .expect("insert");
+        // As if the rules had misclassified every row.
+        store
+            .conn
+            .lock()
+            .expect("db lock")
+            .execute("UPDATE stats SET type = 'bot'", [])
+            .expect("update");
+
+        let filters = HashMap::from([("agent".to_string(), "Firefox".to_string())]);
+        let (where_clause, args) =
+            crate::dashboard::build_where("2024-01-10", "2024-01-10", &filters);
+        let updated = store
+            .reanalyze(where_clause, args)
+            .await
+            .expect("reanalyze");
+        assert_eq!(updated, 1);
+        assert_eq!(
+            types(&store).await,
+            vec![
+                typed("2024-01-10", "/chrome", "bot"),
+                typed("2024-01-10", "/in-scope", "browser"),
+                typed("2024-01-11", "/other-day", "bot"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn reanalyze_keeps_html_browser_rows() {
+        let store = memory_store_with(analyzer::Config {
+            html_as_browser: true,
+            ..analyzer::Config::default()
+        });
+        let mut line = hit("2024-01-10", "10:00:00", "10.0.0.1", "/article");
+        line.user_agent = "ReaderView/2.0".to_string();
+        line.content_type = "text/html; charset=utf-8".to_string();
+        line.status = Some(200);
+        store.insert(vec![line]).await.expect("insert");
+        assert_eq!(
+            types(&store).await,
+            vec![typed("2024-01-10", "/article", "browser")]
+        );
+
+        store
+            .reanalyze("TRUE".to_string(), Vec::new())
+            .await
+            .expect("reanalyze");
+        assert_eq!(
+            types(&store).await,
+            vec![typed("2024-01-10", "/article", "browser")]
+        );
+    }
+
+    #[tokio::test]
+    async fn reanalyze_keeps_custom_types() {
+        let store = memory_store();
+        let mut api = hit("2024-01-10", "10:00:00", "10.0.0.1", "/api");
+        api.r#type = "api".to_string();
+        store
+            .insert(vec![
+                api,
+                hit("2024-01-10", "10:00:00", "10.0.0.2", "/page"),
+            ])
+            .await
+            .expect("insert");
+        store
+            .conn
+            .lock()
+            .expect("db lock")
+            .execute("UPDATE stats SET type = 'bot' WHERE path = '/page'", [])
+            .expect("update");
+
+        store
+            .reanalyze("TRUE".to_string(), Vec::new())
+            .await
+            .expect("reanalyze");
+        assert_eq!(
+            types(&store).await,
+            vec![
+                typed("2024-01-10", "/api", "api"),
+                typed("2024-01-10", "/page", "browser"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn compact_keeps_rows_with_different_cookies_apart() {
+        let store = memory_store();
+        let first_cookie = "6f1c1a52-58a1-4a8e-9d0c-1f4f0f5b7a01";
+        let second_cookie = "6f1c1a52-58a1-4a8e-9d0c-1f4f0f5b7a02";
+        let mut lines = Vec::new();
+        for cookie in [first_cookie, second_cookie] {
+            let mut line = hit("2024-01-10", "10:00:00", "10.0.0.1", "/");
+            line.set_cookie = cookie.to_string();
+            lines.push(line);
+        }
+        store.insert(lines).await.expect("insert");
+
+        store
+            .compact(NaiveDate::from_ymd_opt(2024, 2, 1).unwrap())
+            .await
+            .expect("compact");
+        assert_eq!(row_count(&store).await, 2);
+
+        // The second visit of the first cookie still finds its first visit.
+        let mut second = hit("2024-03-01", "10:00:00", "10.0.0.2", "/");
+        second.uniq = first_cookie.to_string();
+        second.second_visit = true;
+        store.insert(vec![second]).await.expect("insert");
+        let relinked: i64 = store
+            .with_conn(move |conn| {
+                Ok(conn.query_row(
+                    "SELECT COUNT(*) FROM stats WHERE uniq = ?",
+                    params![first_cookie],
+                    |row| row.get(0),
+                )?)
+            })
+            .await
+            .expect("count");
+        assert_eq!(relinked, 2);
+    }
+
+    async fn unique_visitors(store: &Store) -> i64 {
+        store
+            .with_conn(|conn| {
+                Ok(
+                    conn.query_row("SELECT COUNT(DISTINCT uniq) FROM stats", [], |row| {
+                        row.get(0)
+                    })?,
+                )
+            })
+            .await
+            .expect("count")
+    }
+
+    /// An anonymous first visit that is handed `cookie`, and the visit that
+    /// later sends it back.
+    fn cookied_visits(cookie: &str) -> (Line, Line) {
+        let mut first = hit("2024-01-10", "10:00:00", "10.0.0.1", "/");
+        first.set_cookie = cookie.to_string();
+        let mut second = hit("2024-01-11", "09:00:00", "10.0.0.2", "/about");
+        second.uniq = cookie.to_string();
+        second.second_visit = true;
+        (first, second)
+    }
+
+    #[tokio::test]
+    async fn second_visit_relinks_the_anonymous_first_visit() {
+        let store = memory_store();
+        let (first, second) = cookied_visits("6f1c1a52-58a1-4a8e-9d0c-1f4f0f5b7a03");
+        store.insert(vec![first]).await.expect("insert");
+        store.insert(vec![second]).await.expect("insert");
+        assert_eq!(row_count(&store).await, 2);
+        assert_eq!(unique_visitors(&store).await, 1);
+    }
+
+    #[tokio::test]
+    async fn second_visit_relinks_a_first_visit_later_in_the_batch() {
+        let store = memory_store();
+        let (first, second) = cookied_visits("6f1c1a52-58a1-4a8e-9d0c-1f4f0f5b7a04");
+        store.insert(vec![second, first]).await.expect("insert");
+        assert_eq!(row_count(&store).await, 2);
+        assert_eq!(unique_visitors(&store).await, 1);
+    }
+}
@@ -6,6 +6,7 @@ type Config struct {
 	SidecarURL     string `json:"sidecarURL" yaml:"sidecarURL" toml:"sidecarURL"`
 	DashboardPath  string `json:"dashboardPath" yaml:"dashboardPath" toml:"dashboardPath"`
 	DashboardToken string `json:"dashboardToken" yaml:"dashboardToken" toml:"dashboardToken"`
+	IngestKey      string `json:"ingestKey" yaml:"ingestKey" toml:"ingestKey"`
 
 	CookieName     string `json:"cookieName" yaml:"cookieName" toml:"cookieName"`
 	CookiePath     string `json:"cookiePath" yaml:"cookiePath" toml:"cookiePath"`
@@ -28,6 +29,7 @@ func CreateConfig() *Config {
 		SidecarURL:     "",
 		DashboardPath:  "/stats",
 		DashboardToken: "",
+		IngestKey:      "",
 
 		CookieName:     "stats_id",
 		CookiePath:     "/",
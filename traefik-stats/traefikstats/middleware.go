@@ -4,6 +4,7 @@ import (
 	"bufio"
 	"context"
 	"crypto/rand"
+	"crypto/tls"
 	"encoding/hex"
 	"errors"
 	"fmt"
@@ -51,7 +52,7 @@ func New(ctx context.Context, next http.Handler, config *Config, name string) (h
 		config.BufferPath = "/tmp/banan-stats-buffer.sqlite"
 	}
 
-	streamClient, err := newStreamClient(config.SidecarURL)
+	streamClient, err := newStreamClient(config.SidecarURL, config.IngestKey)
 	if err != nil {
 		return nil, fmt.Errorf("stream client init failed: %w", err)
 	}
@@ -92,7 +93,7 @@ func (m *statsMiddleware) ServeHTTP(rw http.ResponseWriter, req *http.Request) {
 	contentType := rec.Header().Get("Content-Type")
 
 	if m.isLoggable(status, contentType) {
-		m.enqueueEvent(req, contentType, cookieState)
+		m.enqueueEvent(req, status, contentType, cookieState)
 	}
 
 	rec.finalize()
@@ -113,17 +114,15 @@ func (m *statsMiddleware) isDashboardRequest(req *http.Request) bool {
 	if req.URL.Path == m.cfg.DashboardPath {
 		return true
 	}
-	return req.URL.Path == strings.TrimSuffix(m.cfg.DashboardPath, "/")+"/favicon.ico"
+	base := strings.TrimSuffix(m.cfg.DashboardPath, "/")
+	return req.URL.Path == base+"/favicon.ico" || strings.HasPrefix(req.URL.Path, base+"/export/")
 }
 
 func (m *statsMiddleware) proxyDashboard(rw http.ResponseWriter, req *http.Request) {
-	if m.cfg.DashboardToken != "" {
-		auth := req.Header.Get("Authorization")
-		if !strings.HasPrefix(auth, "Bearer ") || strings.TrimPrefix(auth, "Bearer ") != m.cfg.DashboardToken {
-			rw.WriteHeader(http.StatusUnauthorized)
-			_, _ = rw.Write([]byte("Unauthorized"))
-			return
-		}
+	if m.cfg.DashboardToken != "" && !m.hasDashboardToken(req) {
+		rw.WriteHeader(http.StatusUnauthorized)
+		_, _ = rw.Write([]byte("Unauthorized"))
+		return
 	}
 
 	target, err := url.Parse(m.cfg.SidecarURL)
@@ -140,6 +139,13 @@ func (m *statsMiddleware) proxyDashboard(rw http.ResponseWriter, req *http.Reque
 		return
 	}
 
+	// The sidecar may check the dashboard token itself.
+	for _, name := range []string{"Authorization", "Cookie"} {
+		if v := req.Header.Get(name); v != "" {
+			outReq.Header.Set(name, v)
+		}
+	}
+
 	resp, err := m.client.Do(outReq)
 	if err != nil {
 		rw.WriteHeader(http.StatusBadGateway)
@@ -156,6 +162,17 @@ func (m *statsMiddleware) proxyDashboard(rw http.ResponseWriter, req *http.Reque
 	_, _ = io.Copy(rw, resp.Body)
 }
 
+// hasDashboardToken reports whether req carries the dashboard token as a
+// bearer token or a "token" cookie.
+func (m *statsMiddleware) hasDashboardToken(req *http.Request) bool {
+	auth := req.Header.Get("Authorization")
+	if strings.HasPrefix(auth, "Bearer ") && strings.TrimPrefix(auth, "Bearer ") == m.cfg.DashboardToken {
+		return true
+	}
+	cookie, err := req.Cookie("token")
+	return err == nil && cookie.Value == m.cfg.DashboardToken
+}
+
 func (m *statsMiddleware) isLoggable(status int, contentType string) bool {
 	if status != http.StatusOK {
 		return false
@@ -166,7 +183,7 @@ func (m *statsMiddleware) isLoggable(status int, contentType string) bool {
 		strings.HasPrefix(ct, "application/rss+xml")
 }
 
-func (m *statsMiddleware) enqueueEvent(req *http.Request, contentType string, cookieState cookieState) {
+func (m *statsMiddleware) enqueueEvent(req *http.Request, status int, contentType string, cookieState cookieState) {
 	ip := req.Header.Get("X-Forwarded-For")
 	if ip == "" {
 		ip = req.RemoteAddr
@@ -176,18 +193,22 @@ func (m *statsMiddleware) enqueueEvent(req *http.Request, contentType string, co
 	}
 
 	evt := event{
-		EventID:     newUUID(),
-		Timestamp:   time.Now().UTC(),
-		Host:        normalizeHost(req.Host),
-		Path:        req.URL.Path,
-		Query:       req.URL.RawQuery,
-		IP:          ip,
-		UserAgent:   req.Header.Get("User-Agent"),
-		Referrer:    req.Header.Get("Referer"),
-		ContentType: contentType,
-		SetCookie:   cookieState.setCookie,
-		Uniq:        cookieState.uniq,
-		SecondVisit: cookieState.secondVisit,
+		EventID:        newUUID(),
+		Timestamp:      time.Now().UTC(),
+		Host:           normalizeHost(req.Host),
+		Path:           req.URL.Path,
+		Query:          req.URL.RawQuery,
+		IP:             ip,
+		UserAgent:      req.Header.Get("User-Agent"),
+		Referrer:       req.Header.Get("Referer"),
+		ContentType:    contentType,
+		Status:         status,
+		AcceptLanguage: req.Header.Get("Accept-Language"),
+		Protocol:       req.Proto,
+		TLSVersion:     tlsVersion(req),
+		SetCookie:      cookieState.setCookie,
+		Uniq:           cookieState.uniq,
+		SecondVisit:    cookieState.secondVisit,
 	}
 
 	if err := m.queue.Enqueue(evt); err != nil {
@@ -195,6 +216,13 @@ func (m *statsMiddleware) enqueueEvent(req *http.Request, contentType string, co
 	}
 }
 
+func tlsVersion(req *http.Request) string {
+	if req.TLS == nil {
+		return ""
+	}
+	return tls.VersionName(req.TLS.Version)
+}
+
 func (m *statsMiddleware) worker(ctx context.Context) {
 	ticker := time.NewTicker(m.flushInterval)
 	defer ticker.Stop()
@@ -12,16 +12,18 @@ import (
 
 type streamClient struct {
 	endpoint string
+	apiKey   string
 	client   *http.Client
 }
 
-func newStreamClient(sidecarURL string) (*streamClient, error) {
+func newStreamClient(sidecarURL, apiKey string) (*streamClient, error) {
 	if strings.TrimSpace(sidecarURL) == "" {
 		return nil, fmt.Errorf("sidecarURL is empty")
 	}
 	endpoint := strings.TrimRight(sidecarURL, "/") + "/ingest"
 	return &streamClient{
 		endpoint: endpoint,
+		apiKey:   apiKey,
 		client:   &http.Client{},
 	}, nil
 }
@@ -33,6 +35,9 @@ func (c *streamClient) StreamEvents(ctx context.Context, events []event) error {
 		return err
 	}
 	req.Header.Set("Content-Type", "application/x-ndjson")
+	if c.apiKey != "" {
+		req.Header.Set("X-Api-Key", c.apiKey)
+	}
 
 	writeErrCh := make(chan error, 1)
 	go func() {
@@ -64,7 +69,7 @@ func (c *streamClient) StreamEvents(ctx context.Context, events []event) error {
 	if err := <-writeErrCh; err != nil {
 		return err
 	}
-	if resp.StatusCode != http.StatusAccepted {
+	if resp.StatusCode < 200 || resp.StatusCode > 299 {
 		return fmt.Errorf("unexpected status %d", resp.StatusCode)
 	}
 	return nil
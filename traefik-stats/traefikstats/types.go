@@ -3,16 +3,20 @@ package traefikstats
 import "time"
 
 type event struct {
-	EventID     string    `json:"eventId"`
-	Timestamp   time.Time `json:"timestamp"`
-	Host        string    `json:"host"`
-	Path        string    `json:"path"`
-	Query       string    `json:"query"`
-	IP          string    `json:"ip"`
-	UserAgent   string    `json:"userAgent"`
-	Referrer    string    `json:"referrer"`
-	ContentType string    `json:"contentType"`
-	SetCookie   string    `json:"setCookie"`
-	Uniq        string    `json:"uniq"`
-	SecondVisit bool      `json:"secondVisit"`
+	EventID        string    `json:"eventId"`
+	Timestamp      time.Time `json:"timestamp"`
+	Host           string    `json:"host"`
+	Path           string    `json:"path"`
+	Query          string    `json:"query"`
+	IP             string    `json:"ip"`
+	UserAgent      string    `json:"userAgent"`
+	Referrer       string    `json:"referrer"`
+	ContentType    string    `json:"contentType"`
+	Status         int       `json:"status"`
+	AcceptLanguage string    `json:"acceptLanguage,omitempty"`
+	Protocol       string    `json:"protocol,omitempty"`
+	TLSVersion     string    `json:"tlsVersion,omitempty"`
+	SetCookie      string    `json:"setCookie"`
+	Uniq           string    `json:"uniq"`
+	SecondVisit    bool      `json:"secondVisit"`
 }